@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use flate2::read::GzDecoder;
+use xz2::read::XzDecoder;
+
+use crate::fs::{self, FileHasher};
+use crate::escape;
+use crate::hasher::{QuickHasher, SlowHasher};
+
+/* separates the path of an archive from the path of a member inside it */
+pub const MEMBER_SEPARATOR : &str = "!";
+
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+    TarXz
+}
+
+/* a regular file inside an archive, hashed while the archive is streamed */
+pub struct ArchiveMember {
+    pub path: PathBuf,     // relative to the root of the archive
+    pub mtime: i64,
+    pub mode: Option<u32>,
+    pub digests: fs::StreamDigests
+}
+
+fn archive_kind(path: &Path) -> Option<ArchiveKind> {
+    let name = path.file_name()?.to_str()?.to_lowercase();
+    if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(ArchiveKind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(ArchiveKind::TarGz)
+    } else if name.ends_with(".tar.xz") || name.ends_with(".txz") {
+        Some(ArchiveKind::TarXz)
+    } else {
+        None
+    }
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    archive_kind(path).is_some()
+}
+
+/* path under which the members of an archive are recorded, e.g. dir/backup.zip! */
+pub fn members_root(archive_path: &Path) -> PathBuf {
+    let mut root = archive_path.as_os_str().to_owned();
+    root.push(MEMBER_SEPARATOR);
+    PathBuf::from(root)
+}
+
+// zip timestamps are in MS-DOS format (taken as UTC here)
+fn zip_mtime(dt: zip::DateTime) -> i64 {
+    let (y, m, d) = (dt.year() as i64, dt.month() as i64, dt.day() as i64);
+    // days since the epoch of a civil date
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    days * 86400 + dt.hour() as i64 * 3600 + dt.minute() as i64 * 60 + dt.second() as i64
+}
+
+fn read_zip(f: File, quick: &mut FileHasher<QuickHasher>,
+            slow: &mut FileHasher<SlowHasher>) -> Result<Vec<ArchiveMember>, String> {
+    let mut zip = zip::ZipArchive::new(BufReader::new(f)).map_err(|_e| _e.to_string())?;
+    let mut members : Vec<ArchiveMember> = Vec::new();
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i).map_err(|_e| _e.to_string())?;
+        if !entry.is_file() {
+            continue;
+        }
+        let path = match entry.enclosed_name() {
+            Some(_p) => _p.to_path_buf(),
+            None => continue // unsafe member name (e.g. ../x)
+        };
+        let mtime = zip_mtime(entry.last_modified());
+        let mode = entry.unix_mode();
+        let digests = fs::hash_stream(&mut entry, quick, &mut QuickHasher::new(quick.hasher.algorithm()), slow)
+            .map_err(|_e| format!("{} ({})", escape::display(&path), _e))?;
+        members.push(ArchiveMember { path, mtime, mode, digests });
+    }
+    Ok(members)
+}
+
+/* the path of a tar member relative to the root of the archive, as enclosed_name() gives
+   those of zip members: leading / dropped, and none for a path leading out with .. */
+fn enclosed_path(path: &Path) -> Option<PathBuf> {
+    let mut enclosed = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(_name) => enclosed.push(_name),
+            Component::ParentDir => return None,
+            Component::RootDir | Component::Prefix(_) | Component::CurDir => {}
+        }
+    }
+    match enclosed.as_os_str().is_empty() {
+        true => None,
+        false => Some(enclosed)
+    }
+}
+
+fn read_tar<R: Read>(r: R, quick: &mut FileHasher<QuickHasher>,
+                     slow: &mut FileHasher<SlowHasher>) -> Result<Vec<ArchiveMember>, String> {
+    let mut tar = tar::Archive::new(r);
+    let mut members : Vec<ArchiveMember> = Vec::new();
+    for entry in tar.entries().map_err(|_e| _e.to_string())? {
+        let mut entry = entry.map_err(|_e| _e.to_string())?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = match enclosed_path(&entry.path().map_err(|_e| _e.to_string())?) {
+            Some(_p) => _p,
+            None => continue // unsafe member name (e.g. ../x)
+        };
+        let mtime = entry.header().mtime().unwrap_or(0) as i64;
+        let mode = entry.header().mode().ok();
+        let digests = fs::hash_stream(&mut entry, quick, &mut QuickHasher::new(quick.hasher.algorithm()), slow)
+            .map_err(|_e| format!("{} ({})", escape::display(&path), _e))?;
+        members.push(ArchiveMember { path, mtime, mode, digests });
+    }
+    Ok(members)
+}
+
+/* hashes the regular files inside a zip or (compressed) tar archive */
+pub fn read_members(path: &Path, quick: &mut FileHasher<QuickHasher>,
+                    slow: &mut FileHasher<SlowHasher>) -> Result<Vec<ArchiveMember>, String> {
+    let kind = archive_kind(path).ok_or_else(|| "not an archive".to_string())?;
+    let f = File::open(path).map_err(|_e| _e.to_string())?;
+    match kind {
+        ArchiveKind::Zip => read_zip(f, quick, slow),
+        ArchiveKind::Tar => read_tar(BufReader::new(f), quick, slow),
+        ArchiveKind::TarGz => read_tar(GzDecoder::new(BufReader::new(f)), quick, slow),
+        ArchiveKind::TarXz => read_tar(XzDecoder::new(BufReader::new(f)), quick, slow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::{QuickAlgorithm, SlowAlgorithm};
+
+    /* a tar archive of the given members, named as they are (tar::Builder refuses
+       absolute names and ones with ..) */
+    fn tar_of(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in members {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn member_paths(archive: Vec<u8>) -> Vec<PathBuf> {
+        let mut quick = FileHasher::new(QuickHasher::new(QuickAlgorithm::Xxh3), 4096, false);
+        let mut slow = FileHasher::new(SlowHasher::new(SlowAlgorithm::Sha256).unwrap(), 4096, false);
+        read_tar(std::io::Cursor::new(archive), &mut quick, &mut slow).unwrap()
+            .into_iter().map(|_m| _m.path).collect()
+    }
+
+    #[test]
+    fn records_absolute_tar_members_below_the_archive() {
+        let archive = tar_of(&[("/etc/passwd", b"root\n"), ("./docs/a", b"a\n")]);
+        assert_eq!(member_paths(archive), vec![PathBuf::from("etc/passwd"), PathBuf::from("docs/a")]);
+        assert_eq!(members_root(Path::new("dir/backup.tar")).join("etc/passwd"),
+                   PathBuf::from("dir/backup.tar!/etc/passwd"));
+    }
+
+    #[test]
+    fn skips_tar_members_leading_out_of_the_archive() {
+        let archive = tar_of(&[("../outside", b"x\n"), ("docs/../../up", b"y\n"), ("docs/b", b"b\n")]);
+        assert_eq!(member_paths(archive), vec![PathBuf::from("docs/b")]);
+    }
+}
@@ -0,0 +1,74 @@
+use clap::{App, AppSettings, Arg, SubCommand, ArgMatches};
+use std::path::Path;
+use wfiles::db;
+use wfiles::error::{Result, Status};
+
+enum DbAction {
+    Upgrade,
+}
+
+fn describe_upgrade(dbfile: &Path, upgrade: &db::Upgrade) -> String {
+    let mut st = format!("upgraded {} from schema {:#06x} to {:#06x} (previous version kept as {})",
+                         dbfile.display(), upgrade.from, upgrade.to, upgrade.backup.display());
+    if upgrade.n_quarantined > 0 {
+        st.push_str(&format!("\n{} files recorded by relative path were moved to table quarantined_files",
+                             upgrade.n_quarantined));
+    }
+    st
+}
+
+/* tells that a stash file was upgraded, as when opened by another subcommand */
+pub fn report_upgrade(dbfile: &Path, upgrade: &db::Upgrade) {
+    eprintln!("{}", describe_upgrade(dbfile, upgrade));
+}
+
+/* maintenance of the stash file itself */
+pub struct DbOperation<'a> {
+    dbfile: &'a str,
+    action: DbAction,
+}
+
+impl<'a> DbOperation<'a> {
+    pub fn from_args(sub_m: &'a ArgMatches<'a>) -> Result<Self> {
+        let mut _dbfile : &str = db::DEFAULT_DB;
+
+        if let Some(_db) = sub_m.value_of("db") {
+            _dbfile = _db;
+        }
+
+        let _action = match sub_m.subcommand() {
+            ("upgrade", Some(_)) => DbAction::Upgrade,
+            _ => unreachable!(), // a subcommand is required
+        };
+
+        return Ok(DbOperation {
+            dbfile: _dbfile,
+            action: _action,
+        });
+    }
+
+    pub fn do_operation(&self) -> Result<Status> {
+        match self.action {
+            DbAction::Upgrade => match db::DataBase::upgrade(self.dbfile)? {
+                Some(_upgrade) => println!("{}", describe_upgrade(Path::new(self.dbfile), &_upgrade)),
+                None => println!("{} is up to date", self.dbfile),
+            },
+        }
+        Ok(Status::Done)
+    }
+}
+
+pub fn args_config<'a, 'b>() -> App<'a, 'b> {
+    return SubCommand::with_name("db")
+            .about("Maintains the stash file")
+            .usage("wfiles db [-d <FILE>] upgrade")
+            .setting(AppSettings::VersionlessSubcommands)
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .arg(Arg::with_name("db")
+                 .short("d")
+                 .value_name("FILE")
+                 .default_value("stash.db")
+                 .help("Sets stash file"))
+            .subcommand(SubCommand::with_name("upgrade")
+                .about("Upgrades a stash file written by an older version of wfiles (keeping a copy of it)"));
+}
@@ -0,0 +1,229 @@
+use clap::{App, Arg, SubCommand, ArgMatches};
+use regex::Regex;
+use std::io;
+use wfiles::db;
+use wfiles::db::DupFile;
+use wfiles::dup_prune::{self, KeepStrategy, DirBasedPruneRule, PrunePlan};
+use wfiles::dup_confirm;
+use wfiles::escape;
+use wfiles::error::{Error, Result, Status, Context};
+
+pub struct DupOperation<'a> {
+    dbfile: &'a str,
+    verbose: bool,
+    to_prune: bool,
+    confirm: bool,
+}
+
+impl<'a> DupOperation<'a> {
+    pub fn from_args(sub_m: &'a ArgMatches<'a>) -> Result<Self> {
+        let mut _dbfile : &str = db::DEFAULT_DB;
+ 
+        if let Some(_db) = sub_m.value_of("db") {
+            _dbfile = _db;
+        }
+
+        return Ok(DupOperation {
+            dbfile: _dbfile,
+            verbose: sub_m.is_present("verbose"),
+            to_prune: sub_m.is_present("prune"),
+            // nothing is pruned on the strength of digests alone
+            confirm: sub_m.is_present("confirm") || sub_m.is_present("prune"),
+        });
+    }
+
+    pub fn do_operation(&self) -> Result<Status> {
+        let store = db::DataBase::for_reading(self.dbfile)?;
+        let mut dup_state = db::IdentifyDupsStatement::new(&store)?;
+        let mut dups = dup_state.get_dups()?;
+        let mut n_failed : usize = 0;
+        if self.confirm {
+            let report;
+            (dups, report) = dup_confirm::confirm_sets(&dups);
+            for (path, reason) in &report.unreadable {
+                eprintln!("{}: {}", escape::display(path), reason);
+            }
+            for (path, n_groups) in &report.split {
+                eprintln!("set of {} did not fully match byte for byte, split into {} groups of matching files",
+                          escape::display(path), n_groups);
+            }
+            n_failed = report.unreadable.len();
+        }
+        let mut how_much_would_be_freed : u64 = 0;
+        let mut n_sets : usize = 0;
+        let mut n_hardlink_sets : usize = 0;
+        for dup in &dups {  
+            // links to a single inode take no extra space and are not duplicates
+            if dup.num_copies() < 2 {
+                n_hardlink_sets += 1;
+                continue;
+            }
+            n_sets += 1;
+            how_much_would_be_freed += dup.freeable_bytes();
+            if self.verbose {
+                println!("{} [{}]", escape::display(&dup.one_path), dup.confidence);
+            } else {
+                print!("{}", escape::display(&dup.one_path));
+            }
+            for (idx, other_path) in (1..).zip(dup.other_paths.iter()) {
+                if self.verbose {
+                    if dup.is_hardlink(idx) {
+                        println!("`== {} (hardlink)", escape::display(other_path));
+                    } else {
+                        println!("`-- {}", escape::display(other_path));
+                    }
+                } else {
+                    print!(",{}", escape::display(other_path));
+                }
+            }
+            if !self.verbose { println!(); }
+        }
+        /* files that could not be slow hashed (e.g. on unplugged media) only match by fasthash,
+           or just by size if they could not be hashed at all */
+        let mut probable_dups = dup_state.get_probable_dups()?;
+        probable_dups.extend(dup_state.get_size_dups()?);
+        for dup in &probable_dups {
+            if self.verbose {
+                println!("{} [{}, probable]", escape::display(&dup.one_path), dup.confidence);
+                for (idx, other_path) in (1..).zip(dup.other_paths.iter()) {
+                    if dup.is_hardlink(idx) {
+                        println!("`== {} (hardlink)", escape::display(other_path));
+                    } else {
+                        println!("`?? {}", escape::display(other_path));
+                    }
+                }
+            } else {
+                let other_paths : Vec<_> = dup.other_paths.iter().map(|_p| escape::display(_p)).collect();
+                println!("?{},{}", escape::display(&dup.one_path), other_paths.join(","));
+            }
+        }
+        if self.verbose {
+            if self.confirm {
+                println!("{} sets of duplicate files found (byte-verified)", n_sets);
+            } else {
+                println!("{} sets of duplicate files found (by {} digest)", n_sets,
+                         store.algorithms().slow);
+            }
+            if !probable_dups.is_empty() {
+                println!("{} sets of probable duplicates are pending hashing", probable_dups.len());
+            }
+            if n_hardlink_sets > 0 {
+                println!("{} sets of hardlinks to the same file were not counted as duplicates",
+                    n_hardlink_sets);
+            }
+            println!("{} bytes would be freed by removing duplicates",
+                how_much_would_be_freed);
+        }
+
+        /* files that could not be compared are left out of their sets */
+        let status = match n_failed {
+            0 => Status::Done,
+            _ => Status::FilesSkipped
+        };
+        if !self.to_prune {
+            return Ok(status);
+        }
+
+        let plan = collect_dup_path_rules(&dups)?;
+        for rule in plan.rules() {
+            println!("{}", rule);
+        }
+        Ok(status)
+    }
+}
+
+fn parse_choice(s : &str, max_idx: usize, default: Option<KeepStrategy>) 
+    -> std::result::Result<KeepStrategy, &'static str>
+{
+    if let Some(d) = default {
+        if let KeepStrategy::KEEP_THIS_OF_THESE(_) = d {
+            return Err("Cannot use \"keep this of these\" rule as default rule");
+        }
+        if let KeepStrategy::KEEP_THIS_OF_ANY(_) = d {
+            return Err("Cannot use \"keep this of any\" rule as default rule");
+        }
+    }
+
+    let re_keep_as_is = Regex::new("^a").unwrap();
+    let re_keep_this_of_these = Regex::new("^b ([0-9]+)").unwrap();
+    let re_keep_this_of_any = Regex::new("^c ([0-9]+)").unwrap();
+    let re_keep_any_one = Regex::new("^d").unwrap();
+    let re_keep_oldest = Regex::new("^e").unwrap();
+    let re_keep_newest = Regex::new("^f").unwrap();
+
+    if re_keep_as_is.is_match(s) {
+        return Ok(KeepStrategy::KEEP_AS_IS);
+    } else if re_keep_this_of_these.is_match(s) {
+        let idx = re_keep_this_of_these.captures(s).unwrap().get(1).unwrap().as_str().parse::<usize>()
+            .map_err(|_| "Invalid index for Keep This of These strategy")?;
+        if idx == 0 || idx > max_idx {
+            return Err("Invalid index for Keep This of These strategy");
+        }
+        return Ok(KeepStrategy::KEEP_THIS_OF_THESE(idx));
+    } else if re_keep_this_of_any.is_match(s) {
+        let idx = re_keep_this_of_any.captures(s).unwrap().get(1).unwrap().as_str().parse::<usize>()
+            .map_err(|_| "Invalid index for Keep This Of Any strategy")?;
+        if idx == 0 || idx > max_idx {
+            return Err("Invalid index for Keep This Of Any strategy");
+        }
+        return Ok(KeepStrategy::KEEP_THIS_OF_ANY(idx));
+    } else if re_keep_any_one.is_match(s) {
+        return Ok(KeepStrategy::KEEP_ANY_ONE);
+    } else if re_keep_oldest.is_match(s) {
+        return Ok(KeepStrategy::KEEP_OLDEST);
+    } else if re_keep_newest.is_match(s) {
+        return Ok(KeepStrategy::KEEP_NEWEST);
+    }
+
+    return match default {
+        None => Err("No default keep strategy set"),
+        Some(t) => Ok(t),
+    }; 
+}
+
+/* asks for a rule for each combination of directories duplicates reside in */
+fn collect_dup_path_rules(dups: &[DupFile]) -> Result<PrunePlan>
+{
+    let mut plan = PrunePlan::new();
+    let cases = dup_prune::rule_cases(dups);
+    for (idx, c) in (1..).zip(cases.iter()) {
+        println!("How should we handle this? [{} of {} decisions]\n{}", 
+                 idx, cases.len(), c.dup.format_minimal(true));
+        println!("a. {}", KeepStrategy::KEEP_AS_IS);
+        println!("b. {}", KeepStrategy::KEEP_THIS_OF_THESE(1));
+        println!("c. {}", KeepStrategy::KEEP_THIS_OF_ANY(1));
+        println!("d. {}", KeepStrategy::KEEP_ANY_ONE);
+        println!("e. {}", KeepStrategy::KEEP_OLDEST);
+        println!("f. {}", KeepStrategy::KEEP_NEWEST);
+
+        let mut choice = String::new();
+        io::stdin().read_line(&mut choice).context("error reading choice")?;
+
+        let choice_type = parse_choice(&choice, c.dirs.len(), 
+            Some(KeepStrategy::KEEP_AS_IS)).map_err(|_e| Error::Usage(_e.to_string()))?;
+
+        plan.insert(c.key.clone(), DirBasedPruneRule { verdict: choice_type, paths: c.dirs.clone() });
+    }
+    return Ok(plan);
+}
+
+pub fn args_config<'a, 'b>() -> App<'a, 'b> {
+    return SubCommand::with_name("dup")
+            .about("Identifies duplicates in stash file")
+            .usage("wfiles dup [-v] [-c] [-d <FILE>] [-p]")
+            .arg(Arg::with_name("db")
+                 .short("d")
+                 .value_name("FILE")
+                 .default_value("stash.db")
+                 .help("Sets stash file"))
+            .arg(Arg::with_name("verbose")
+                .short("v")
+                .help("Verbose mode"))
+            .arg(Arg::with_name("confirm")
+                .short("c")
+                .help("Compares duplicates byte for byte, splitting sets that do not match"))
+            .arg(Arg::with_name("prune")
+                .short("p")
+                .help("Prunes duplicates according to strategy (implies -c)"));
+}
+
@@ -0,0 +1,109 @@
+use clap::{App, Arg, SubCommand, ArgMatches};
+use std::collections::BTreeMap;
+use wfiles::db;
+use wfiles::escape;
+use wfiles::error::{Error, Result, Status};
+
+/* lists the files that stash runs could not record, so as to tell whether a medium
+   was stashed completely */
+pub struct ErrorsOperation<'a> {
+    dbfile: &'a str,
+    run_id: Option<i64>,   // default: the latest run
+    all_runs: bool,
+    medium: Option<&'a str>,
+    counts_only: bool,
+}
+
+impl<'a> ErrorsOperation<'a> {
+    pub fn from_args(sub_m: &'a ArgMatches<'a>) -> Result<Self> {
+        let mut _dbfile : &str = db::DEFAULT_DB;
+
+        if let Some(_db) = sub_m.value_of("db") {
+            _dbfile = _db;
+        }
+
+        let _run_id = sub_m.value_of("run").map(|_r| _r.parse::<i64>()
+            .map_err(|_| Error::Usage("error during parsing of run id to integer".to_string())))
+            .transpose()?;
+
+        return Ok(ErrorsOperation {
+            dbfile: _dbfile,
+            run_id: _run_id,
+            all_runs: sub_m.is_present("all"),
+            medium: sub_m.value_of("medium"),
+            counts_only: sub_m.is_present("count"),
+        });
+    }
+
+    pub fn do_operation(&self) -> Result<Status> {
+        let store = db::DataBase::for_reading(self.dbfile)?;
+        if let Some(_medium) = self.medium {
+            let mut list_statement = db::MediaListStatement::new(&store)?;
+            if !list_statement.get_media()?.iter().any(|_m| _m.medium == _medium) {
+                return Err(Error::NotFound(format!("no medium named {} found in stash file", _medium)));
+            }
+        }
+        let mut error_statement = db::ErrorListStatement::new(&store)?;
+        let run_id = match self.all_runs {
+            true => None,
+            false => match error_statement.run(self.run_id)? {
+                Some(_run_id) => Some(_run_id),
+                None => return Err(Error::NotFound(match self.run_id {
+                    Some(_run_id) => format!("no run {} found in stash file", _run_id),
+                    None => "no run found in stash file".to_string()
+                }))
+            }
+        };
+
+        let errors = error_statement.errors(run_id, self.medium)?;
+        let mut counts : BTreeMap<(i64, &str), BTreeMap<String, usize>> = BTreeMap::new();
+        for e in &errors {
+            *counts.entry((e.run_id, &e.medium)).or_default()
+                .entry(e.kind.to_string()).or_insert(0) += 1;
+            if !self.counts_only {
+                println!("{} {}: {}", e.kind.to_string().to_uppercase(), escape::display(&e.path), e.reason);
+            }
+        }
+
+        for ((_run_id, _medium), _kinds) in &counts {
+            let kinds : Vec<String> = _kinds.iter().map(|(_k, _n)| format!("{} {}", _n, _k)).collect();
+            println!("run {}, medium {}: {} errors ({})", _run_id, _medium,
+                     _kinds.values().sum::<usize>(), kinds.join(", "));
+        }
+        match (errors.is_empty(), run_id) {
+            (true, Some(_run_id)) => println!("no errors recorded by run {}", _run_id),
+            (true, None) => println!("no errors recorded"),
+            (false, _) => ()
+        }
+
+        match errors.is_empty() {
+            true => Ok(Status::Done),
+            false => Ok(Status::FilesSkipped)
+        }
+    }
+}
+
+pub fn args_config<'a, 'b>() -> App<'a, 'b> {
+    return SubCommand::with_name("errors")
+            .about("Lists the files that stash runs could not read")
+            .usage("wfiles errors [-c] [-d <FILE>] [-r <RUN> | -a] [MEDIUM]")
+            .arg(Arg::with_name("db")
+                 .short("d")
+                 .value_name("FILE")
+                 .default_value("stash.db")
+                 .help("Sets stash file"))
+            .arg(Arg::with_name("run")
+                 .short("r")
+                 .value_name("RUN")
+                 .help("Lists the errors of run RUN (default: the latest run)"))
+            .arg(Arg::with_name("all")
+                 .short("a")
+                 .conflicts_with("run")
+                 .help("Lists the errors of all runs"))
+            .arg(Arg::with_name("count")
+                 .short("c")
+                 .help("Only counts the errors of each kind"))
+            .arg(Arg::with_name("medium")
+                .value_name("MEDIUM")
+                .help("Medium whose errors are listed (default: all media)"));
+}
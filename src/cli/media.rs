@@ -0,0 +1,131 @@
+use clap::{App, AppSettings, Arg, SubCommand, ArgMatches};
+use std::path::Path;
+use wfiles::db;
+use wfiles::error::{Error, Result, Status};
+use super::dbcmd;
+
+enum MediaAction<'a> {
+    List,
+    Rename(&'a str, &'a str),
+    Describe(&'a str, &'a str),
+    Delete(&'a str),
+}
+
+pub struct MediaOperation<'a> {
+    dbfile: &'a str,
+    action: MediaAction<'a>,
+}
+
+impl<'a> MediaOperation<'a> {
+    pub fn from_args(sub_m: &'a ArgMatches<'a>) -> Result<Self> {
+        let mut _dbfile : &str = db::DEFAULT_DB;
+
+        if let Some(_db) = sub_m.value_of("db") {
+            _dbfile = _db;
+        }
+
+        let _action = match sub_m.subcommand() {
+            ("rename", Some(m)) => MediaAction::Rename(
+                m.value_of("medium").unwrap(), m.value_of("new_name").unwrap()),
+            ("describe", Some(m)) => MediaAction::Describe(
+                m.value_of("medium").unwrap(), m.value_of("descr").unwrap()),
+            ("delete", Some(m)) => MediaAction::Delete(
+                m.value_of("medium").unwrap()),
+            _ => MediaAction::List,
+        };
+
+        return Ok(MediaOperation {
+            dbfile: _dbfile,
+            action: _action,
+        });
+    }
+
+    pub fn do_operation(&self) -> Result<Status> {
+        if let MediaAction::List = self.action {
+            let store = db::DataBase::for_reading(self.dbfile)?;
+            let mut list_state = db::MediaListStatement::new(&store)?;
+            for m in list_state.get_media()? {
+                print!("{} ({}): {} files, {} bytes", m.medium,
+                       m.comment.unwrap_or_default(), m.num_files, m.size);
+                if let Some(uuid) = m.uuid {
+                    print!(" [{} UUID={}", m.fstype.unwrap_or_default(), uuid);
+                    if let Some(label) = m.label {
+                        print!(" LABEL={}", label);
+                    }
+                    if let Some(capacity) = m.capacity {
+                        print!(", {} bytes capacity", capacity);
+                    }
+                    print!("]");
+                }
+                println!();
+            }
+            return Ok(Status::Done);
+        }
+
+        let mut store = db::DataBase::for_updating(self.dbfile)?;
+        if let Some(_upgrade) = store.upgraded() {
+            dbcmd::report_upgrade(Path::new(self.dbfile), _upgrade);
+        }
+        let trans = db::DBTransaction::new(&mut store)?;
+        let mut edit_state = db::MediaEditStatement::new(&trans)?;
+
+        let (medium, found) = match self.action {
+            MediaAction::Rename(medium, new_name) =>
+                (medium, edit_state.rename(medium, new_name)?),
+            MediaAction::Describe(medium, descr) =>
+                (medium, edit_state.describe(medium, descr)?),
+            MediaAction::Delete(medium) => match edit_state.delete(medium)? {
+                Some(n_files) => {
+                    println!("removed medium {} and {} of its files from stash",
+                             medium, n_files);
+                    (medium, true)
+                },
+                None => (medium, false),
+            },
+            MediaAction::List => unreachable!(),
+        };
+
+        if !found {
+            return Err(Error::NotFound(format!("no medium named {} found in stash file", medium)));
+        }
+
+        drop(edit_state);
+        trans.commit()?;
+        Ok(Status::Done)
+    }
+}
+
+pub fn args_config<'a, 'b>() -> App<'a, 'b> {
+    return SubCommand::with_name("media")
+            .about("Lists and edits media recorded in stash file")
+            .usage("wfiles media [-d <FILE>] [list|rename|describe|delete]")
+            .setting(AppSettings::VersionlessSubcommands)
+            .arg(Arg::with_name("db")
+                 .short("d")
+                 .value_name("FILE")
+                 .default_value("stash.db")
+                 .help("Sets stash file"))
+            .subcommand(SubCommand::with_name("list")
+                .about("Lists media with their descriptions (default)"))
+            .subcommand(SubCommand::with_name("rename")
+                .about("Renames a medium")
+                .arg(Arg::with_name("medium")
+                    .required(true)
+                    .value_name("MEDIUM"))
+                .arg(Arg::with_name("new_name")
+                    .required(true)
+                    .value_name("NEW_NAME")))
+            .subcommand(SubCommand::with_name("describe")
+                .about("Sets the description of a medium")
+                .arg(Arg::with_name("medium")
+                    .required(true)
+                    .value_name("MEDIUM"))
+                .arg(Arg::with_name("descr")
+                    .required(true)
+                    .value_name("DESCRIPTION")))
+            .subcommand(SubCommand::with_name("delete")
+                .about("Deletes a medium and all of its files from stash")
+                .arg(Arg::with_name("medium")
+                    .required(true)
+                    .value_name("MEDIUM")));
+}
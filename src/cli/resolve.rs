@@ -0,0 +1,128 @@
+use clap::{App, Arg, SubCommand, ArgMatches};
+use std::path::Path;
+use wfiles::db;
+use wfiles::escape;
+use wfiles::fs;
+use wfiles::hasher;
+use wfiles::stash;
+use wfiles::error::{Error, Result, Status};
+use super::dbcmd;
+
+/* completes the slow hashing of colliding files whose media were not available
+   when the collision was found */
+pub struct ResolveOperation<'a> {
+    dbfile: &'a str,
+    bufsize: u64,
+    force_read: bool,
+    verbose: bool,
+}
+
+impl<'a> ResolveOperation<'a> {
+    pub fn from_args(sub_m: &'a ArgMatches<'a>) -> Result<Self> {
+        let mut _dbfile : &str = db::DEFAULT_DB;
+        let mut _bufsize : u64 = stash::DEFAULT_READ_BUFFER_SIZE;
+
+        if let Some(_db) = sub_m.value_of("db") {
+            _dbfile = _db;
+        }
+
+        if let Some(_buf) = sub_m.value_of("buf") {
+            _bufsize = _buf.parse::<u64>()
+                .map_err(|_| Error::Usage("error during parsing of buffer size to integer".to_string()))?
+                * 1024 * 1024;
+        }
+
+        return Ok(ResolveOperation {
+            dbfile: _dbfile,
+            bufsize: _bufsize,
+            force_read: sub_m.is_present("force_read"),
+            verbose: sub_m.is_present("verbose"),
+        });
+    }
+
+    pub fn do_operation(&self) -> Result<Status> {
+        let mut store = db::DataBase::for_updating(self.dbfile)?;
+        if let Some(_upgrade) = store.upgraded() {
+            dbcmd::report_upgrade(Path::new(self.dbfile), _upgrade);
+        }
+        let algorithms = store.algorithms();
+        let trans = db::DBTransaction::new(&mut store)?;
+        let mut check_collision = db::CheckCollisionStatement::new(&trans)?;
+        let mut slow_hasher = fs::FileHasher::new(hasher::SlowHasher::new(algorithms.slow)?,
+                                                  self.bufsize, self.force_read);
+
+        let verbose = self.verbose;
+        let (n_hashed, n_pending) = check_collision.resolve(&mut slow_hasher, |path| if verbose {
+            eprintln!("> slow hashing {}", path);
+        })?;
+        for f in check_collision.take_failures() {
+            eprintln!("{}: {}", escape::display(&f.path), f.reason);
+        }
+        drop(check_collision);
+        trans.commit()?;
+
+        println!("{} files slow hashed, {} still pending", n_hashed, n_pending);
+        match n_pending {
+            0 => Ok(Status::Done),
+            _ => Ok(Status::FilesSkipped)
+        }
+    }
+}
+
+pub fn args_config<'a, 'b>() -> App<'a, 'b> {
+    return SubCommand::with_name("resolve")
+            .about("Slow hashes colliding files that were left pending (e.g. on media not plugged in)")
+            .usage("wfiles resolve [-v] [-r] [-d <FILE>] [-b <SIZE>]")
+            .arg(Arg::with_name("db")
+                 .short("d")
+                 .value_name("FILE")
+                 .default_value("stash.db")
+                 .help("Sets stash file"))
+            .arg(Arg::with_name("force_read")
+                 .short("r")
+                 .help("Don't mmap(2), use read(2) instead"))
+            .arg(Arg::with_name("buf")
+                .short("b")
+                .value_name("SIZE")
+                .default_value("8")
+                .help("Read-buffer size (in megabytes)"))
+            .arg(Arg::with_name("verbose")
+                .short("v")
+                .help("Verbose mode"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wfiles::testutil::{self, ScratchDir};
+
+    #[test]
+    fn slow_hashes_files_left_pending_once_they_are_available() {
+        let scratch = ScratchDir::new("resolve");
+        scratch.write("files/one/a", b"same contents\n");
+        scratch.write("files/two/b", b"same contents\n");
+        let dbfile = scratch.join("stash.db");
+        let options = |dir: &str| stash::StashOptions { dbfile: dbfile.clone(), threads: 1,
+                                                        topdirs: vec![scratch.join("files").join(dir)],
+                                                        ..stash::StashOptions::default() };
+        /* a is away (as on a medium not plugged in) when b collides with it */
+        {
+            let _turn = testutil::take_turn();
+            options("one").run(|_| ()).unwrap();
+            std::fs::rename(scratch.join("files/one"), scratch.join("away")).unwrap();
+            assert_eq!(options("two").run(|_| ()).unwrap().n_failed, 1);
+        }
+        let resolve = ResolveOperation { dbfile: dbfile.to_str().unwrap(), bufsize: stash::DEFAULT_READ_BUFFER_SIZE,
+                                         force_read: false, verbose: false };
+        assert_eq!(resolve.do_operation().unwrap(), Status::FilesSkipped);
+
+        std::fs::rename(scratch.join("away"), scratch.join("files/one")).unwrap();
+        assert_eq!(resolve.do_operation().unwrap(), Status::Done);
+        let conn = rusqlite::Connection::open(&dbfile).unwrap();
+        let slowhashes : Vec<(Option<String>, bool)> = conn.prepare(
+            "SELECT slowhash, pending_slowhash from files order by fname").unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().collect::<rusqlite::Result<_>>().unwrap();
+        assert!(slowhashes[0].0.is_some() && !slowhashes[0].1);
+        assert_eq!(slowhashes[0], slowhashes[1]);
+    }
+}
@@ -0,0 +1,87 @@
+use clap::{App, Arg, SubCommand, ArgMatches};
+use wfiles::db;
+use wfiles::escape;
+use wfiles::error::{Error, Result, Status};
+
+/* lists the stash runs recorded, who ran them where and how they went */
+pub struct RunsOperation<'a> {
+    dbfile: &'a str,
+    medium: Option<&'a str>,
+    limit: Option<u64>,
+}
+
+impl<'a> RunsOperation<'a> {
+    pub fn from_args(sub_m: &'a ArgMatches<'a>) -> Result<Self> {
+        let mut _dbfile : &str = db::DEFAULT_DB;
+
+        if let Some(_db) = sub_m.value_of("db") {
+            _dbfile = _db;
+        }
+
+        let _limit = sub_m.value_of("limit").map(|_n| _n.parse::<u64>()
+            .map_err(|_| Error::Usage("error during parsing of number of runs to integer".to_string())))
+            .transpose()?;
+
+        return Ok(RunsOperation {
+            dbfile: _dbfile,
+            medium: sub_m.value_of("medium"),
+            limit: _limit,
+        });
+    }
+
+    pub fn do_operation(&self) -> Result<Status> {
+        let store = db::DataBase::for_reading(self.dbfile)?;
+        if let Some(_medium) = self.medium {
+            let mut list_statement = db::MediaListStatement::new(&store)?;
+            if !list_statement.get_media()?.iter().any(|_m| _m.medium == _medium) {
+                return Err(Error::NotFound(format!("no medium named {} found in stash file", _medium)));
+            }
+        }
+        let mut run_statement = db::RunListStatement::new(&store)?;
+        for run in run_statement.runs(self.medium, self.limit)? {
+            print!("run {}", run.run_id);
+            if let (Some(_user), Some(_host)) = (&run.user, &run.hostname) {
+                print!(" by {}@{}", _user, _host);
+            }
+            print!(", started {}", run.started);
+            match (&run.finished, run.completed) {
+                (Some(_finished), true) => println!(", completed {}", _finished),
+                (Some(_finished), false) => println!(", interrupted {}", _finished),
+                (None, _) => println!(", not completed"),
+            }
+            for (path, medium) in &run.paths {
+                match medium {
+                    Some(_medium) => println!("    {} (medium {})", escape::display(path), _medium),
+                    None => println!("    {}", escape::display(path)),
+                }
+            }
+            if let Some((_quick, _slow)) = &run.algorithms {
+                print!("    hashed with {} (quick) and {} (slow)", _quick, _slow);
+                if !run.options.is_empty() {
+                    print!(", {}", run.options.join(", "));
+                }
+                println!();
+            }
+            println!("    {} files, {} bytes recorded, {} errors", run.n_files, run.n_bytes, run.n_errors);
+        }
+        Ok(Status::Done)
+    }
+}
+
+pub fn args_config<'a, 'b>() -> App<'a, 'b> {
+    return SubCommand::with_name("runs")
+            .about("Lists the stash runs recorded in the stash file")
+            .usage("wfiles runs [-d <FILE>] [-n <NUM>] [MEDIUM]")
+            .arg(Arg::with_name("db")
+                 .short("d")
+                 .value_name("FILE")
+                 .default_value("stash.db")
+                 .help("Sets stash file"))
+            .arg(Arg::with_name("limit")
+                 .short("n")
+                 .value_name("NUM")
+                 .help("Lists only the NUM latest runs"))
+            .arg(Arg::with_name("medium")
+                .value_name("MEDIUM")
+                .help("Medium whose runs are listed (default: all media)"));
+}
@@ -0,0 +1,299 @@
+use clap::{App, AppSettings, Arg, SubCommand, ArgMatches};
+use std::path::{Path, PathBuf};
+
+use wfiles::db;
+use wfiles::hasher;
+use wfiles::fs;
+use wfiles::filter::PathFilter;
+use wfiles::escape;
+use wfiles::stash::{self, StashOptions, StashEvent};
+use wfiles::error::{Error, Result, Status};
+use super::dbcmd;
+
+const DEFAULT_PATH : &str = ".";
+
+pub struct StashOperation {
+    options: StashOptions,
+    verbose: bool
+}
+
+impl StashOperation {
+    pub fn from_args(sub_m: &ArgMatches) -> Result<Self> {
+        let mut _topdirs : Vec<PathBuf> = Vec::new();
+        let mut _dbfile : &str = db::DEFAULT_DB;
+        let mut _bufsize : u64 = stash::DEFAULT_READ_BUFFER_SIZE;
+        let mut _threads : usize = std::thread::available_parallelism()
+            .map_or(1, |_n| _n.get());
+        let _force_db_overwrite : bool;
+        let _incremental : bool;
+        let _verbose : bool;
+        let _force_read : bool;
+        let _only_slowhash : bool;
+
+        /* when resuming without paths, those of the interrupted run are used */
+        if let Some(_p) = sub_m.values_of_os("path") {
+            _topdirs = _p.map(PathBuf::from).collect();
+        } else if !sub_m.is_present("resume") {
+            _topdirs.push(PathBuf::from(DEFAULT_PATH));
+        }
+
+        if let Some(_db) = sub_m.value_of("db") {
+            _dbfile = _db;
+        }
+
+        if let Some(_buf) = sub_m.value_of("buf") {
+            _bufsize = _buf.parse::<u64>()
+                .map_err(|_| Error::Usage("error during parsing of buffer size to integer".to_string()))?
+                * 1024 * 1024;
+        }
+
+        if let Some(_t) = sub_m.value_of("threads") {
+            _threads = _t.parse::<usize>()
+                .map_err(|_| Error::Usage("error during parsing of number of threads to integer".to_string()))?
+                .max(1);
+        }
+
+        let patterns = |name: &str| -> Vec<&str> {
+            sub_m.values_of(name).map_or(Vec::new(), |_v| _v.collect())
+        };
+        let _filter = PathFilter::new(&patterns("include"), &patterns("exclude"),
+                                      &patterns("include_regex"), &patterns("exclude_regex"),
+                                      sub_m.is_present("ignore_files"))?;
+
+        let depth = |name: &str| -> Result<Option<usize>> {
+            sub_m.value_of(name).map(|_d| _d.parse::<usize>()
+                .map_err(|_| Error::Usage(format!("error during parsing of {} to integer", name))))
+                .transpose()
+        };
+        let defaults = fs::WalkOptions::default();
+        let _walk_options = fs::WalkOptions {
+            follow_links: sub_m.is_present("follow_links"),
+            same_file_system: sub_m.is_present("one_file_system"),
+            min_depth: depth("min_depth")?.unwrap_or(defaults.min_depth),
+            max_depth: depth("max_depth")?.unwrap_or(defaults.max_depth),
+            symlinks: sub_m.is_present("record_symlinks")
+        };
+
+        _verbose = sub_m.is_present("verbose");
+        _force_db_overwrite = sub_m.is_present("force_db_overwrite");
+        _incremental = sub_m.is_present("incremental");
+        _force_read = sub_m.is_present("force_read");
+        let _quick_algorithm = sub_m.value_of("quick_hash").map(|_a| _a.parse::<hasher::QuickAlgorithm>().unwrap());
+        let _slow_algorithm = match sub_m.is_present("force_sha512") {
+            true => Some(hasher::SlowAlgorithm::Sha512),
+            false => sub_m.value_of("slow_hash").map(|_a| _a.parse::<hasher::SlowAlgorithm>().unwrap())
+        };
+        _only_slowhash = sub_m.is_present("only_slowhash");
+
+        let _options = StashOptions {
+            topdirs: _topdirs,
+            dbfile: PathBuf::from(_dbfile),
+            medium: sub_m.value_of("medium").map(String::from),
+            medium_descr: sub_m.value_of("medium_descr").map(String::from),
+            bufsize: _bufsize,
+            threads: _threads,
+            walk_options: _walk_options,
+            filter: _filter,
+            force_db_overwrite: _force_db_overwrite,
+            incremental: _incremental,
+            resume: sub_m.is_present("resume"),
+            sync: sub_m.is_present("sync"),
+            archives: sub_m.is_present("archives"),
+            force_read: _force_read,
+            quick_algorithm : _quick_algorithm,
+            slow_algorithm : _slow_algorithm,
+            only_slowhash : _only_slowhash,
+            size_first : sub_m.is_present("size_first"),
+            partial : sub_m.is_present("partial")
+        };
+
+        Ok(StashOperation {
+            options: _options,
+            verbose: _verbose
+        })
+    }
+
+    pub fn do_operation(&self) -> Result<Status> {
+        let verbose = self.verbose;
+        stash::catch_signals();
+        let summary = self.options.run(|event| match event {
+            StashEvent::Upgraded { upgrade } => dbcmd::report_upgrade(&self.options.dbfile, upgrade),
+            StashEvent::Root { path, medium, medium_path } => if verbose {
+                eprintln!("{} is recorded as {} in medium {}", escape::display(path),
+                          escape::display(&Path::new("/").join(medium_path)), medium);
+            },
+            StashEvent::Started { algorithms, resumed, .. } => if verbose {
+                eprintln!("hashing with {} (quick) and {} (slow)", algorithms.quick, algorithms.slow);
+                if let Some(_run) = resumed {
+                    eprintln!("resuming run {} after {} ({} files recorded)", _run.run_id,
+                              _run.last_path.as_deref().map_or("start".into(), escape::display), _run.n_files);
+                }
+            },
+            StashEvent::Processing { path, n, total } => if verbose {
+                eprint!("> processing {} ({}/{})", escape::display(path), n, total);
+            },
+            StashEvent::Processed { .. } => if verbose {
+                eprint!("\x1b[2K\r");
+            },
+            StashEvent::Failed { path, reason, .. } => eprintln!("{}: {}", escape::display(path), reason)
+        })?;
+
+        if summary.interrupted {
+            eprintln!("interrupted, {} files recorded by this run so far (resume with -R)", summary.n_recorded);
+        }
+        if self.options.sync {
+            println!("{} added, {} changed, {} moved, {} removed, {} unchanged",
+                     summary.n_added, summary.n_changed, summary.n_moved, summary.n_removed, 
+                     summary.n_unchanged);
+        }
+        if verbose && (self.options.incremental || self.options.resume || self.options.sync) {
+            eprintln!("{} of {} files were unchanged and skipped", summary.n_unchanged, summary.n_files);
+        }
+        if summary.n_failed > 0 {
+            eprintln!("{} files could not be read and were skipped (see wfiles errors)", summary.n_failed);
+        }
+        if summary.interrupted {
+            Ok(Status::Interrupted)
+        } else if summary.n_failed > 0 {
+            Ok(Status::FilesSkipped)
+        } else {
+            Ok(Status::Done)
+        }
+    }
+}
+
+pub fn args_config<'a, 'b>() -> App<'a, 'b> {
+    return SubCommand::with_name("stash")
+            .about("Creates a stash of recorded files")
+            .usage("wfiles stash [-v] [-f] [-i] [-R] [-S] [-a] [-r] [-s | --slow-hash <ALGO>] [--quick-hash <ALGO>] [-l] [-d <FILE>] [-m <MEDIUM>] [-c <DESCR>] [-b <SIZE>] [-t <NUM>] [-z] [-p] [-L | --record-symlinks] [-x] [--min-depth <N>] [--max-depth <N>] [--include <GLOB>]... [--exclude <GLOB>]... [--include-regex <REGEX>]... [--exclude-regex <REGEX>]... [--ignore-files] <PATH> ...")
+            .setting(AppSettings::TrailingVarArg)
+            .arg(Arg::with_name("db")
+                 .short("d")
+                 .value_name("FILE")
+                 .default_value("stash.db")
+                 .help("Sets stash file"))
+            .arg(Arg::with_name("medium")
+                 .short("m")
+                 .value_name("MEDIUM")
+                 .help("Names the medium holding the files (default: label or UUID of their filesystem, or else \"filesystem\")"))
+            .arg(Arg::with_name("medium_descr")
+                 .short("c")
+                 .value_name("DESCR")
+                 .help("Sets a description for the medium"))
+            .arg(Arg::with_name("force_db_overwrite")
+                 .short("f")
+                 .help("Forces the overwrite of an existing stash file"))
+            .arg(Arg::with_name("incremental")
+                 .short("i")
+                 .conflicts_with("force_db_overwrite")
+                 .help("Only hash new or changed files (by size, mtime and inode)"))
+            .arg(Arg::with_name("resume")
+                 .short("R")
+                 .long("resume")
+                 .conflicts_with("force_db_overwrite")
+                 .help("Resume the last (interrupted) run, skipping the files recorded so far"))
+            .arg(Arg::with_name("sync")
+                 .short("S")
+                 .long("sync")
+                 .conflicts_with("force_db_overwrite")
+                 .help("Sync stash with the paths: hash new or changed files, follow moved ones and remove vanished ones"))
+            .arg(Arg::with_name("archives")
+                 .short("a")
+                 .long("archives")
+                 .help("Also record the files inside .zip, .tar, .tar.gz and .tar.xz archives"))
+            .arg(Arg::with_name("force_read")
+                 .short("r")
+                 .help("Don't mmap(2), use read(2) instead"))
+            .arg(Arg::with_name("force_sha512")
+                .short("s")
+                .help("Prefer SHA512 over MD5 for slow hashing (same as --slow-hash sha512)"))
+            .arg(Arg::with_name("slow_hash")
+                .long("slow-hash")
+                .value_name("ALGO")
+                .possible_values(&hasher::SlowAlgorithm::NAMES)
+                .conflicts_with("force_sha512")
+                .help("Slow hashing algorithm of a new stash file (default: md5)"))
+            .arg(Arg::with_name("quick_hash")
+                .long("quick-hash")
+                .value_name("ALGO")
+                .possible_values(&hasher::QuickAlgorithm::NAMES)
+                .help("Quick hashing algorithm of a new stash file (default: xxh3)"))
+            .arg(Arg::with_name("only_slowhash")
+                .short("l")
+                .help("Disable quick hashing (and perform only slow hashing)"))
+            .arg(Arg::with_name("size_first")
+                .short("z")
+                .long("size-first")
+                .conflicts_with("only_slowhash")
+                .help("Only hash files whose size is shared with another file (the rest are hashed when needed)"))
+            .arg(Arg::with_name("partial")
+                .short("p")
+                .long("partial")
+                .conflicts_with("only_slowhash")
+                .help("Hash the first and last 16KiB of files, quick hashing them in full only on collision"))
+            .arg(Arg::with_name("verbose")
+                .short("v")
+                .help("Verbose mode"))
+            .arg(Arg::with_name("buf")
+                .short("b")
+                .value_name("SIZE")
+                .default_value("8")
+                .help("Read-buffer size (in megabytes)"))
+            .arg(Arg::with_name("threads")
+                .short("t")
+                .value_name("NUM")
+                .help("Number of hashing threads (default: number of CPUs)"))
+            .arg(Arg::with_name("follow_links")
+                .short("L")
+                .help("Follow symbolic links (loops are detected and reported)"))
+            .arg(Arg::with_name("record_symlinks")
+                .long("record-symlinks")
+                .conflicts_with("follow_links")
+                .help("Record symbolic links themselves, along with their targets"))
+            .arg(Arg::with_name("one_file_system")
+                .short("x")
+                .help("Do not cross filesystem boundaries"))
+            .arg(Arg::with_name("min_depth")
+                .long("min-depth")
+                .value_name("N")
+                .help("Skip files less than N levels below the paths (files in a path are at level 1)"))
+            .arg(Arg::with_name("max_depth")
+                .long("max-depth")
+                .value_name("N")
+                .help("Do not descend more than N levels below the paths"))
+            .arg(Arg::with_name("include")
+                .long("include")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only stash files whose name or relative path matches GLOB"))
+            .arg(Arg::with_name("exclude")
+                .long("exclude")
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Skip files and directories whose name or relative path matches GLOB"))
+            .arg(Arg::with_name("include_regex")
+                .long("include-regex")
+                .value_name("REGEX")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only stash files whose full path matches REGEX"))
+            .arg(Arg::with_name("exclude_regex")
+                .long("exclude-regex")
+                .value_name("REGEX")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Skip files and directories whose full path matches REGEX"))
+            .arg(Arg::with_name("ignore_files")
+                .long("ignore-files")
+                .help("Honor .wfilesignore files (gitignore syntax) found in traversed directories"))
+            .arg(Arg::with_name("path")
+                .required_unless("resume")
+//              .last(true) cannot use as it makes '--' mandatory :/
+                .allow_hyphen_values(true)
+                .multiple(true)
+                .value_name("PATH")
+                .help("Path(s) to examine"));
+}
+
@@ -0,0 +1,285 @@
+use clap::{App, Arg, SubCommand, ArgMatches};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use wfiles::db;
+use wfiles::fs;
+use wfiles::hasher;
+use wfiles::stash;
+use wfiles::error::{Error, Result, Status};
+use super::dbcmd;
+
+const CHECKPOINT_FILES : u64 = 1000;
+
+enum Outcome {
+    Ok,
+    Recorded,   // had no digest to compare against, one was recorded
+    Modified,   // size or mtime changed, as when edited
+    Corrupted,  // content changed though size and mtime did not
+    Missing
+}
+
+/* re-hashes recorded files and compares them against the stored digests */
+pub struct VerifyOperation<'a> {
+    dbfile: &'a str,
+    medium: Option<&'a str>,
+    limit: Option<u64>,
+    bufsize: u64,
+    force_read: bool,
+    verbose: bool,
+}
+
+impl<'a> VerifyOperation<'a> {
+    pub fn from_args(sub_m: &'a ArgMatches<'a>) -> Result<Self> {
+        let mut _dbfile : &str = db::DEFAULT_DB;
+        let mut _bufsize : u64 = stash::DEFAULT_READ_BUFFER_SIZE;
+
+        if let Some(_db) = sub_m.value_of("db") {
+            _dbfile = _db;
+        }
+
+        if let Some(_buf) = sub_m.value_of("buf") {
+            _bufsize = _buf.parse::<u64>()
+                .map_err(|_| Error::Usage("error during parsing of buffer size to integer".to_string()))?
+                * 1024 * 1024;
+        }
+
+        let _limit = sub_m.value_of("limit").map(|_n| _n.parse::<u64>()
+            .map_err(|_| Error::Usage("error during parsing of number of files to integer".to_string())))
+            .transpose()?;
+
+        return Ok(VerifyOperation {
+            dbfile: _dbfile,
+            medium: sub_m.value_of("medium"),
+            limit: _limit,
+            bufsize: _bufsize,
+            force_read: sub_m.is_present("force_read"),
+            verbose: sub_m.is_present("verbose"),
+        });
+    }
+
+    /* an error of the stash file stops the verification, one of the file only skips it */
+    fn verify_file(&self, f: &db::VerifiableFile,
+                   verify_statement: &mut db::VerifyStatement,
+                   quick_hasher: &mut fs::FileHasher<hasher::QuickHasher>,
+                   slow_hasher: &mut fs::FileHasher<hasher::SlowHasher>) 
+        -> Result<std::result::Result<Outcome, String>> {
+        let full_path = f.full_path.as_ref().unwrap();
+        /* symlinks are not verified, a file recorded through one (-L) is compared by its target */
+        let meta = match std::fs::metadata(full_path) {
+            Ok(_m) => _m,
+            Err(_e) if _e.kind() == std::io::ErrorKind::NotFound => return Ok(Ok(Outcome::Missing)),
+            Err(_e) => return Ok(Err(format!("failed to stat file ({})", _e)))
+        };
+        if meta.len() != f.size || Some(meta.mtime()) != f.mtime {
+            return Ok(Ok(Outcome::Modified));
+        }
+
+        /* the strongest digest recorded is the one compared */
+        let matches = if let Some(_slowhash) = &f.slowhash {
+            slow_hasher.hash_dbentry(full_path, f.size).map(|_d| &_d == _slowhash)
+        } else if let Some(_fasthash) = &f.fasthash {
+            quick_hasher.hash_dbentry(full_path, f.size).map(|_d| &_d == _fasthash)
+        } else if let Some(_parthash) = &f.parthash {
+            quick_hasher.hash_partial_dbentry(full_path, f.size).map(|_d| &_d == _parthash)
+        } else {
+            match quick_hasher.hash_dbentry(full_path, f.size) {
+                Ok(_fasthash) => verify_statement.set_fasthash(f.rowid, &_fasthash)?,
+                Err(_e) => return Ok(Err(format!("failed to read file ({})", _e)))
+            }
+            return Ok(Ok(Outcome::Recorded));
+        };
+        Ok(match matches {
+            Ok(true) => Ok(Outcome::Ok),
+            Ok(false) => Ok(Outcome::Corrupted),
+            Err(_e) => Err(format!("failed to read file ({})", _e))
+        })
+    }
+
+    pub fn do_operation(&self) -> Result<Status> {
+        let mut store = db::DataBase::for_updating(self.dbfile)?;
+        if let Some(_upgrade) = store.upgraded() {
+            dbcmd::report_upgrade(Path::new(self.dbfile), _upgrade);
+        }
+        let algorithms = store.algorithms();
+        let trans = db::DBTransaction::new(&mut store)?;
+        if let Some(_medium) = self.medium {
+            let mut media_statement = db::MediaInsertStatement::new(&trans)?;
+            if media_statement.medium_uuid(_medium)?.is_none() {
+                return Err(Error::NotFound(format!("no medium named {} found in stash file", _medium)));
+            }
+        }
+        let mut verify_statement = db::VerifyStatement::new(&trans)?;
+        let mut quick_hasher = fs::FileHasher::new(hasher::QuickHasher::new(algorithms.quick),
+                                                   self.bufsize, self.force_read);
+        let mut slow_hasher = fs::FileHasher::new(hasher::SlowHasher::new(algorithms.slow)?,
+                                                  self.bufsize, self.force_read);
+
+        let (mut n_ok, mut n_modified, mut n_corrupted, mut n_missing) = (0, 0, 0, 0);
+        let (mut n_unavailable, mut n_failed) = (0, 0);
+        let mut since_checkpoint : u64 = 0;
+        for f in verify_statement.files(self.medium, self.limit)? {
+            if f.full_path.is_none() {
+                n_unavailable += 1;
+                continue;
+            }
+            match self.verify_file(&f, &mut verify_statement, &mut quick_hasher, &mut slow_hasher)? {
+                Ok(outcome) => {
+                    match outcome {
+                        /* the others stay first in line for the next verification */
+                        Outcome::Ok | Outcome::Recorded => {
+                            n_ok += 1;
+                            if self.verbose {
+                                println!("OK {}", f.display_path);
+                            }
+                            verify_statement.verified(f.rowid)?;
+                        },
+                        Outcome::Modified => {
+                            n_modified += 1;
+                            println!("MODIFIED {}", f.display_path);
+                        },
+                        Outcome::Corrupted => {
+                            n_corrupted += 1;
+                            println!("CORRUPTED {}", f.display_path);
+                        },
+                        Outcome::Missing => {
+                            n_missing += 1;
+                            println!("MISSING {}", f.display_path);
+                        }
+                    }
+                },
+                Err(_e) => {
+                    n_failed += 1;
+                    eprintln!("{}: {}", f.display_path, _e);
+                }
+            }
+            since_checkpoint += 1;
+            if since_checkpoint >= CHECKPOINT_FILES {
+                trans.checkpoint()?;
+                since_checkpoint = 0;
+            }
+        }
+        drop(verify_statement);
+        trans.commit()?;
+
+        println!("{} ok, {} modified, {} corrupted, {} missing",
+                 n_ok, n_modified, n_corrupted, n_missing);
+        if n_failed > 0 {
+            println!("{} files could not be read", n_failed);
+        }
+        if n_unavailable > 0 {
+            println!("{} files were skipped, as their media are not available", n_unavailable);
+        }
+
+        if n_modified + n_corrupted + n_missing > 0 {
+            Ok(Status::ProblemsFound)
+        } else if n_failed > 0 {
+            Ok(Status::FilesSkipped)
+        } else {
+            Ok(Status::Done)
+        }
+    }
+}
+
+pub fn args_config<'a, 'b>() -> App<'a, 'b> {
+    return SubCommand::with_name("verify")
+            .about("Re-hashes recorded files to detect modified, corrupted and missing ones")
+            .usage("wfiles verify [-v] [-r] [-d <FILE>] [-b <SIZE>] [-n <NUM>] [MEDIUM]")
+            .arg(Arg::with_name("db")
+                 .short("d")
+                 .value_name("FILE")
+                 .default_value("stash.db")
+                 .help("Sets stash file"))
+            .arg(Arg::with_name("limit")
+                 .short("n")
+                 .value_name("NUM")
+                 .help("Verifies only the NUM least recently verified files"))
+            .arg(Arg::with_name("force_read")
+                 .short("r")
+                 .help("Don't mmap(2), use read(2) instead"))
+            .arg(Arg::with_name("buf")
+                .short("b")
+                .value_name("SIZE")
+                .default_value("8")
+                .help("Read-buffer size (in megabytes)"))
+            .arg(Arg::with_name("verbose")
+                .short("v")
+                .help("Verbose mode (lists files found ok as well)"))
+            .arg(Arg::with_name("medium")
+                .value_name("MEDIUM")
+                .help("Medium whose files are verified (default: all media)"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wfiles::testutil::{self, ScratchDir};
+
+    /* a stash of the files (and symlinks) under a scratch directory */
+    struct Stashed {
+        scratch: ScratchDir,
+        dbfile: String
+    }
+
+    impl Stashed {
+        fn new(name: &str, files: &[(&str, &str)], links: &[(&str, &str)], follow_links: bool) -> Self {
+            let scratch = ScratchDir::new(name);
+            for (rel, contents) in files {
+                scratch.write(rel, contents.as_bytes());
+            }
+            for (rel, target) in links {
+                std::os::unix::fs::symlink(scratch.join(target), scratch.join(rel)).unwrap();
+            }
+            let dbfile = scratch.join("stash.db");
+            let options = stash::StashOptions {
+                dbfile: dbfile.clone(), topdirs: vec![scratch.join("files")], threads: 1,
+                walk_options: fs::WalkOptions { follow_links, ..fs::WalkOptions::default() },
+                ..stash::StashOptions::default() };
+            let summary = {
+                let _turn = testutil::take_turn();
+                options.run(|_| ()).unwrap()
+            };
+            assert_eq!(summary.n_failed, 0);
+            Stashed { dbfile: dbfile.to_string_lossy().into_owned(), scratch }
+        }
+
+        fn verify(&self) -> Status {
+            VerifyOperation { dbfile: &self.dbfile, medium: None, limit: None,
+                              bufsize: stash::DEFAULT_READ_BUFFER_SIZE, force_read: false, verbose: false }
+                .do_operation().unwrap()
+        }
+
+        /* file names along with whether they are stamped as verified */
+        fn stamped(&self) -> Vec<(String, bool)> {
+            let conn = rusqlite::Connection::open(&self.dbfile).unwrap();
+            let mut stmt = conn.prepare("SELECT fname, verified is not null from files order by fname").unwrap();
+            let stamped = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap()
+                .collect::<rusqlite::Result<_>>().unwrap();
+            stamped
+        }
+    }
+
+    #[test]
+    fn stamps_only_files_found_intact() {
+        let stashed = Stashed::new("verify-stamps", &[("files/intact", "intact\n"), ("files/edited", "edited\n"),
+                                                      ("files/deleted", "deleted\n")], &[], false);
+        std::fs::write(stashed.scratch.join("files/edited"), "edited once more\n").unwrap();
+        std::fs::remove_file(stashed.scratch.join("files/deleted")).unwrap();
+
+        assert_eq!(stashed.verify(), Status::ProblemsFound);
+        assert_eq!(stashed.stamped(), vec![("deleted".to_string(), false), ("edited".to_string(), false),
+                                           ("intact".to_string(), true)]);
+
+        /* problems are reported again, rather than put off as recently verified */
+        assert_eq!(stashed.verify(), Status::ProblemsFound);
+    }
+
+    #[test]
+    fn verifies_files_recorded_through_symlinks_by_their_targets() {
+        let stashed = Stashed::new("verify-links", &[("files/plain", "plain\n"),
+                                                     ("elsewhere/target", "contents of the target\n")],
+                                   &[("files/link", "elsewhere/target")], true);
+
+        assert_eq!(stashed.verify(), Status::Done);
+        assert_eq!(stashed.stamped(), vec![("link".to_string(), true), ("plain".to_string(), true)]);
+    }
+}
@@ -1,29 +1,157 @@
-use rusqlite::{Connection, Transaction, Result, CachedStatement, OpenFlags};
-use rusqlite::params;
+use rusqlite::{Connection, Transaction, CachedStatement, OpenFlags, Rows};
+use rusqlite::{params, OptionalExtension};
 use rusqlite::ToSql;
 use std::path::Path;
 use std::path::PathBuf;
+use std::borrow::Cow;
+use std::ffi::OsString;
 use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::fmt::Debug;
 use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
 use crate::fs::FileHasher;
-use crate::hasher::SlowHasher;
+use crate::hasher::{QuickHasher, SlowHasher, QuickAlgorithm, SlowAlgorithm, HashAlgorithms};
+use crate::mounts::{Mount, MountTable};
+use crate::archive::{self, ArchiveMember};
+use crate::escape;
+use crate::db_migrate;
+use crate::error::{Error, Result, Context};
 
 pub const DEFAULT_DB : &str = "stash.db";
-const DB_VERSION : u16 = 0x0100;
+pub const DEFAULT_MEDIUM : &str = "filesystem";
+pub const DEFAULT_MEDIUM_DESCR : &str = "my computer";
+const DB_VERSION : u16 = 0x1000; // see db_migrate when changing the schema
 
 pub struct FileMetadata<'a> {
     pub path : &'a Path, 
+    pub parthash : Option<String>,
     pub fasthash : Option<String>,
     pub slowhash : Option<String>,
+    pub pending_slowhash : bool, // collides by fasthash, but could not be slow hashed
     pub medium : &'a str,
-    pub size : u64
+    pub size : u64,
+    pub mtime : i64,
+    pub ctime : i64,
+    pub atime : i64,
+    pub inode : u64,
+    pub dev : u64,
+    pub mode : u32,
+    pub uid : u32,
+    pub gid : u32,
+    pub nlink : u64,
+    pub link_target : Option<String>
+}
+
+impl<'a> FileMetadata<'a> {
+    pub fn from_fs(path: &'a Path, medium: &'a str, meta: &fs::Metadata) -> Self {
+        FileMetadata { path, 
+                       parthash: None,
+                       fasthash: None,
+                       slowhash: None,
+                       pending_slowhash: false,
+                       medium,
+                       size: meta.len(),
+                       mtime: meta.mtime(),
+                       ctime: meta.ctime(),
+                       atime: meta.atime(),
+                       inode: meta.ino(),
+                       dev: meta.dev(),
+                       mode: meta.mode(),
+                       uid: meta.uid(),
+                       gid: meta.gid(),
+                       nlink: meta.nlink(),
+                       link_target: None }
+    }
+}
+
+pub struct StoredFile {
+    pub rowid : u64,
+    pub size : u64,
+    pub mtime : i64,
+    pub inode : u64
+}
+
+impl StoredFile {
+    pub fn is_unchanged(&self, size: u64, mtime: i64, inode: u64) -> bool {
+        self.size == size && self.mtime == mtime && self.inode == inode
+    }
+}
+
+/* the directory and file name a file is recorded under (see escape) */
+fn split_path(path: &Path) -> (Cow<'_, str>, Cow<'_, str>) {
+    (escape::encode(path.parent().unwrap_or(Path::new(""))),
+     escape::encode(Path::new(path.file_name().unwrap_or_default())))
+}
+
+/* where the files of each medium can currently be found; media identified by
+   a filesystem UUID are rooted wherever that filesystem is mounted (if at all), 
+   all other media are rooted at / */
+pub struct MediaRoots {
+    mounts: MountTable,
+    media: HashMap<i64, (String, Option<String>)>
+}
+
+impl MediaRoots {
+    fn load(conn: &Connection) -> Result<MediaRoots> {
+        let mut media = HashMap::new();
+        let mut stmt = conn.prepare("SELECT medium_id, medium, uuid from media")
+            .context("error compiling media roots statement")?;
+        let mut rows = stmt.query([]).context("error executing media roots query")?;
+        while let Some(row) = rows.next().context("could not retrieve next row")? {
+            media.insert(row.get::<usize, i64>(0)?, 
+                         (row.get::<usize, String>(1)?, 
+                          row.get::<usize, Option<String>>(2)?));
+        }
+        Ok(MediaRoots { mounts: MountTable::load(), media })
+    }
+
+    /* current absolute path of a recorded file, None if its medium is not available */
+    pub fn locate(&self, medium_id: i64, path: &str, fname: &str) -> Option<PathBuf> {
+        let medium_path = escape::decode(path).join(escape::decode(fname));
+        match self.media.get(&medium_id) {
+            Some((_, Some(uuid))) => self.mounts.locate(uuid, &medium_path),
+            Some((_, None)) => Some(Path::new("/").join(medium_path)),
+            None => None
+        }
+    }
+
+    /* absolute path of a recorded file if available, medium:/path otherwise */
+    pub fn path(&self, medium_id: i64, path: &str, fname: &str) -> PathBuf {
+        match self.locate(medium_id, path, fname) {
+            Some(abs_path) => abs_path,
+            None => {
+                let medium = self.media.get(&medium_id).map_or("?", |m| m.0.as_str());
+                let mut medium_path = OsString::from(format!("{}:/", medium));
+                medium_path.push(escape::decode(path).join(escape::decode(fname)));
+                PathBuf::from(medium_path)
+            }
+        }
+    }
+
+    /* path() as shown to the user */
+    pub fn display(&self, medium_id: i64, path: &str, fname: &str) -> String {
+        escape::display(&self.path(medium_id, path, fname)).into_owned()
+    }
+
+    fn medium(&self, medium_id: i64) -> &str {
+        self.media.get(&medium_id).map_or("?", |m| m.0.as_str())
+    }
+}
+
+/* an older stash file brought up to the current schema */
+pub struct Upgrade {
+    pub from: u16,
+    pub to: u16,
+    pub backup: PathBuf,      // copy of the stash file as it was
+    pub n_quarantined: u64    // files the migrations could not carry over (see db_migrate)
 }
 
 pub struct DataBase {
     conn: Connection,
-    force_sha512: bool,
-    only_slowhash: bool
+    algorithms: HashAlgorithms,
+    only_slowhash: bool,
+    upgraded: Option<Upgrade>
 }
 
 impl DataBase {
@@ -31,8 +159,58 @@ impl DataBase {
         (file_version & 0xff00) == (DB_VERSION & 0xff00)
     }
 
+    /* version of an existing stash file, which must not be newer than this binary */
+    fn file_version(conn: &Connection) -> Result<u16> {
+        let version : u16 = Self::get_config_value(conn, "version")?;
+        if (version & 0xff00) > (DB_VERSION & 0xff00) {
+            return Err(Error::Incompatible(format!(
+                "stash file was written by a newer version of wfiles (schema {:#06x}, \
+                 this version supports up to {:#06x})", version, DB_VERSION)));
+        }
+        Ok(version)
+    }
+
+    /* brings an older stash file up to the current schema, keeping a copy of it as it was */
+    fn upgrade_conn(conn: &mut Connection, path: &Path) -> Result<Option<Upgrade>> {
+        let version = Self::file_version(conn)?;
+        if Self::is_compatible(version) {
+            return Ok(None);
+        }
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(".{:04x}.bak", version));
+        let backup = PathBuf::from(backup);
+        fs::copy(path, &backup)
+            .context(&format!("error backing up stash file to {}", backup.display()))?;
+        let (upgraded, n_quarantined) = db_migrate::migrate(conn, version)?;
+        if !Self::is_compatible(upgraded) {
+            return Err(Error::Incompatible(format!("no migration of stash file to schema {:#06x} found", 
+                                                   DB_VERSION)));
+        }
+        Ok(Some(Upgrade { from: version, to: upgraded, backup, n_quarantined }))
+    }
+
+    /* returns what upgrading did, None if the stash file was up to date */
+    pub fn upgrade<P: AsRef<Path>>(_path: P) -> Result<Option<Upgrade>> {
+        let path : &Path = _path.as_ref();
+        let mut conn = Self::open_existing(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        Self::upgrade_conn(&mut conn, path)
+    }
+
+    /* opens a stash file that must already exist */
+    fn open_existing(path: &Path, flags: OpenFlags) -> Result<Connection> {
+        if !path.exists() {
+            return Err(Error::NotFound(format!("stash file {} not found", path.display())));
+        }
+        let conn = Connection::open_with_flags(path, flags)
+            .context("error opening stash file")?;
+        if !Self::is_initialized(&conn) {
+            return Err(Error::Incompatible("non initialized stash file found".to_string()));
+        }
+        Ok(conn)
+    }
+
     fn is_initialized(conn: &Connection) -> bool {
-        let res : Result<String> = conn.query_row("SELECT * from config", 
+        let res : rusqlite::Result<String> = conn.query_row("SELECT * from config", 
                                                [], |r| r.get(1), );
         res.is_ok()
     }
@@ -42,9 +220,10 @@ impl DataBase {
         format!("INSERT INTO config (key, value) VALUES('{}', '{}')", key, value)
     }
 
-    fn init_db(conn: &Connection, version: u16, force_sha512: bool, only_slowhash: bool) {
+    fn init_db(conn: &Connection, version: u16, algorithms: &HashAlgorithms, only_slowhash: bool) -> Result<()> {
         let config_version_stmt = Self::config_db_sql("version", &version);
-        let config_force_sha512_stmt = Self::config_db_sql("force_sha512", &force_sha512);
+        let config_quick_hash_stmt = Self::config_db_sql("quick_hash", &algorithms.quick);
+        let config_slow_hash_stmt = Self::config_db_sql("slow_hash", &algorithms.slow);
         let config_only_slowhash_stmt = Self::config_db_sql("only_slowhash", &only_slowhash);
 
         let sql = [
@@ -58,18 +237,71 @@ impl DataBase {
             (medium_id INTEGER NOT NULL,
             medium text NOT NULL UNIQUE,
             medium_comment text,
+            uuid text UNIQUE,
+            label text,
+            fstype text,
+            capacity INTEGER,
             PRIMARY KEY (medium_id))",
 
             "CREATE TABLE files
             (medium_id INTEGER NOT NULL, 
             path text NOT NULL, 
             fname text NOT NULL, 
+            parthash text,
             fasthash text,
             slowhash text,
             size INTEGER NOT NULL,
+            mtime INTEGER,
+            ctime INTEGER,
+            atime INTEGER,
+            inode INTEGER,
+            dev INTEGER,
+            mode INTEGER,
+            uid INTEGER,
+            gid INTEGER,
+            nlink INTEGER,
+            link_target text,
+            container INTEGER,
+            pending_slowhash INTEGER NOT NULL DEFAULT 0,
+            verified INTEGER,
+            run_id INTEGER,
             CONSTRAINT files_id PRIMARY KEY (medium_id, path, fname),
             FOREIGN KEY(medium_id) REFERENCES media(medium_id))",
 
+            "CREATE TABLE runs
+            (run_id INTEGER NOT NULL,
+            started INTEGER NOT NULL,
+            finished INTEGER,
+            completed INTEGER NOT NULL DEFAULT 0,
+            last_path text,
+            n_files INTEGER NOT NULL DEFAULT 0,
+            n_bytes INTEGER NOT NULL DEFAULT 0,
+            hostname text,
+            user text,
+            quick_hash text,
+            slow_hash text,
+            options text,
+            PRIMARY KEY (run_id))",
+
+            "CREATE TABLE run_paths
+            (run_id INTEGER NOT NULL,
+            path text NOT NULL,
+            medium_id INTEGER,
+            FOREIGN KEY(run_id) REFERENCES runs(run_id),
+            FOREIGN KEY(medium_id) REFERENCES media(medium_id))",
+
+            "CREATE TABLE errors
+            (run_id INTEGER NOT NULL,
+            medium_id INTEGER NOT NULL,
+            path text NOT NULL,
+            fname text NOT NULL,
+            kind text NOT NULL,
+            reason text NOT NULL,
+            FOREIGN KEY(run_id) REFERENCES runs(run_id),
+            FOREIGN KEY(medium_id) REFERENCES media(medium_id))",
+
+            "CREATE INDEX idx_parthash ON files (parthash)",
+
             "CREATE INDEX idx_fasthash ON files (fasthash)",
 
             "CREATE INDEX idx_slowhash ON files (slowhash)",
@@ -78,83 +310,161 @@ impl DataBase {
 
             "CREATE INDEX idx_fname ON files (fname)",
 
+            "CREATE INDEX idx_inode ON files (medium_id, inode)",
+
+            "CREATE INDEX idx_link ON files (medium_id, dev, inode)",
+
+            "CREATE INDEX idx_container ON files (container)",
+
+            "CREATE INDEX idx_verified ON files (medium_id, verified)",
+
+            "CREATE INDEX idx_errors ON errors (run_id, medium_id)",
+
+            "CREATE INDEX idx_run ON files (run_id)",
+
             "CREATE INDEX idx_medium ON media (medium)",
 
+            "CREATE INDEX idx_uuid ON media (uuid)",
+
             "CREATE INDEX idx_config ON config (key)",
 
             &config_version_stmt,
 
-            &config_force_sha512_stmt,
+            &config_quick_hash_stmt,
+
+            &config_slow_hash_stmt,
 
             &config_only_slowhash_stmt,
 
         ].join(";");
 
-        conn.execute_batch(&sql).expect("SQL failed during db init");
+        conn.execute_batch(&sql).context("SQL failed during db init")
     }
 
-    fn get_config_value<T>(conn: &Connection, key: &str) -> T 
+    fn get_config_value<T>(conn: &Connection, key: &str) -> Result<T> 
     where T: FromStr, <T as FromStr>::Err: Debug
     {
         let sql = format!("SELECT value from config where key='{}'", &key);
         let error = format!("did not find {} configuration in stash file", &key);
 
-        let value: String = conn.query_row(&sql, [], |row| row.get(0)).expect(&error);
-        value.parse::<T>().unwrap()
+        let value: String = conn.query_row(&sql, [], |row| row.get(0)).context(&error)?;
+        value.parse::<T>().map_err(|_e| Error::Incompatible(
+            format!("invalid {} configuration in stash file ({:?})", &key, _e)))
     }
 
-    pub fn new<P: AsRef<Path>>(_path: P, _force_db_overwrite: bool, force_sha512: bool, only_slowhash: bool) -> DataBase
+    fn get_algorithms(conn: &Connection) -> Result<HashAlgorithms> {
+        Ok(HashAlgorithms { quick: Self::get_config_value(conn, "quick_hash")?,
+                            slow: Self::get_config_value(conn, "slow_hash")? })
+    }
+
+    /* algorithms not given are those of an existing stash file (or else the defaults) */
+    pub fn new<P: AsRef<Path>>(_path: P, _force_db_overwrite: bool, 
+                               quick: Option<QuickAlgorithm>, slow: Option<SlowAlgorithm>, 
+                               only_slowhash: bool) -> Result<DataBase>
     {
         let db_file_exists = fs::metadata(&_path).is_ok();
 
         if db_file_exists && _force_db_overwrite {
             fs::remove_file(&_path)
-                .expect("error in removing stash file");
+                .context("error in removing stash file")?;
         }
 
         let path : &Path = _path.as_ref();
-        let conn = Connection::open_with_flags(path, 
+        let mut conn = Connection::open_with_flags(path, 
                         OpenFlags::SQLITE_OPEN_READ_WRITE | 
                         OpenFlags::SQLITE_OPEN_CREATE).
-                        expect("error opening/creating stash file");
+                        context("error opening/creating stash file")?;
 
+        let algorithms : HashAlgorithms;
+        let mut upgraded = None;
         if !Self::is_initialized(&conn) {
-            Self::init_db(&conn, DB_VERSION, force_sha512, only_slowhash);
+            let defaults = HashAlgorithms::default();
+            algorithms = HashAlgorithms { quick: quick.unwrap_or(defaults.quick),
+                                          slow: slow.unwrap_or(defaults.slow) };
+            Self::init_db(&conn, DB_VERSION, &algorithms, only_slowhash)?;
         } else {
-            if !Self::is_compatible(Self::get_config_value(&conn, "version")) {
-                panic!("stash file cannot be processed by this version of wfiles");
+            upgraded = Self::upgrade_conn(&mut conn, path)?;
+            algorithms = Self::get_algorithms(&conn)?;
+            if quick.is_some_and(|_q| _q != algorithms.quick) {
+                return Err(Error::Incompatible(format!(
+                    "stash file was generated with {} quick hashing (see --quick-hash option)", algorithms.quick)));
             }
-            if force_sha512 != Self::get_config_value(&conn, "force_sha512") {
-                panic!("stash file was generated under different force_sha512 setting (see -s option)");
+            if slow.is_some_and(|_s| _s != algorithms.slow) {
+                return Err(Error::Incompatible(format!(
+                    "stash file was generated with {} slow hashing (see --slow-hash option)", algorithms.slow)));
             }
-            if only_slowhash != Self::get_config_value(&conn, "only_slowhash") {
-                panic!("stash file was generated under different only_slowhash setting (see -l option)");
+            if only_slowhash != Self::get_config_value::<bool>(&conn, "only_slowhash")? {
+                return Err(Error::Incompatible(
+                    "stash file was generated under different only_slowhash setting (see -l option)".to_string()));
             }
         }
 
-        DataBase { conn, force_sha512, only_slowhash }
+        Ok(DataBase { conn, algorithms, only_slowhash, upgraded })
    }
 
-   pub fn for_reading<P: AsRef<Path>>(_path: P) -> DataBase
+   pub fn for_updating<P: AsRef<Path>>(_path: P) -> Result<DataBase>
    {
         let path : &Path = _path.as_ref();
-        let conn = Connection::open_with_flags(path, 
-                        OpenFlags::SQLITE_OPEN_READ_ONLY).  
-                        expect("error opening stash file");
+        let mut conn = Self::open_existing(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
 
-        let force_sha512 = Self::get_config_value(&conn, "force_sha512");
-        let only_slowhash = Self::get_config_value(&conn, "only_slowhash");
+        let upgraded = Self::upgrade_conn(&mut conn, path)?;
 
-        if !Self::is_initialized(&conn) {
-            panic!("non initialized stash file found");
-        }
+        let algorithms = Self::get_algorithms(&conn)?;
+        let only_slowhash = Self::get_config_value(&conn, "only_slowhash")?;
+
+        Ok(DataBase { conn, algorithms, only_slowhash, upgraded })
+   }
+
+   pub fn for_reading<P: AsRef<Path>>(_path: P) -> Result<DataBase>
+   {
+        let path : &Path = _path.as_ref();
+        let conn = Self::open_existing(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
 
-        if !Self::is_compatible(Self::get_config_value(&conn, "version")) {
-            panic!("stash file cannot be processed by this version of wfiles");
+        let version = Self::file_version(&conn)?;
+        if !Self::is_compatible(version) {
+            return Err(Error::Incompatible(format!(
+                "stash file has an older schema ({:#06x}), upgrade it with wfiles db upgrade", version)));
         }
 
-        DataBase { conn, force_sha512, only_slowhash }
+        let algorithms = Self::get_algorithms(&conn)?;
+        let only_slowhash = Self::get_config_value(&conn, "only_slowhash")?;
+
+        Ok(DataBase { conn, algorithms, only_slowhash, upgraded: None })
    }        
+
+   /* in WAL mode, readers of the stash file (see pin_snapshot) neither block nor are blocked
+      by a writer; it is left again once the other connections are closed */
+   pub fn set_wal_mode(&self, wal: bool) -> Result<()> {
+        let mode = if wal { "WAL" } else { "DELETE" };
+        self.conn.query_row(&format!("PRAGMA journal_mode = {}", mode), [], |_| Ok(()))
+            .context("error switching journal mode of stash file")
+   }
+
+   /* keeps seeing the stash file as it is now, whatever other connections commit
+      meanwhile, for as long as this one is open */
+   pub fn pin_snapshot(&self) -> Result<()> {
+        self.conn.execute_batch("BEGIN").context("error beginning read transaction")?;
+        self.conn.query_row("SELECT count(*) from config", [], |_| Ok(()))
+            .context("error reading stash file snapshot")
+   }
+
+   pub fn algorithms(&self) -> HashAlgorithms {
+        self.algorithms
+   }
+
+   /* whether files are only slow hashed, as set when the stash file was created */
+   pub fn only_slowhash(&self) -> bool {
+        self.only_slowhash
+   }
+
+   /* how the stash file was upgraded on opening, None if it was up to date */
+   pub fn upgraded(&self) -> Option<&Upgrade> {
+        self.upgraded.as_ref()
+   }
+
+   pub fn media_roots(&self) -> Result<MediaRoots> {
+        MediaRoots::load(&self.conn)
+   }
 }
 
 pub struct DBTransaction<'conn> {
@@ -162,58 +472,316 @@ pub struct DBTransaction<'conn> {
 }
 
 impl<'conn> DBTransaction<'conn> {
-    pub fn new(db: &mut DataBase) -> DBTransaction {
+    pub fn new(db: &mut DataBase) -> Result<DBTransaction<'_>> {
         let trans = db.conn.transaction()
-            .expect("error receiving transaction handler");
-        DBTransaction { trans }
+            .context("error receiving transaction handler")?;
+        Ok(DBTransaction { trans })
+    }
+
+    pub fn commit(self) -> Result<()> {
+        self.trans.commit().context("error during commit")
+    }
+
+    /* commits what is done so far and carries on in a new transaction */
+    pub fn checkpoint(&self) -> Result<()> {
+        self.trans.execute_batch("COMMIT; BEGIN").context("error during checkpoint commit")
     }
 
-    pub fn commit(self) {
-        self.trans.commit().expect("error during commit");
+    pub fn media_roots(&self) -> Result<MediaRoots> {
+        MediaRoots::load(&self.trans)
     }
 }
 
+/* a row of the files table, as needed to locate the file and its hardlinks */
+struct RecordedFile {
+    rowid: i64,
+    medium_id: i64,
+    path: String,
+    fname: String,
+    inode: Option<i64>,
+    mtime: Option<i64>,
+    dev: Option<i64>
+}
+
+impl RecordedFile {
+    /* off a row of rowid, medium_id, path, fname, inode, mtime and dev */
+    fn from_row(r: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(RecordedFile { rowid: r.get(0)?, medium_id: r.get(1)?, path: r.get(2)?, fname: r.get(3)?,
+                          inode: r.get(4)?, mtime: r.get(5)?, dev: r.get(6)? })
+    }
+
+    /* medium, device and inode, which all hardlinks of the file share (device numbers
+       are handed out anew as media are mounted, so they only tell files apart within a
+       medium; rows of older versions may lack the device, and are then taken to have
+       no hardlinks) */
+    fn link_key(&self) -> Option<(i64, i64, i64)> {
+        self.dev.zip(self.inode).map(|(_d, _i)| (self.medium_id, _d, _i))
+    }
+}
+
+/// A recorded file that could not be hashed when one of its digests was needed (as
+/// a file of the same size or digest was being recorded), the digest being left out.
+pub struct LazyFailure {
+    pub path: PathBuf,        // where it was looked for (or medium:/path if not available)
+    pub medium: String,
+    pub medium_path: PathBuf, // relative to the root of its medium
+    pub kind: FailureKind,
+    pub reason: String
+}
+
+impl LazyFailure {
+    fn new(roots: &MediaRoots, f: &RecordedFile, kind: FailureKind, reason: String) -> Self {
+        LazyFailure { path: roots.path(f.medium_id, &f.path, &f.fname),
+                      medium: roots.medium(f.medium_id).to_string(),
+                      medium_path: escape::decode(&f.path).join(escape::decode(&f.fname)),
+                      kind, reason }
+    }
+}
+
+/* failures of lazy hashing, each file reported once however often it is retried */
+#[derive(Default)]
+struct LazyFailures {
+    failures: Vec<LazyFailure>,
+    rowids: HashSet<i64>
+}
+
+impl LazyFailures {
+    fn push(&mut self, roots: &MediaRoots, f: &RecordedFile, kind: FailureKind, reason: String) {
+        if self.rowids.insert(f.rowid) {
+            self.failures.push(LazyFailure::new(roots, f, kind, reason));
+        }
+    }
+}
+
+/* digests the given rows (at most once per inode) with hash_file and stores 
+   the digests through update; files of unavailable media are left as they are */
+#[allow(clippy::too_many_arguments)]
+fn fill_digests<F>(select: &mut CachedStatement, update: &mut CachedStatement, roots: &MediaRoots,
+                   failures: &mut LazyFailures, select_params: &[&dyn ToSql], fsize: u64, 
+                   mut hash_file: F) -> Result<()>
+    where F: FnMut(&Path) -> std::result::Result<String, std::io::Error>
+{
+    let unhashed : Vec<RecordedFile> = select
+        .query_map(select_params, RecordedFile::from_row)
+        .context("error when performing query for unhashed files")?
+        .collect::<rusqlite::Result<_>>().context("could not retrieve unhashed file row")?;
+
+    let mut inodes_done : HashSet<(i64, i64, i64)> = HashSet::new();
+    for f in unhashed {
+        if f.link_key().is_some_and(|_k| inodes_done.contains(&_k)) {
+            continue; // hardlink filled along with an earlier one
+        }
+        let full_path = match roots.locate(f.medium_id, &f.path, &f.fname) {
+            Some(_p) => _p,
+            None => continue
+        };
+        match hash_file(&full_path) {
+            Ok(digest) => {
+                update.execute(params![&digest, f.rowid, f.medium_id, f.dev, f.inode, fsize as i64, f.mtime])
+                    .context("error while storing lazily computed hash")?;
+                inodes_done.extend(f.link_key());
+            },
+            Err(_e) => failures.push(roots, &f, FailureKind::Read.of(&_e), 
+                                     format!("failed to read recorded file for lazy hashing ({})", _e))
+        }
+    }
+    Ok(())
+}
+
 pub struct CheckCollisionStatement<'conn> {
     check_collision : CachedStatement<'conn>,
+    unslowed_colliding : CachedStatement<'conn>,
+    unresolved : CachedStatement<'conn>,
     update_slowhash : CachedStatement<'conn>,
+    mark_pending : CachedStatement<'conn>,
+    unhashed_of_size : CachedStatement<'conn>,
+    update_fasthash : CachedStatement<'conn>,
+    check_partial_collision : CachedStatement<'conn>,
+    unparted_of_size : CachedStatement<'conn>,
+    update_parthash : CachedStatement<'conn>,
+    roots : MediaRoots,
+    failures : LazyFailures
 }
 
 impl<'conn> CheckCollisionStatement<'conn> {
-    pub fn new<'d>(dt: &'d DBTransaction) -> CheckCollisionStatement<'d> {
+    pub fn new<'d>(dt: &'d DBTransaction) -> Result<CheckCollisionStatement<'d>> {
         let cc_state = dt.trans.prepare_cached(
-            "SELECT rowid, path, fname, size from files where fasthash = ?")
-            .expect("error compiling check collision statement");
-        let us_state = dt.trans.prepare_cached("UPDATE files set slowhash=? where rowid=?")
-            .expect("error compiling update of slowhash statement");
-        CheckCollisionStatement { check_collision: cc_state, update_slowhash: us_state}
+            "SELECT 1 from files where fasthash = ? and rowid != ? limit 1")
+            .context("error compiling check collision statement")?;
+        /* archive members (and files hashed before) need no slow hashing */
+        let uc_state = dt.trans.prepare_cached(
+            "SELECT rowid, medium_id, path, fname, inode, mtime, dev, size from files 
+             where fasthash = ? and rowid != ? and slowhash is null and link_target is null")
+            .context("error compiling colliding files without slowhash statement")?;
+        let ur_state = dt.trans.prepare_cached(
+            "SELECT rowid, medium_id, path, fname, inode, mtime, dev, size from files 
+             where slowhash is null and link_target is null and fasthash in 
+             (select fasthash from files where fasthash is not null group by fasthash having count(*) > 1)
+             order by medium_id, path, fname")
+            .context("error compiling unresolved collisions statement")?;
+        /* hardlinks of the file (same medium, device and inode) share its slowhash */
+        let us_state = dt.trans.prepare_cached(
+            "UPDATE files set slowhash=?1, pending_slowhash=0 where rowid=?2 or 
+             (medium_id=?3 and dev=?4 and inode=?5 and size=?6 and mtime=?7 and link_target is null)")
+            .context("error compiling update of slowhash statement")?;
+        let mp_state = dt.trans.prepare_cached(
+            "UPDATE files set pending_slowhash=1 where slowhash is null and (rowid=?1 or 
+             (medium_id=?2 and dev=?3 and inode=?4 and size=?5 and mtime=?6 and link_target is null))")
+            .context("error compiling pending slowhash statement")?;
+        /* restricted to the files of the given partial hash, if any */
+        let uos_state = dt.trans.prepare_cached(
+            "SELECT rowid, medium_id, path, fname, inode, mtime, dev from files 
+             where size = ?1 and fasthash is null and link_target is null and rowid != ?2
+             and (?3 is null or parthash = ?3)")
+            .context("error compiling unhashed files of size statement")?;
+        let uf_state = dt.trans.prepare_cached(
+            "UPDATE files set fasthash=?1 where rowid=?2 or 
+             (medium_id=?3 and dev=?4 and inode=?5 and size=?6 and mtime=?7 and link_target is null)")
+            .context("error compiling update of fasthash statement")?;
+        let cpc_state = dt.trans.prepare_cached(
+            "SELECT 1 from files where parthash = ? and rowid != ? limit 1")
+            .context("error compiling check partial collision statement")?;
+        let upos_state = dt.trans.prepare_cached(
+            "SELECT rowid, medium_id, path, fname, inode, mtime, dev from files 
+             where size = ? and parthash is null and link_target is null and rowid != ?")
+            .context("error compiling files of size without partial hash statement")?;
+        let up_state = dt.trans.prepare_cached(
+            "UPDATE files set parthash=?1 where rowid=?2 or 
+             (medium_id=?3 and dev=?4 and inode=?5 and size=?6 and mtime=?7 and link_target is null)")
+            .context("error compiling update of parthash statement")?;
+        /* media must have been added before, for their files to be located */
+        Ok(CheckCollisionStatement { check_collision: cc_state, unslowed_colliding: uc_state,
+                                  unresolved: ur_state,
+                                  update_slowhash: us_state, mark_pending: mp_state,
+                                  unhashed_of_size: uos_state, update_fasthash: uf_state,
+                                  check_partial_collision: cpc_state, unparted_of_size: upos_state,
+                                  update_parthash: up_state,
+                                  roots: dt.media_roots()?, failures: LazyFailures::default() })
     }
 
-    fn trigger_slowhashing(&mut self, fh: &mut FileHasher<SlowHasher>, rowid: u64, path: &str, fname : &str, fsize: u64) {
-        let digest = fh.hash_dbentry(path, fname, fsize).expect("error while accessing file for triggered slowhashing");
-        self.update_slowhash.execute(params![&digest, rowid as i64]).expect("error while creating slowhash in trigger_slowhashing()");
+    /* a file that cannot be slow hashed (e.g. deleted, or on a medium not plugged in) 
+       is marked as pending, to be slow hashed at the next collision (or by resolve) */
+    fn trigger_slowhashing(&mut self, fh: &mut FileHasher<SlowHasher>, f: &RecordedFile, fsize: u64) -> Result<bool> {
+        let full_path = match self.roots.locate(f.medium_id, &f.path, &f.fname) {
+            Some(_p) => _p,
+            None => {
+                self.failures.push(&self.roots, f, FailureKind::Open, 
+                                   "medium of recorded file is not available, slow hashing is pending".to_string());
+                self.mark_pending.execute(params![f.rowid, f.medium_id, f.dev, f.inode, fsize as i64, f.mtime])
+                    .context("error while marking slowhash as pending")?;
+                return Ok(false);
+            }
+        };
+        match fh.hash_dbentry(&full_path, fsize) {
+            Ok(digest) => {
+                self.update_slowhash.execute(params![&digest, f.rowid, f.medium_id, f.dev, f.inode, fsize as i64, f.mtime])
+                    .context("error while creating slowhash in trigger_slowhashing()")?;
+                Ok(true)
+            },
+            Err(_e) => {
+                self.failures.push(&self.roots, f, FailureKind::Read.of(&_e), format!(
+                    "failed to read recorded file for triggered slow hashing ({}), slow hashing is pending", _e));
+                self.mark_pending.execute(params![f.rowid, f.medium_id, f.dev, f.inode, fsize as i64, f.mtime])
+                    .context("error while marking slowhash as pending")?;
+                Ok(false)
+            }
+        }
     }
 
-    pub fn collision(&mut self, fh: &mut FileHasher<SlowHasher>, fasthash: &str) -> bool {
-        let mut rows = self.check_collision.query([fasthash])
-            .expect("error when performing query for quickhash collision");
+    /* quick hashes the recorded files of the given size (and partial hash, if given)
+       that were left without a quick hash, so that they can be checked for collisions */
+    pub fn fill_fasthashes(&mut self, fh: &mut FileHasher<QuickHasher>, fsize: u64, 
+                           parthash: Option<&str>, entry_rowid: Option<u64>) -> Result<()> {
+        let exclude : i64 = entry_rowid.map_or(-1, |r| r as i64);
+        fill_digests(&mut self.unhashed_of_size, &mut self.update_fasthash, &self.roots, &mut self.failures,
+                     params![fsize as i64, exclude, parthash], fsize,
+                     |p| fh.hash_dbentry(p, fsize))
+    }
+
+    /* likewise, for the partial hashes of the recorded files of the given size */
+    pub fn fill_parthashes(&mut self, fh: &mut FileHasher<QuickHasher>, fsize: u64, 
+                           entry_rowid: Option<u64>) -> Result<()> {
+        let exclude : i64 = entry_rowid.map_or(-1, |r| r as i64);
+        fill_digests(&mut self.unparted_of_size, &mut self.update_parthash, &self.roots, &mut self.failures,
+                     params![fsize as i64, exclude], fsize,
+                     |p| fh.hash_partial_dbentry(p, fsize))
+    }
+
+    /* the recorded files that failed to be hashed since last asked, see LazyFailure */
+    pub fn take_failures(&mut self) -> Vec<LazyFailure> {
+        std::mem::take(&mut self.failures.failures)
+    }
+
+    /* a colliding partial hash only calls for quick hashing, so nothing is hashed here */
+    pub fn partial_collision(&mut self, parthash: &str, entry_rowid: Option<u64>) -> Result<bool> {
+        let exclude : i64 = entry_rowid.map_or(-1, |r| r as i64);
+        self.check_partial_collision.exists(params![parthash, exclude])
+            .context("error when performing query for partial hash collision")
+    }
+
+    /* entry_rowid is the row currently recorded for the file being hashed (if any),
+       which must not be mistaken for a colliding file */
+    /* slow hashes the files of colliding fasthash that were left without a slowhash 
+       (calling on_file with each, as shown to the user, before it is hashed), 
+       returning how many were slow hashed and how many are still pending */
+    pub fn resolve<F>(&mut self, fh: &mut FileHasher<SlowHasher>, mut on_file: F) -> Result<(usize, usize)> 
+        where F: FnMut(&str)
+    {
+        let unresolved : Vec<(RecordedFile, u64)> = self.unresolved
+            .query_map([],
+                       |r| Ok((RecordedFile::from_row(r)?, r.get::<usize, i64>(7)? as u64)))
+            .context("error when performing query for unresolved collisions")?
+            .collect::<rusqlite::Result<_>>().context("could not retrieve unresolved file row")?;
+
+        let (mut n_hashed, mut n_pending) = (0, 0);
+        let mut inodes_done : HashSet<(i64, i64, i64)> = HashSet::new();
+        for (f, fsize) in unresolved {
+            if f.link_key().is_some_and(|_k| inodes_done.contains(&_k)) {
+                continue; // hardlink hashed along with an earlier one
+            }
+            on_file(&self.roots.display(f.medium_id, &f.path, &f.fname));
+            if self.trigger_slowhashing(fh, &f, fsize)? {
+                n_hashed += 1;
+            } else {
+                n_pending += 1;
+            }
+            inodes_done.extend(f.link_key());
+        }
+        Ok((n_hashed, n_pending))
+    }
 
-        if let Ok(Some(row)) = rows.next() {
-            let rowid : u64 = row.get_unwrap::<usize, i64>(0) as u64;
-            let path : String = row.get_unwrap::<usize, String>(1);
-            let fname : String = row.get_unwrap::<usize, String>(2);
-            let fsize : u64 = row.get_unwrap::<usize, i64>(3) as u64;
-            drop(rows);
-            self.trigger_slowhashing(fh, rowid, &path, &fname, fsize);
-            return true
+    pub fn collision(&mut self, fh: &mut FileHasher<SlowHasher>, fasthash: &str, 
+                     entry_rowid: Option<u64>) -> Result<bool> {
+        let exclude : i64 = entry_rowid.map_or(-1, |r| r as i64);
+        if !self.check_collision.exists(params![fasthash, exclude])
+            .context("error when performing query for quickhash collision")? {
+            return Ok(false);
         }
 
-        return false
+        /* every colliding file is slow hashed, so that N-way collisions are all resolved */
+        let unslowed : Vec<(RecordedFile, u64)> = self.unslowed_colliding
+            .query_map(params![fasthash, exclude],
+                       |r| Ok((RecordedFile::from_row(r)?, r.get::<usize, i64>(7)? as u64)))
+            .context("error when performing query for colliding files")?
+            .collect::<rusqlite::Result<_>>().context("could not retrieve colliding file row")?;
 
+        let mut inodes_done : HashSet<(i64, i64, i64)> = HashSet::new();
+        for (f, fsize) in unslowed {
+            if f.link_key().is_some_and(|_k| inodes_done.contains(&_k)) {
+                continue; // hardlink hashed along with an earlier one
+            }
+            self.trigger_slowhashing(fh, &f, fsize)?;
+            inodes_done.extend(f.link_key());
+        }
+        return Ok(true)
     }
 }
 
 pub struct FileInsertStatement<'conn> {
-    file_insert : CachedStatement<'conn>
+    file_insert : CachedStatement<'conn>,
+    member_insert : CachedStatement<'conn>,
+    members_clear : CachedStatement<'conn>,
+    run_id : i64
 }
 
 impl<'conn> Drop for FileInsertStatement<'conn> {
@@ -221,27 +789,658 @@ impl<'conn> Drop for FileInsertStatement<'conn> {
 }
 
 impl<'conn> FileInsertStatement<'conn> {
-    pub fn new<'c>(dt: &'c DBTransaction) -> FileInsertStatement<'c> {
+    /* files are recorded as last touched by the given run */
+    pub fn new<'c>(dt: &'c DBTransaction, run_id: i64) -> Result<FileInsertStatement<'c>> {
         let fi_state = dt.trans.prepare_cached(
             "INSERT into files
-            (medium_id, path, fname, fasthash, slowhash, size) 
+            (medium_id, path, fname, parthash, fasthash, slowhash, pending_slowhash, size, 
+             mtime, ctime, atime, inode, dev, mode, uid, gid, nlink, link_target, run_id) 
             values ((select medium_id from media where medium = ? ), 
-                    ?, ?, ?, ?, ?)")
-            .expect("error compiling file insertion statement");
-        FileInsertStatement { file_insert : fi_state }
+                    ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (medium_id, path, fname) DO UPDATE SET
+                parthash = excluded.parthash, fasthash = excluded.fasthash, slowhash = excluded.slowhash,
+                pending_slowhash = excluded.pending_slowhash,
+                size = excluded.size, mtime = excluded.mtime, 
+                ctime = excluded.ctime, atime = excluded.atime,
+                inode = excluded.inode, dev = excluded.dev,
+                mode = excluded.mode, uid = excluded.uid, 
+                gid = excluded.gid, nlink = excluded.nlink,
+                link_target = excluded.link_target, run_id = excluded.run_id
+            RETURNING rowid")
+            .context("error compiling file insertion statement")?;
+        let mi_state = dt.trans.prepare_cached(
+            "INSERT into files
+            (medium_id, path, fname, parthash, fasthash, slowhash, size, mtime, mode, container, run_id) 
+            values ((select medium_id from media where medium = ? ), ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (medium_id, path, fname) DO UPDATE SET
+                parthash = excluded.parthash, fasthash = excluded.fasthash,
+                slowhash = excluded.slowhash, size = excluded.size, mtime = excluded.mtime, 
+                mode = excluded.mode, container = excluded.container, run_id = excluded.run_id
+            RETURNING rowid")
+            .context("error compiling archive member insertion statement")?;
+        let mc_state = dt.trans.prepare_cached("DELETE from files where container = ?")
+            .context("error compiling archive members removal statement")?;
+        Ok(FileInsertStatement { file_insert : fi_state, member_insert: mi_state, members_clear: mc_state,
+                                 run_id })
+    }
+
+    /* returns the rowid of the (inserted or updated) row */
+    pub fn add_file(&mut self, fm: FileMetadata) -> Result<u64> {
+        let (dir, fname) = split_path(fm.path);
+        let rowid = self.file_insert.query_row(
+            params![fm.medium, dir, fname, 
+            fm.parthash, fm.fasthash, fm.slowhash, 
+            fm.pending_slowhash, fm.size as i64,
+            fm.mtime, fm.ctime, fm.atime, fm.inode as i64, fm.dev as i64, 
+            fm.mode, fm.uid, fm.gid, fm.nlink as i64, fm.link_target, self.run_id], 
+            |r| r.get::<usize, i64>(0))
+            .context(&format!("INSERT for file {:?}", fm.path))?;
+        Ok(rowid as u64)
     }
 
-    pub fn add_file(&mut self, fm: FileMetadata) {
-        self.file_insert.execute(
-            params![fm.medium, fm.path.parent().unwrap().to_str().unwrap(), 
-            fm.path.file_name().unwrap().to_str().unwrap(), 
-            fm.fasthash.to_sql().unwrap(), fm.slowhash.to_sql().unwrap(), fm.size as i64]).unwrap_or_else(|_| 
-                panic!("INSERT for file {:?}", fm.path));
+    /* records a member of the archive at archive_path (relative to the medium),
+       returns the rowid of the member */
+    pub fn add_member(&mut self, medium: &str, archive_path: &Path, member: &ArchiveMember, 
+                      container: u64) -> Result<u64> {
+        let path = archive::members_root(archive_path).join(&member.path);
+        let (dir, fname) = split_path(&path);
+        let rowid = self.member_insert.query_row(
+            params![medium, dir, fname,
+            member.digests.parthash, member.digests.fasthash, member.digests.slowhash,
+            member.digests.size as i64, member.mtime, member.mode, container as i64, self.run_id],
+            |r| r.get::<usize, i64>(0))
+            .context(&format!("INSERT for archive member {:?}", path))?;
+        Ok(rowid as u64)
+    }
+
+    /* forgets the members of an archive, before it is indexed anew */
+    pub fn clear_members(&mut self, container: u64) -> Result<()> {
+        self.members_clear.execute(params![container as i64])
+            .context("error while removing archive members")?;
+        Ok(())
+    }
+}
+
+pub struct FileLookupStatement<'conn> {
+    file_lookup : CachedStatement<'conn>
+}
+
+impl<'conn> FileLookupStatement<'conn> {
+    pub fn new(db: &DataBase) -> Result<FileLookupStatement<'_>> {
+        let fl_state = db.conn.prepare_cached(
+            "SELECT files.rowid, size, mtime, inode from files 
+             join media on files.medium_id = media.medium_id 
+             where medium = ? and path = ? and fname = ?")
+            .context("error compiling file lookup statement")?;
+        Ok(FileLookupStatement { file_lookup : fl_state })
+    }
+
+    pub fn lookup(&mut self, medium: &str, path: &Path) -> Result<Option<StoredFile>> {
+        let (dir, fname) = split_path(path);
+        let mut rows = self.file_lookup.query(params![medium, dir, fname])
+            .context("error when performing file lookup query")?;
+
+        match rows.next().context("could not retrieve file lookup row")? {
+            Some(row) => Ok(Some(StoredFile {
+                rowid: row.get::<usize, i64>(0)? as u64,
+                size: row.get::<usize, i64>(1)? as u64,
+                mtime: row.get::<usize, Option<i64>>(2)?.unwrap_or(-1),
+                inode: row.get::<usize, Option<i64>>(3)?.unwrap_or(-1) as u64 })),
+            None => Ok(None)
+        }
+    }
+}
+
+fn is_gone(path: &Path) -> bool {
+    matches!(fs::symlink_metadata(path), Err(_e) if _e.kind() == std::io::ErrorKind::NotFound)
+}
+
+pub struct MovedFileStatement<'conn> {
+    inode_lookup : CachedStatement<'conn>,
+    roots : MediaRoots
+}
+
+impl<'conn> MovedFileStatement<'conn> {
+    pub fn new(db: &DataBase) -> Result<MovedFileStatement<'_>> {
+        let il_state = db.conn.prepare_cached(
+            "SELECT files.rowid, files.medium_id, path, fname from files 
+             join media on files.medium_id = media.medium_id 
+             where medium = ? and inode = ? and size = ? and mtime = ? and container is null")
+            .context("error compiling inode lookup statement")?;
+        Ok(MovedFileStatement { inode_lookup: il_state, roots: db.media_roots()? })
+    }
+
+    /* a recorded file of the same inode, size and mtime that is no longer found 
+       where it was recorded, i.e. the file was moved (rows in claimed excluded) */
+    pub fn find(&mut self, medium: &str, meta: &fs::Metadata, claimed: &[u64]) -> Result<Option<u64>> {
+        let candidates : Vec<(i64, i64, String, String)> = self.inode_lookup
+            .query_map(params![medium, meta.ino() as i64, meta.len() as i64, meta.mtime()],
+                       |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))
+            .context("error when performing inode lookup query")?
+            .collect::<rusqlite::Result<_>>().context("could not retrieve inode lookup row")?;
+
+        for (rowid, medium_id, path, fname) in candidates {
+            if claimed.contains(&(rowid as u64)) {
+                continue;
+            }
+            if let Some(_old) = self.roots.locate(medium_id, &path, &fname) {
+                if is_gone(&_old) {
+                    return Ok(Some(rowid as u64));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+pub struct SizeLookupStatement<'conn> {
+    size_lookup : CachedStatement<'conn>
+}
+
+impl<'conn> SizeLookupStatement<'conn> {
+    pub fn new(db: &DataBase) -> Result<SizeLookupStatement<'_>> {
+        let sl_state = db.conn.prepare_cached(
+            "SELECT 1 from files where size = ?1 and rowid != ?2 and link_target is null 
+             and not coalesce(medium_id = (select medium_id from media where medium = ?3) 
+                              and dev = ?4 and inode = ?5, 0) limit 1")
+            .context("error compiling size lookup statement")?;
+        Ok(SizeLookupStatement { size_lookup : sl_state })
+    }
+
+    /* whether a file other than the one at entry_rowid (or its hardlinks, of the same
+       medium, device and inode) is recorded with this size */
+    pub fn size_taken(&mut self, size: u64, medium: &str, dev: u64, inode: u64, 
+                      entry_rowid: Option<u64>) -> Result<bool> {
+        let exclude : i64 = entry_rowid.map_or(-1, |r| r as i64);
+        self.size_lookup.exists(params![size as i64, exclude, medium, dev as i64, inode as i64])
+            .context("error when performing size lookup query")
+    }
+}
+
+/* keeps track of the rows met during a sync run, so that the rows of files 
+   that have vanished can be told apart and removed */
+pub struct SyncStatement<'conn> {
+    mark_seen : CachedStatement<'conn>,
+    relocate : CachedStatement<'conn>,
+    old_path : CachedStatement<'conn>,
+    relocate_members : CachedStatement<'conn>,
+    unseen : CachedStatement<'conn>,
+    remove : CachedStatement<'conn>,
+    roots : MediaRoots,
+    run_id : i64
+}
+
+impl<'conn> SyncStatement<'conn> {
+    /* moved files are recorded as last touched by the given run */
+    pub fn new<'d>(dt: &'d DBTransaction, run_id: i64) -> Result<SyncStatement<'d>> {
+        dt.trans.execute_batch("CREATE TEMP TABLE IF NOT EXISTS sync_seen (seen_rowid INTEGER PRIMARY KEY)")
+            .context("error creating table of files seen")?;
+        let ms_state = dt.trans.prepare_cached(
+            "INSERT OR IGNORE into temp.sync_seen (seen_rowid) values (?)")
+            .context("error compiling mark seen statement")?;
+        let rl_state = dt.trans.prepare_cached(
+            "UPDATE files set path = ?, fname = ?, size = ?, mtime = ?, ctime = ?, atime = ?, 
+             inode = ?, dev = ?, mode = ?, uid = ?, gid = ?, nlink = ?, run_id = ? where rowid = ?")
+            .context("error compiling relocate statement")?;
+        let op_state = dt.trans.prepare_cached("SELECT path, fname from files where rowid = ?")
+            .context("error compiling path of file statement")?;
+        /* replaces the members root (the archive path followed by !) of member paths */
+        let rm_members_state = dt.trans.prepare_cached(
+            "UPDATE files set path = ?1 || substr(path, length(?2) + 1) where container = ?3")
+            .context("error compiling relocate members statement")?;
+        /* files under the given path (all files of the medium for an empty path) */
+        let us_state = dt.trans.prepare_cached(
+            "SELECT rowid, medium_id, path, fname from files 
+             where medium_id = (select medium_id from media where medium = ?1) 
+             and (?2 = '' or path = ?2 or substr(path, 1, length(?2) + 1) = ?2 || '/')
+             and container is null and rowid not in (select seen_rowid from temp.sync_seen)")
+            .context("error compiling unseen files statement")?;
+        /* along with its members, if the file is an archive */
+        let rm_state = dt.trans.prepare_cached("DELETE from files where rowid = ?1 or container = ?1")
+            .context("error compiling file removal statement")?;
+        Ok(SyncStatement { mark_seen: ms_state, relocate: rl_state, old_path: op_state,
+                        relocate_members: rm_members_state, unseen: us_state, 
+                        remove: rm_state, roots: dt.media_roots()?, run_id })
+    }
+
+    pub fn seen(&mut self, rowid: u64) -> Result<()> {
+        self.mark_seen.execute(params![rowid as i64]).context("error while marking file as seen")?;
+        Ok(())
+    }
+
+    /* moves the row of a file to its new path, keeping its digests */
+    pub fn relocate(&mut self, rowid: u64, fm: &FileMetadata) -> Result<()> {
+        let (old_dir, old_fname) : (String, String) = self.old_path
+            .query_row(params![rowid as i64], |r| Ok((r.get(0)?, r.get(1)?)))
+            .context("error when looking up path of moved file")?;
+        let old_root = archive::members_root(&escape::decode(&old_dir).join(escape::decode(&old_fname)));
+        let new_root = archive::members_root(fm.path);
+        self.relocate_members.execute(params![escape::encode(&new_root), escape::encode(&old_root), rowid as i64])
+            .context("error while relocating archive members")?;
+        let (dir, fname) = split_path(fm.path);
+        self.relocate.execute(params![dir, fname, fm.size as i64,
+            fm.mtime, fm.ctime, fm.atime, fm.inode as i64, fm.dev as i64,
+            fm.mode, fm.uid, fm.gid, fm.nlink as i64, self.run_id, rowid as i64])
+            .context(&format!("UPDATE for moved file {:?}", fm.path))?;
+        self.seen(rowid)
+    }
+
+    /* removes the rows of the files under medium_path that were not seen and 
+       no longer exist, returns how many were removed */
+    pub fn remove_vanished(&mut self, medium: &str, medium_path: &Path) -> Result<usize> {
+        let unseen : Vec<(i64, i64, String, String)> = self.unseen
+            .query_map(params![medium, escape::encode(medium_path)],
+                       |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)))
+            .context("error when performing unseen files query")?
+            .collect::<rusqlite::Result<_>>().context("could not retrieve unseen file row")?;
+
+        let mut n_removed : usize = 0;
+        for (rowid, medium_id, path, fname) in unseen {
+            /* files skipped by filters or depth limits are still there */
+            let vanished = match self.roots.locate(medium_id, &path, &fname) {
+                Some(_p) => is_gone(&_p),
+                None => false
+            };
+            if vanished {
+                self.remove.execute(params![rowid]).context("error while removing vanished file")?;
+                n_removed += 1;
+            }
+        }
+        Ok(n_removed)
+    }
+}
+
+pub struct HardlinkStatement<'conn> {
+    find_hardlink : CachedStatement<'conn>
+}
+
+impl<'conn> HardlinkStatement<'conn> {
+    pub fn new<'d>(dt: &'d DBTransaction) -> Result<HardlinkStatement<'d>> {
+        let fh_state = dt.trans.prepare_cached(
+            "SELECT parthash, fasthash, slowhash from files 
+             where medium_id = (select medium_id from media where medium = ?) 
+             and dev = ? and inode = ? and size = ? and mtime = ? and link_target is null and rowid != ? 
+             and (parthash is not null or fasthash is not null or slowhash is not null)")
+            .context("error compiling hardlink lookup statement")?;
+        Ok(HardlinkStatement { find_hardlink: fh_state })
+    }
+
+    /* copies the digests of an already recorded hardlink (of the same medium, device and inode), if any */
+    pub fn copy_digests(&mut self, fm: &mut FileMetadata, entry_rowid: Option<u64>) -> Result<bool> {
+        let exclude : i64 = entry_rowid.map_or(-1, |r| r as i64);
+        let found = self.find_hardlink.query_row(
+            params![fm.medium, fm.dev as i64, fm.inode as i64, fm.size as i64, fm.mtime, exclude],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)));
+        match found {
+            Ok((parthash, fasthash, slowhash)) => {
+                fm.parthash = parthash;
+                fm.fasthash = fasthash;
+                fm.slowhash = slowhash;
+                Ok(true)
+            },
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+            Err(_e) => Err(Error::Db("error when performing hardlink lookup query".to_string(), _e))
+        }
+    }
+}
+
+/* what could not be done with a file during a run, as recorded in the errors table
+   (so names must not change); permission errors are told apart whatever was being done */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FailureKind {
+    Walk,        // reading a directory
+    Stat,
+    Open,
+    Read,
+    Symlink,     // reading its target
+    Archive,     // reading its members
+    Permission
+}
+
+impl FailureKind {
+    /* this kind, or Permission if that is what the error is about */
+    pub fn of(self, e: &std::io::Error) -> FailureKind {
+        match e.kind() {
+            std::io::ErrorKind::PermissionDenied => FailureKind::Permission,
+            _ => self
+        }
+    }
+}
+
+impl std::fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            FailureKind::Walk => "walk",
+            FailureKind::Stat => "stat",
+            FailureKind::Open => "open",
+            FailureKind::Read => "read",
+            FailureKind::Symlink => "symlink",
+            FailureKind::Archive => "archive",
+            FailureKind::Permission => "permission"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for FailureKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "walk" => Ok(FailureKind::Walk),
+            "stat" => Ok(FailureKind::Stat),
+            "open" => Ok(FailureKind::Open),
+            "read" => Ok(FailureKind::Read),
+            "symlink" => Ok(FailureKind::Symlink),
+            "archive" => Ok(FailureKind::Archive),
+            "permission" => Ok(FailureKind::Permission),
+            _ => Err(format!("unknown error kind {}", s))
+        }
+    }
+}
+
+/* a stash run that did not complete (e.g. was interrupted) */
+pub struct InterruptedRun {
+    pub run_id: i64,
+    pub paths: Vec<PathBuf>,
+    pub last_path: Option<PathBuf>,
+    pub n_files: u64,
+    pub n_bytes: u64
+}
+
+/* who started a run where, and how it hashes files */
+pub struct RunProvenance {
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub algorithms: HashAlgorithms,
+    pub options: Vec<&'static str>   // settings the files are walked and hashed with
+}
+
+/* a recorded file as needed to check it against its stored digests */
+pub struct VerifiableFile {
+    pub rowid: u64,
+    pub full_path: Option<PathBuf>, // None if its medium is not available
+    pub display_path: String,
+    pub size: u64,
+    pub mtime: Option<i64>,
+    pub parthash: Option<String>,
+    pub fasthash: Option<String>,
+    pub slowhash: Option<String>
+}
+
+pub struct VerifyStatement<'conn> {
+    to_verify : CachedStatement<'conn>,
+    mark_verified : CachedStatement<'conn>,
+    store_fasthash : CachedStatement<'conn>,
+    roots : MediaRoots
+}
+
+impl<'conn> VerifyStatement<'conn> {
+    pub fn new<'d>(dt: &'d DBTransaction) -> Result<VerifyStatement<'d>> {
+        /* archive members cannot be read on their own, they are verified along with their archive */
+        let tv_state = dt.trans.prepare_cached(
+            "SELECT rowid, medium_id, path, fname, size, mtime, parthash, fasthash, slowhash from files
+             where link_target is null and container is null 
+             and (?1 is null or medium_id = (select medium_id from media where medium = ?1))
+             order by verified, rowid limit ?2")
+            .context("error compiling files to verify statement")?;
+        let mv_state = dt.trans.prepare_cached(
+            "UPDATE files set verified = strftime('%s', 'now') where rowid = ?")
+            .context("error compiling mark verified statement")?;
+        let sf_state = dt.trans.prepare_cached("UPDATE files set fasthash = ? where rowid = ?")
+            .context("error compiling store fasthash statement")?;
+        Ok(VerifyStatement { to_verify: tv_state, mark_verified: mv_state, store_fasthash: sf_state,
+                          roots: dt.media_roots()? })
+    }
+
+    /* the files of the medium (or of all media), least recently verified first */
+    pub fn files(&mut self, medium: Option<&str>, limit: Option<u64>) -> Result<Vec<VerifiableFile>> {
+        let limit : i64 = limit.map_or(-1, |l| l as i64);
+        let roots = &self.roots;
+        self.to_verify.query_map(params![medium, limit], |r| {
+                let (medium_id, path, fname) : (i64, String, String) = (r.get(1)?, r.get(2)?, r.get(3)?);
+                Ok(VerifiableFile { rowid: r.get::<usize, i64>(0)? as u64,
+                                    full_path: roots.locate(medium_id, &path, &fname),
+                                    display_path: roots.display(medium_id, &path, &fname),
+                                    size: r.get::<usize, i64>(4)? as u64, mtime: r.get(5)?,
+                                    parthash: r.get(6)?, fasthash: r.get(7)?, slowhash: r.get(8)? })
+            })
+            .context("error when performing query for files to verify")?
+            .collect::<rusqlite::Result<_>>().context("could not retrieve file to verify")
+    }
+
+    pub fn verified(&mut self, rowid: u64) -> Result<()> {
+        self.mark_verified.execute(params![rowid as i64])
+            .context("error while storing verification time")?;
+        Ok(())
+    }
+
+    /* files recorded without any digest get one to be verified against from now on */
+    pub fn set_fasthash(&mut self, rowid: u64, fasthash: &str) -> Result<()> {
+        self.store_fasthash.execute(params![fasthash, rowid as i64])
+            .context("error while storing fasthash of verified file")?;
+        Ok(())
+    }
+}
+
+pub struct RunStatement<'conn> {
+    run_insert : CachedStatement<'conn>,
+    run_path_insert : CachedStatement<'conn>,
+    run_progress : CachedStatement<'conn>,
+    run_finish : CachedStatement<'conn>,
+    last_run : CachedStatement<'conn>,
+    run_paths : CachedStatement<'conn>,
+    error_insert : CachedStatement<'conn>
+}
+
+impl<'conn> RunStatement<'conn> {
+    pub fn new<'d>(dt: &'d DBTransaction) -> Result<RunStatement<'d>> {
+        let ri_state = dt.trans.prepare_cached(
+            "INSERT into runs (started, hostname, user, quick_hash, slow_hash, options) 
+             values (strftime('%s', 'now'), ?, ?, ?, ?, ?)")
+            .context("error compiling run insertion statement")?;
+        let rpi_state = dt.trans.prepare_cached(
+            "INSERT into run_paths (run_id, path, medium_id) 
+             values (?1, ?2, (select medium_id from media where medium = ?3))")
+            .context("error compiling run path insertion statement")?;
+        let rp_state = dt.trans.prepare_cached(
+            "UPDATE runs set last_path = ?, n_files = ?, n_bytes = ? where run_id = ?")
+            .context("error compiling run progress statement")?;
+        let rf_state = dt.trans.prepare_cached(
+            "UPDATE runs set completed = ?2, finished = strftime('%s', 'now') where run_id = ?1")
+            .context("error compiling run completion statement")?;
+        let lr_state = dt.trans.prepare_cached(
+            "SELECT run_id, completed, last_path, n_files, n_bytes from runs order by run_id desc limit 1")
+            .context("error compiling last run statement")?;
+        let rps_state = dt.trans.prepare_cached(
+            "SELECT path from run_paths where run_id = ? order by rowid")
+            .context("error compiling run paths statement")?;
+        let ei_state = dt.trans.prepare_cached(
+            "INSERT into errors (run_id, medium_id, path, fname, kind, reason) 
+             values (?1, (select medium_id from media where medium = ?2), ?3, ?4, ?5, ?6)")
+            .context("error compiling error insertion statement")?;
+        Ok(RunStatement { run_insert: ri_state, run_path_insert: rpi_state, run_progress: rp_state,
+                       run_finish: rf_state, last_run: lr_state, run_paths: rps_state,
+                       error_insert: ei_state })
+    }
+
+    /* records the start of a run over the given paths (each with its medium), returns its id */
+    pub fn start(&mut self, provenance: &RunProvenance, paths: &[(&Path, &str)]) -> Result<i64> {
+        let run_id = self.run_insert.insert(params![provenance.hostname, provenance.user,
+                                                    provenance.algorithms.quick.to_string(),
+                                                    provenance.algorithms.slow.to_string(),
+                                                    provenance.options.join(",")])
+            .context("error while recording run")?;
+        for (p, medium) in paths {
+            self.run_path_insert.execute(params![run_id, escape::encode(p), medium])
+                .context("error while recording path of run")?;
+        }
+        Ok(run_id)
+    }
+
+    pub fn progress(&mut self, run_id: i64, last_path: &Path, n_files: u64, n_bytes: u64) -> Result<()> {
+        self.run_progress.execute(params![escape::encode(last_path), n_files as i64, n_bytes as i64, run_id])
+            .context("error while recording progress of run")?;
+        Ok(())
+    }
+
+    /* records when the run stopped, and whether it got to the end */
+    pub fn finish(&mut self, run_id: i64, completed: bool) -> Result<()> {
+        self.run_finish.execute(params![run_id, completed]).context("error while recording end of run")?;
+        Ok(())
+    }
+
+    /* records a file (given relative to the root of its medium) that the run could not
+       record, or only in part */
+    pub fn failed(&mut self, run_id: i64, medium: &str, path: &Path, kind: FailureKind, 
+                  reason: &str) -> Result<()> {
+        let (dir, fname) = split_path(path);
+        self.error_insert.execute(params![run_id, medium, dir, fname, kind.to_string(), reason])
+            .context("error while recording error of run")?;
+        Ok(())
+    }
+
+    /* the latest run, if it did not complete */
+    pub fn interrupted(&mut self) -> Result<Option<InterruptedRun>> {
+        let last = self.last_run.query_row([], 
+            |r| Ok((r.get::<usize, i64>(0)?, r.get::<usize, bool>(1)?, 
+                    r.get::<usize, Option<String>>(2)?, r.get::<usize, i64>(3)?, r.get::<usize, i64>(4)?)));
+        match last {
+            Ok((run_id, false, last_path, n_files, n_bytes)) => {
+                let paths = self.run_paths.query_map(params![run_id], |r| r.get::<usize, String>(0))
+                    .context("error when performing run paths query")?
+                    .map(|_p| _p.map(|_p| escape::decode(&_p)))
+                    .collect::<rusqlite::Result<_>>().context("could not retrieve run path row")?;
+                Ok(Some(InterruptedRun { run_id, paths, last_path: last_path.map(|_p| escape::decode(&_p)),
+                                         n_files: n_files as u64, n_bytes: n_bytes as u64 }))
+            },
+            Ok(_) | Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(_e) => Err(Error::Db("error when performing last run query".to_string(), _e))
+        }
+    }
+}
+
+/* a stash run as recorded, runs of older versions lacking their provenance */
+pub struct RunInfo {
+    pub run_id: i64,
+    pub started: String,           // local time
+    pub finished: Option<String>,  // when it last stopped, even if it did not complete
+    pub completed: bool,
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub paths: Vec<(PathBuf, Option<String>)>, // each with its medium
+    pub algorithms: Option<(String, String)>,  // quick and slow
+    pub options: Vec<String>,
+    pub n_files: u64,
+    pub n_bytes: u64,
+    pub n_errors: u64
+}
+
+pub struct RunListStatement<'conn> {
+    run_list : CachedStatement<'conn>,
+    run_paths : CachedStatement<'conn>
+}
+
+impl<'conn> RunListStatement<'conn> {
+    pub fn new(db: &DataBase) -> Result<RunListStatement<'_>> {
+        let rl_state = db.conn.prepare_cached(
+            "SELECT run_id, datetime(started, 'unixepoch', 'localtime'), 
+             datetime(finished, 'unixepoch', 'localtime'), completed, hostname, user, 
+             quick_hash, slow_hash, options, n_files, n_bytes, 
+             (select count(*) from errors where errors.run_id = runs.run_id) from runs
+             where ?1 is null or run_id in (select run_id from run_paths 
+                 where medium_id = (select medium_id from media where medium = ?1))
+             order by run_id desc limit ?2")
+            .context("error compiling run list statement")?;
+        let rps_state = db.conn.prepare_cached(
+            "SELECT path, medium from run_paths LEFT JOIN media ON media.medium_id = run_paths.medium_id
+             where run_id = ? order by run_paths.rowid")
+            .context("error compiling run paths statement")?;
+        Ok(RunListStatement { run_list: rl_state, run_paths: rps_state })
+    }
+
+    /* the latest runs (over the given medium, if any), oldest first */
+    pub fn runs(&mut self, medium: Option<&str>, limit: Option<u64>) -> Result<Vec<RunInfo>> {
+        let limit : i64 = limit.map_or(-1, |l| l as i64);
+        let mut runs : Vec<RunInfo> = self.run_list.query_map(params![medium, limit], |r| {
+                let (quick, slow) : (Option<String>, Option<String>) = (r.get(6)?, r.get(7)?);
+                Ok(RunInfo { run_id: r.get(0)?, started: r.get(1)?, finished: r.get(2)?, 
+                             completed: r.get(3)?, hostname: r.get(4)?, user: r.get(5)?,
+                             paths: Vec::new(), algorithms: quick.zip(slow),
+                             options: r.get::<usize, Option<String>>(8)?.filter(|_o| !_o.is_empty())
+                                 .map_or(Vec::new(), |_o| _o.split(',').map(String::from).collect()),
+                             n_files: r.get::<usize, i64>(9)? as u64, n_bytes: r.get::<usize, i64>(10)? as u64,
+                             n_errors: r.get::<usize, i64>(11)? as u64 })
+            })
+            .context("error when performing run list query")?
+            .collect::<rusqlite::Result<_>>().context("could not retrieve run row")?;
+        runs.reverse();
+        for run in runs.iter_mut() {
+            run.paths = self.run_paths.query_map(params![run.run_id], 
+                    |r| Ok((escape::decode(&r.get::<usize, String>(0)?), r.get(1)?)))
+                .context("error when performing run paths query")?
+                .collect::<rusqlite::Result<_>>().context("could not retrieve run path row")?;
+        }
+        Ok(runs)
+    }
+}
+
+/* a file that a run could not record, or only in part */
+pub struct RecordedError {
+    pub run_id: i64,
+    pub medium: String,
+    pub path: PathBuf,      // as given by MediaRoots::path()
+    pub kind: FailureKind,
+    pub reason: String
+}
+
+pub struct ErrorListStatement<'conn> {
+    error_list : CachedStatement<'conn>,
+    last_run : CachedStatement<'conn>,
+    roots : MediaRoots
+}
+
+impl<'conn> ErrorListStatement<'conn> {
+    pub fn new(db: &DataBase) -> Result<ErrorListStatement<'_>> {
+        let el_state = db.conn.prepare_cached(
+            "SELECT run_id, errors.medium_id, medium, path, fname, kind, reason from errors
+             JOIN media ON media.medium_id = errors.medium_id
+             where (?1 is null or run_id = ?1) and (?2 is null or medium = ?2)
+             order by run_id, errors.rowid")
+            .context("error compiling error list statement")?;
+        let lr_state = db.conn.prepare_cached(
+            "SELECT run_id from runs where ?1 is null or run_id = ?1 order by run_id desc limit 1")
+            .context("error compiling last run statement")?;
+        Ok(ErrorListStatement { error_list: el_state, last_run: lr_state, roots: db.media_roots()? })
+    }
+
+    /* the given run (or else the latest one), None if there is no such run */
+    pub fn run(&mut self, run_id: Option<i64>) -> Result<Option<i64>> {
+        self.last_run.query_row(params![run_id], |r| r.get(0)).optional()
+            .context("error when performing last run query")
+    }
+
+    /* the errors of a run (or of all runs) in the order they were met, those of 
+       the given medium only if any */
+    pub fn errors(&mut self, run_id: Option<i64>, medium: Option<&str>) -> Result<Vec<RecordedError>> {
+        let roots = &self.roots;
+        self.error_list.query_map(params![run_id, medium], |r| {
+                let (medium_id, path, fname) : (i64, String, String) = (r.get(1)?, r.get(3)?, r.get(4)?);
+                let kind = r.get::<usize, String>(5)?.parse::<FailureKind>().map_err(|_e| 
+                    rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Text, _e.into()))?;
+                Ok(RecordedError { run_id: r.get(0)?, medium: r.get(2)?, 
+                                   path: roots.path(medium_id, &path, &fname), kind, reason: r.get(6)? })
+            })
+            .context("error when performing error list query")?
+            .collect::<rusqlite::Result<_>>().context("could not retrieve error row")
     }
 }
 
 pub struct MediaInsertStatement<'conn> {
-    media_insert : CachedStatement<'conn>
+    media_insert : CachedStatement<'conn>,
+    medium_by_uuid : CachedStatement<'conn>,
+    medium_uuid : CachedStatement<'conn>,
+    files_under : CachedStatement<'conn>
 }
 
 impl<'conn> Drop for MediaInsertStatement<'conn> {
@@ -249,130 +1448,382 @@ impl<'conn> Drop for MediaInsertStatement<'conn> {
 }
 
 impl<'conn> MediaInsertStatement<'conn> {
-    pub fn new<'d>(dt: &'d DBTransaction) -> MediaInsertStatement<'d> {
+    pub fn new<'d>(dt: &'d DBTransaction) -> Result<MediaInsertStatement<'d>> {
         let mi_state = dt.trans.prepare_cached(
-            "INSERT OR IGNORE INTO media (medium, medium_comment) 
-             VALUES (?, ?)")
-            .expect("error compiling media insertion statement");
-        MediaInsertStatement { media_insert: mi_state}
+            "INSERT INTO media (medium, medium_comment, uuid, label, fstype, capacity) 
+             VALUES (?1, ?2, ?4, ?5, ?6, ?7) 
+             ON CONFLICT (medium) DO UPDATE SET 
+                medium_comment = CASE WHEN ?3 THEN ?2 ELSE medium_comment END,
+                uuid = COALESCE(?4, uuid), label = COALESCE(?5, label),
+                fstype = COALESCE(?6, fstype), capacity = COALESCE(?7, capacity)")
+            .context("error compiling media insertion statement")?;
+        let mu_state = dt.trans.prepare_cached(
+            "SELECT medium FROM media WHERE uuid = ?")
+            .context("error compiling medium by uuid statement")?;
+        let um_state = dt.trans.prepare_cached(
+            "SELECT uuid FROM media WHERE medium = ?")
+            .context("error compiling uuid of medium statement")?;
+        let fu_state = dt.trans.prepare_cached(
+            "SELECT exists(SELECT 1 from files 
+             where medium_id = (select medium_id from media where medium = ?1 and uuid is null) 
+             and (?2 = '' or path = ?2 or substr(path, 1, length(?2) + 1) = ?2 || '/'))")
+            .context("error compiling files under path statement")?;
+        Ok(MediaInsertStatement { media_insert: mi_state, medium_by_uuid: mu_state,
+                               medium_uuid: um_state, files_under: fu_state })
     }
 
-    pub fn try_add_medium(&mut self, medium: &str, descr: &str) {
+    /* an existing medium keeps its description, unless a new one is given; 
+       filesystem details are recorded for media identified by UUID */
+    pub fn try_add_medium(&mut self, medium: &str, descr: Option<&str>, fs: Option<&Mount>) -> Result<()> {
         self.media_insert.execute(
-            params![medium, descr]).expect("adding medium entry");
+            params![medium, descr.unwrap_or(DEFAULT_MEDIUM_DESCR), descr.is_some(),
+                    fs.and_then(|m| m.uuid.as_ref()), fs.and_then(|m| m.label.as_ref()),
+                    fs.map(|m| &m.fstype), fs.and_then(|m| m.capacity()).map(|c| c as i64)])
+            .context("error adding medium entry")?;
+        Ok(())
+    }
+
+    pub fn medium_by_uuid(&mut self, uuid: &str) -> Result<Option<String>> {
+        self.medium_by_uuid.query_row([uuid], |r| r.get(0)).optional()
+            .context("error when looking up medium by UUID")
+    }
+
+    /* None if there is no such medium, Some(None) if it has no UUID */
+    pub fn medium_uuid(&mut self, medium: &str) -> Result<Option<Option<String>>> {
+        self.medium_uuid.query_row([medium], |r| r.get(0)).optional()
+            .context("error when looking up UUID of medium")
+    }
+
+    /* whether a medium without a UUID holds files under the given path */
+    pub fn has_files_under(&mut self, medium: &str, medium_path: &Path) -> Result<bool> {
+        self.files_under.query_row(params![medium, escape::encode(medium_path)], |r| r.get(0))
+            .context("error when looking up files of medium")
+    }
+}
+
+pub struct MediumInfo {
+    pub medium: String,
+    pub comment: Option<String>,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+    pub fstype: Option<String>,
+    pub capacity: Option<u64>,
+    pub num_files: u64,
+    pub size: u64
+}
+
+pub struct MediaListStatement<'conn> {
+    media_list: CachedStatement<'conn>
+}
+
+impl<'conn> MediaListStatement<'conn> {
+    pub fn new(db: &DataBase) -> Result<MediaListStatement<'_>> {
+        let ml_state = db.conn.prepare_cached(
+            "SELECT medium, medium_comment, COUNT(files.medium_id), 
+             COALESCE(SUM(files.size), 0), uuid, label, fstype, capacity FROM media 
+             LEFT JOIN files ON media.medium_id = files.medium_id 
+             GROUP BY media.medium_id ORDER BY medium")
+            .context("error compiling media list statement")?;
+        Ok(MediaListStatement { media_list: ml_state })
+    }
+
+    pub fn get_media(&mut self) -> Result<Vec<MediumInfo>> {
+        let mut v : Vec<MediumInfo> = Vec::new();
+        let mut rows = self.media_list.query([])
+            .context("error executing media list query")?;
+
+        while let Some(row) = rows.next()
+            .context("could not retrieve next row")?
+        {
+            v.push(MediumInfo { 
+                medium: row.get::<usize, String>(0)?,
+                comment: row.get::<usize, Option<String>>(1)?,
+                uuid: row.get::<usize, Option<String>>(4)?,
+                label: row.get::<usize, Option<String>>(5)?,
+                fstype: row.get::<usize, Option<String>>(6)?,
+                capacity: row.get::<usize, Option<i64>>(7)?.map(|c| c as u64),
+                num_files: row.get::<usize, i64>(2)? as u64,
+                size: row.get::<usize, i64>(3)? as u64 });
+        }
+        Ok(v)
     }
+}
 
+pub struct MediaEditStatement<'conn> {
+    rename : CachedStatement<'conn>,
+    describe : CachedStatement<'conn>,
+    delete_files : CachedStatement<'conn>,
+    delete_errors : CachedStatement<'conn>,
+    forget_run_paths : CachedStatement<'conn>,
+    delete_medium : CachedStatement<'conn>
+}
+
+impl<'conn> MediaEditStatement<'conn> {
+    pub fn new<'d>(dt: &'d DBTransaction) -> Result<MediaEditStatement<'d>> {
+        let rn_state = dt.trans.prepare_cached(
+            "UPDATE media SET medium = ? WHERE medium = ?")
+            .context("error compiling medium rename statement")?;
+        let ds_state = dt.trans.prepare_cached(
+            "UPDATE media SET medium_comment = ? WHERE medium = ?")
+            .context("error compiling medium description statement")?;
+        let df_state = dt.trans.prepare_cached(
+            "DELETE FROM files WHERE medium_id = 
+             (SELECT medium_id FROM media WHERE medium = ?)")
+            .context("error compiling deletion of medium files statement")?;
+        let de_state = dt.trans.prepare_cached(
+            "DELETE FROM errors WHERE medium_id = 
+             (SELECT medium_id FROM media WHERE medium = ?)")
+            .context("error compiling deletion of medium errors statement")?;
+        let fr_state = dt.trans.prepare_cached(
+            "UPDATE run_paths SET medium_id = NULL WHERE medium_id = 
+             (SELECT medium_id FROM media WHERE medium = ?)")
+            .context("error compiling medium of run paths statement")?;
+        let dm_state = dt.trans.prepare_cached(
+            "DELETE FROM media WHERE medium = ?")
+            .context("error compiling medium deletion statement")?;
+        Ok(MediaEditStatement { rename: rn_state, describe: ds_state,
+                             delete_files: df_state, delete_errors: de_state, 
+                             forget_run_paths: fr_state, delete_medium: dm_state })
+    }
+
+    /* all methods return false if no such medium was found */
+
+    pub fn rename(&mut self, medium: &str, new_name: &str) -> Result<bool> {
+        let n = self.rename.execute(params![new_name, medium])
+            .context(&format!("could not rename medium (is {} already taken?)", new_name))?;
+        Ok(n > 0)
+    }
+
+    pub fn describe(&mut self, medium: &str, descr: &str) -> Result<bool> {
+        let n = self.describe.execute(params![descr, medium])
+            .context("error updating medium description")?;
+        Ok(n > 0)
+    }
+
+    /* deletes the medium along with all of its recorded files (and errors), returns 
+       the number of files removed from the stash; the runs over it are kept */
+    pub fn delete(&mut self, medium: &str) -> Result<Option<usize>> {
+        let n_files = self.delete_files.execute([medium])
+            .context("error deleting files of medium")?;
+        self.delete_errors.execute([medium]).context("error deleting errors of medium")?;
+        self.forget_run_paths.execute([medium]).context("error deleting medium of run paths")?;
+        match self.delete_medium.execute([medium]).context("error deleting medium")? {
+            0 => Ok(None),
+            _ => Ok(Some(n_files))
+        }
+    }
+}
+
+/* how certain it is that the files of a set are duplicates */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Confidence {
+    SizeOnly,
+    Fasthash,
+    Slowhash,
+    ByteVerified
+}
+
+impl std::fmt::Display for Confidence {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Confidence::SizeOnly => "size-only",
+            Confidence::Fasthash => "fasthash",
+            Confidence::Slowhash => "slowhash",
+            Confidence::ByteVerified => "byte-verified"
+        };
+        write!(f, "{}", name)
+    }
 }
 
 pub struct DupFile {
-    pub one_path: String,
-    pub other_paths: Vec<String>,
+    pub one_path: PathBuf,
+    pub other_paths: Vec<PathBuf>,
     pub hash: String,
     pub num_dups: u64,
-    pub size: u64
+    pub size: u64,
+    /* (medium, device, inode) of one_path followed by those of other_paths, as recorded */
+    pub inodes: Vec<Option<(i64, u64, u64)>>,
+    /* whether each of these paths is a member of an archive rather than a file of its own */
+    pub members: Vec<bool>,
+    /* mtime of each of these paths, as recorded (if it was) */
+    pub mtimes: Vec<Option<i64>>,
+    pub confidence: Confidence
 }
 
 impl DupFile {
+    /* whether the idx-th path (0 being one_path) shares the inode of an earlier one */
+    pub fn is_hardlink(&self, idx: usize) -> bool {
+        match self.inodes[idx] {
+            Some(key) => self.inodes[..idx].contains(&Some(key)),
+            None => false
+        }
+    }
+
+    /* number of distinct copies of the content, hardlinks not counted */
+    pub fn num_copies(&self) -> u64 {
+        (0..self.inodes.len()).filter(|&i| !self.is_hardlink(i)).count() as u64
+    }
+
+    /* bytes freed by removing all copies but one; archive members take no space of their
+       own and are not removed, so only the copies that are files of their own count */
+    pub fn freeable_bytes(&self) -> u64 {
+        let n_files = (0..self.inodes.len()).filter(|&i| !self.is_hardlink(i) && !self.members[i]).count();
+        n_files.saturating_sub(1) as u64 * self.size
+    }
+
     pub fn format_minimal(&self, indexed: bool) -> String {
         let mut st = String::new();
         let mut idx : usize = 1;
         if indexed {
             st.push_str(&format!("{}. ", idx));
         }
-        st.push_str(&self.one_path);
+        st.push_str(&escape::display(&self.one_path));
         idx += 1;
         for st_other in &self.other_paths {
             st.push('\n');
             if indexed {
                  st.push_str(&format!("{}. ", idx));
             }
-            st.push_str(&st_other);
+            st.push_str(&escape::display(st_other));
             idx += 1;
         }
         st
     }
-
-    pub fn path_sig(&self) -> String
-    {
-        let one_path_dir = PathBuf::from(&self.one_path).parent()
-            .unwrap_or(&PathBuf::from(&format!("{}",std::path::MAIN_SEPARATOR)))
-.to_path_buf();
-        let mut vec = vec![one_path_dir];
-        for p in &self.other_paths {
-            let other_path_dir = PathBuf::from(&p).parent()
-            .unwrap_or(&PathBuf::from(&format!("{}",std::path::MAIN_SEPARATOR)))
-.to_path_buf();
-            vec.push(other_path_dir);
-        }
-        vec.sort();
-        vec.dedup();
-
-        let mut new_vec : Vec<&str> = Vec::new();
-        for _n in &vec {
-            new_vec.push(_n.to_str().unwrap());
-        }
-        new_vec.join(",")
-    }
-                    
 }
 
-
 pub struct IdentifyDupsStatement<'conn> {
-    identify_dups: CachedStatement<'conn>
+    identify_dups: CachedStatement<'conn>,
+    identify_probable_dups: CachedStatement<'conn>,
+    identify_size_dups: CachedStatement<'conn>,
+    roots: MediaRoots
 }
 
 impl<'conn> IdentifyDupsStatement<'conn> {
-    pub fn new(db: &DataBase) -> IdentifyDupsStatement {
+    pub fn new(db: &DataBase) -> Result<IdentifyDupsStatement<'_>> {
         let id_state = db.conn.prepare_cached(
             "SELECT files.path, files.fname, files.slowhash, 
-             T2.P, files.size from files join (select slowhash,COUNT(*) AS P 
+             T2.P, files.size, files.medium_id, files.inode, files.dev, files.container is not null, files.mtime from files join (select slowhash,COUNT(*) AS P 
              FROM files WHERE slowhash is NOT NULL GROUP BY slowhash HAVING COUNT(*) > 1 ORDER BY slowhash) 
-             T2 ON files.slowhash = T2.slowhash;")
-            .expect("error compiling lazy dup query statement");
-        IdentifyDupsStatement { identify_dups: id_state }
+             T2 ON files.slowhash = T2.slowhash ORDER BY files.slowhash;")
+            .context("error compiling lazy dup query statement")?;
+        let ipd_state = db.conn.prepare_cached(
+            "SELECT files.path, files.fname, files.fasthash, 
+             T2.P, files.size, files.medium_id, files.inode, files.dev, files.container is not null, files.mtime from files join (select fasthash,COUNT(*) AS P 
+             FROM files WHERE fasthash is NOT NULL GROUP BY fasthash 
+             HAVING COUNT(*) > 1 AND SUM(pending_slowhash) > 0 ORDER BY fasthash) 
+             T2 ON files.fasthash = T2.fasthash ORDER BY files.fasthash;")
+            .context("error compiling probable dup query statement")?;
+        /* files never hashed (e.g. their media were not available to fill in a digest) 
+           along with the other files of their size */
+        let isd_state = db.conn.prepare_cached(
+            "SELECT files.path, files.fname, cast(files.size as text), 
+             T2.P, files.size, files.medium_id, files.inode, files.dev, files.container is not null, files.mtime from files join (select size,COUNT(*) AS P 
+             FROM files WHERE link_target is NULL GROUP BY size 
+             HAVING COUNT(*) > 1 AND SUM(parthash is NULL and fasthash is NULL and slowhash is NULL) > 0 
+             ORDER BY size) 
+             T2 ON files.size = T2.size WHERE files.link_target is NULL ORDER BY files.size;")
+            .context("error compiling size dup query statement")?;
+        Ok(IdentifyDupsStatement { identify_dups: id_state, identify_probable_dups: ipd_state,
+                                identify_size_dups: isd_state, roots: db.media_roots()? })
     }
 
-    pub fn get_dups(&mut self) -> Vec<DupFile> {
-        let mut v : Vec<DupFile> = Vec::new();
-        let mut rows = self.identify_dups.query([])
-            .expect("error executing dup query");
+    /* sets of files of the same slowhash */
+    pub fn dups(&mut self) -> Result<DupSets<'_>> {
+        let rows = self.identify_dups.query([])
+            .context("error executing dup query")?;
+        Ok(DupSets { rows, roots: &self.roots, confidence: Confidence::Slowhash })
+    }
 
-        let mut start_of_new_duplicate_set = true;
-        let mut remaining_dups : u64 = 0;
+    /* sets of files of the same fasthash, of which some could not be slow hashed */
+    pub fn probable_dups(&mut self) -> Result<DupSets<'_>> {
+        let rows = self.identify_probable_dups.query([])
+            .context("error executing probable dup query")?;
+        Ok(DupSets { rows, roots: &self.roots, confidence: Confidence::Fasthash })
+    }
 
-        while let Some(row) = rows.next()
-            .expect("could not retrieve next row") 
-        {
-            if start_of_new_duplicate_set {
-               let num_dups : u64 = row.get_unwrap::<usize,i64>(3) as u64;
-               let hash : String = row.get_unwrap::<usize,String>(2);
-               let size : u64 = row.get_unwrap::<usize, i64>(4) as u64;
-               let v2: Vec<String> = Vec::new();
-               let one_path : String = format!("{}{}{}", 
-                    row.get_unwrap::<usize,String>(0),
-                    std::path::MAIN_SEPARATOR, 
-                    row.get_unwrap::<usize,String>(1));
-               v.push(DupFile { one_path,
-                                other_paths: v2,
-                                hash,
-                                num_dups, 
-                                size });
-               remaining_dups = num_dups - 1;
-               start_of_new_duplicate_set = false;
-            } else {
-                let last = v.last_mut().unwrap();
-                let one_path : String = format!("{}{}{}", 
-                    row.get_unwrap::<usize,String>(0),
-                    std::path::MAIN_SEPARATOR,
-                    row.get_unwrap::<usize,String>(1));
-                last.other_paths.push(one_path);
-                remaining_dups -= 1;
-                if remaining_dups == 0 {
-                    start_of_new_duplicate_set = true;
-                }
-            }
+    /* sets of files of the same size, of which some were never hashed */
+    pub fn size_dups(&mut self) -> Result<DupSets<'_>> {
+        let rows = self.identify_size_dups.query([])
+            .context("error executing size dup query")?;
+        Ok(DupSets { rows, roots: &self.roots, confidence: Confidence::SizeOnly })
+    }
+
+    pub fn get_dups(&mut self) -> Result<Vec<DupFile>> {
+        self.dups()?.collect()
+    }
+
+    /* unlike the sets of get_dups, those of links to a single file are left out, as they
+       would otherwise be reported for as long as the file goes unhashed */
+    pub fn get_probable_dups(&mut self) -> Result<Vec<DupFile>> {
+        self.probable_dups()?.filter(|_d| !matches!(_d, Ok(_dup) if _dup.num_copies() < 2)).collect()
+    }
+
+    pub fn get_size_dups(&mut self) -> Result<Vec<DupFile>> {
+        self.size_dups()?.filter(|_d| !matches!(_d, Ok(_dup) if _dup.num_copies() < 2)).collect()
+    }
+}
+
+/* sets of duplicate files, read off the rows of a dup query (ordered by digest, 
+   each row carrying the number of files of its set) as they are iterated */
+pub struct DupSets<'s> {
+    rows: Rows<'s>,
+    roots: &'s MediaRoots,
+    confidence: Confidence
+}
+
+impl<'s> DupSets<'s> {
+    /* adds the file of a row to its set: its path, (medium, device, inode), whether it is an archive member
+       and its mtime */
+    fn add_entry(dup: &mut DupFile, row: &rusqlite::Row, roots: &MediaRoots) -> Result<()> {
+        let medium_id : i64 = row.get(5)?;
+        let path = roots.path(medium_id,
+                                 &row.get::<usize,String>(0)?,
+                                 &row.get::<usize,String>(1)?);
+        if dup.inodes.is_empty() {
+            dup.one_path = path;
+        } else {
+            dup.other_paths.push(path);
+        }
+        dup.inodes.push(row.get::<usize, Option<i64>>(7)?.zip(row.get::<usize, Option<i64>>(6)?)
+                        .map(|(d, i)| (medium_id, d as u64, i as u64)));
+        dup.members.push(row.get(8)?);
+        dup.mtimes.push(row.get(9)?);
+        Ok(())
+    }
+
+    fn next_set(&mut self) -> Result<Option<DupFile>> {
+        let mut dup = match self.rows.next().context("could not retrieve next row")? {
+            Some(row) => {
+                let mut dup = DupFile { one_path: PathBuf::new(),
+                                        other_paths: Vec::new(),
+                                        hash: row.get::<usize,String>(2)?,
+                                        num_dups: row.get::<usize,i64>(3)? as u64,
+                                        size: row.get::<usize, i64>(4)? as u64,
+                                        inodes: Vec::new(),
+                                        members: Vec::new(),
+                                        mtimes: Vec::new(),
+                                        confidence: self.confidence };
+                Self::add_entry(&mut dup, row, self.roots)?;
+                dup
+            },
+            None => return Ok(None)
+        };
+        for _ in 1..dup.num_dups {
+            let row = match self.rows.next().context("could not retrieve next row")? {
+                Some(_row) => _row,
+                None => break
+            };
+            Self::add_entry(&mut dup, row, self.roots)?;
         }
-        return v;
+        Ok(Some(dup))
+    }
+}
+
+impl<'s> Iterator for DupSets<'s> {
+    type Item = Result<DupFile>;
+
+    fn next(&mut self) -> Option<Result<DupFile>> {
+        self.next_set().transpose()
     }
 }
 
@@ -0,0 +1,188 @@
+use rusqlite::{Connection, params};
+use crate::error::{Result, Context};
+
+/* each migration brings a stash file up to the schema version it is keyed on, from the
+   version of the migration before it; a new schema version always comes with a migration
+   here, with only the major byte of a version denoting a schema change */
+const MIGRATIONS : [(u16, &str); 15] = [
+    (0x0200,
+     "ALTER TABLE files ADD COLUMN mtime INTEGER;
+      ALTER TABLE files ADD COLUMN inode INTEGER"),
+
+    (0x0300,
+     "ALTER TABLE files ADD COLUMN ctime INTEGER;
+      ALTER TABLE files ADD COLUMN atime INTEGER;
+      ALTER TABLE files ADD COLUMN dev INTEGER;
+      ALTER TABLE files ADD COLUMN mode INTEGER;
+      ALTER TABLE files ADD COLUMN uid INTEGER;
+      ALTER TABLE files ADD COLUMN gid INTEGER;
+      ALTER TABLE files ADD COLUMN nlink INTEGER"),
+
+    /* paths became relative to the root of their medium, which is / for media without
+       a filesystem UUID (the only kind there was); files recorded by a relative path
+       cannot be placed on their medium, and are moved aside to quarantined_files */
+    (0x0400,
+     "ALTER TABLE media ADD COLUMN uuid text;
+      ALTER TABLE media ADD COLUMN label text;
+      ALTER TABLE media ADD COLUMN fstype text;
+      ALTER TABLE media ADD COLUMN capacity INTEGER;
+      CREATE UNIQUE INDEX idx_uuid ON media (uuid);
+      CREATE TABLE quarantined_files AS SELECT * FROM files WHERE substr(path, 1, 1) <> '/';
+      DELETE FROM files WHERE substr(path, 1, 1) <> '/';
+      UPDATE files SET path = substr(path, 2)"),
+
+    (0x0500,
+     "ALTER TABLE files ADD COLUMN link_target text"),
+
+    (0x0600,
+     "CREATE INDEX idx_inode ON files (medium_id, inode)"),
+
+    (0x0700,
+     "ALTER TABLE files ADD COLUMN parthash text;
+      CREATE INDEX idx_parthash ON files (parthash)"),
+
+    (0x0800,
+     "CREATE TABLE runs
+      (run_id INTEGER NOT NULL,
+      started INTEGER NOT NULL,
+      finished INTEGER,
+      completed INTEGER NOT NULL DEFAULT 0,
+      last_path text,
+      n_files INTEGER NOT NULL DEFAULT 0,
+      PRIMARY KEY (run_id));
+      CREATE TABLE run_paths
+      (run_id INTEGER NOT NULL,
+      path text NOT NULL,
+      FOREIGN KEY(run_id) REFERENCES runs(run_id))"),
+
+    (0x0900,
+     "ALTER TABLE files ADD COLUMN container INTEGER;
+      CREATE INDEX idx_container ON files (container)"),
+
+    /* force_sha512 gave way to named algorithms, xxh3 being the only quick one before */
+    (0x0a00,
+     "INSERT INTO config (key, value) VALUES ('quick_hash', 'xxh3');
+      INSERT INTO config (key, value)
+        SELECT 'slow_hash', CASE value WHEN 'true' THEN 'sha512' ELSE 'md5' END
+        FROM config WHERE key = 'force_sha512';
+      DELETE FROM config WHERE key = 'force_sha512'"),
+
+    (0x0b00,
+     "ALTER TABLE files ADD COLUMN pending_slowhash INTEGER NOT NULL DEFAULT 0"),
+
+    (0x0c00,
+     "ALTER TABLE files ADD COLUMN verified INTEGER;
+      CREATE INDEX idx_verified ON files (medium_id, verified)"),
+
+    /* paths became escaped (see escape), paths with no backslash being recorded as before */
+    (0x0d00,
+     "UPDATE files SET path = replace(path, '\\', '\\\\'), fname = replace(fname, '\\', '\\\\')
+        WHERE instr(path, '\\') > 0 OR instr(fname, '\\') > 0;
+      UPDATE files SET link_target = replace(link_target, '\\', '\\\\') WHERE instr(link_target, '\\') > 0;
+      UPDATE run_paths SET path = replace(path, '\\', '\\\\') WHERE instr(path, '\\') > 0;
+      UPDATE runs SET last_path = replace(last_path, '\\', '\\\\') WHERE instr(last_path, '\\') > 0"),
+
+    (0x0e00,
+     "CREATE TABLE errors
+      (run_id INTEGER NOT NULL,
+      medium_id INTEGER NOT NULL,
+      path text NOT NULL,
+      fname text NOT NULL,
+      kind text NOT NULL,
+      reason text NOT NULL,
+      FOREIGN KEY(run_id) REFERENCES runs(run_id),
+      FOREIGN KEY(medium_id) REFERENCES media(medium_id));
+      CREATE INDEX idx_errors ON errors (run_id, medium_id)"),
+
+    /* runs of older versions are left without provenance, and their files without a run */
+    (0x0f00,
+     "ALTER TABLE runs ADD COLUMN n_bytes INTEGER NOT NULL DEFAULT 0;
+      ALTER TABLE runs ADD COLUMN hostname text;
+      ALTER TABLE runs ADD COLUMN user text;
+      ALTER TABLE runs ADD COLUMN quick_hash text;
+      ALTER TABLE runs ADD COLUMN slow_hash text;
+      ALTER TABLE runs ADD COLUMN options text;
+      ALTER TABLE run_paths ADD COLUMN medium_id INTEGER REFERENCES media(medium_id);
+      ALTER TABLE files ADD COLUMN run_id INTEGER;
+      CREATE INDEX idx_run ON files (run_id)"),
+
+    /* hardlinks became identified by medium, device and inode, rows without a device having none */
+    (0x1000,
+     "CREATE INDEX idx_link ON files (medium_id, dev, inode)"),
+];
+
+/* applies the migrations a stash file of the given version is missing, all in one
+   transaction, and returns the version it is brought up to along with the number
+   of files quarantined on the way */
+pub fn migrate(conn: &mut Connection, from_version: u16) -> Result<(u16, u64)> {
+    let trans = conn.transaction().context("error starting migration transaction")?;
+    let mut version = from_version;
+    for (to_version, sql) in MIGRATIONS.iter() {
+        if (to_version & 0xff00) <= (version & 0xff00) {
+            continue;
+        }
+        trans.execute_batch(sql)
+            .context(&format!("error migrating stash file to version {:#06x}", to_version))?;
+        version = *to_version;
+    }
+    let n_quarantined : i64 = if (from_version & 0xff00) < 0x0400 {
+        trans.query_row("SELECT count(*) from quarantined_files", [], |r| r.get(0))
+            .context("error counting quarantined files")?
+    } else {
+        0
+    };
+    trans.execute("UPDATE config SET value = ? WHERE key = 'version'", params![version])
+        .context("error storing version of migrated stash file")?;
+    trans.commit().context("error committing migration of stash file")?;
+    Ok((version, n_quarantined as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DataBase;
+    use crate::testutil::{self, ScratchDir};
+    use std::path::PathBuf;
+
+    /* a stash file of the first schema, with a file recorded by an absolute path and 
+       one by a relative path, as the first versions did when given one */
+    fn first_schema_stash(scratch: &ScratchDir) -> PathBuf {
+        let dbfile = scratch.join("stash.db");
+        testutil::first_schema_stash(&dbfile, "default", &[("/home/user", "absolute", 5), ("user", "relative", 6)]);
+        dbfile
+    }
+
+    #[test]
+    fn upgrades_the_first_schema() {
+        let scratch = ScratchDir::new("migrate-first");
+        let dbfile = first_schema_stash(&scratch);
+
+        let store = DataBase::for_updating(&dbfile).unwrap();
+        let upgrade = store.upgraded().unwrap();
+        assert_eq!((upgrade.from, upgrade.to), (0x0100, MIGRATIONS[MIGRATIONS.len() - 1].0));
+        assert!(upgrade.backup.is_file());
+        assert_eq!(store.algorithms().slow.to_string(), "sha512");
+        drop(store);
+
+        /* the file recorded by a relative path is quarantined, the other placed on its medium */
+        let conn = Connection::open(&dbfile).unwrap();
+        let files : Vec<(String, String)> = conn.prepare("SELECT path, fname from files").unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(files, vec![("home/user".to_string(), "absolute".to_string())]);
+        let quarantined : Vec<(String, String)> = conn.prepare("SELECT path, fname from quarantined_files").unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap().collect::<rusqlite::Result<_>>().unwrap();
+        assert_eq!(quarantined, vec![("user".to_string(), "relative".to_string())]);
+
+        /* once upgraded, it is opened as it is */
+        assert!(DataBase::for_updating(&dbfile).unwrap().upgraded().is_none());
+        assert!(DataBase::for_reading(&dbfile).is_ok());
+    }
+
+    #[test]
+    fn counts_the_files_quarantined() {
+        let scratch = ScratchDir::new("migrate-quarantine");
+        let dbfile = first_schema_stash(&scratch);
+        assert_eq!(DataBase::upgrade(&dbfile).unwrap().unwrap().n_quarantined, 1);
+        assert!(DataBase::upgrade(&dbfile).unwrap().is_none());
+    }
+}
@@ -0,0 +1,197 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use crate::db::{Confidence, DupFile};
+use crate::escape;
+
+const COMPARE_CHUNK : usize = 1024 * 1024;
+
+// reads until buf is full or the end of the file is reached
+fn read_chunk(f: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled : usize = 0;
+    while filled < buf.len() {
+        match f.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(_e) if _e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_e) => return Err(_e)
+        }
+    }
+    Ok(filled)
+}
+
+fn same_content(a: &Path, b: &Path, buf_a: &mut [u8], buf_b: &mut [u8]) -> io::Result<bool> {
+    let mut fa = File::open(a)?;
+    let mut fb = File::open(b)?;
+    loop {
+        let na = read_chunk(&mut fa, buf_a)?;
+        let nb = read_chunk(&mut fb, buf_b)?;
+        if na != nb || buf_a[..na] != buf_b[..nb] {
+            return Ok(false);
+        }
+        if na == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// What byte-verifying duplicate sets found, besides the sets confirmed.
+#[derive(Default)]
+pub struct ConfirmReport {
+    pub unreadable: Vec<(PathBuf, String)>, // files left out of their sets, and why
+    pub split: Vec<(PathBuf, usize)>        // sets (by their first file) whose readable files did not
+                                            // all match, and the number of groups of matching files
+}
+
+/* compares the files of a set byte for byte, splitting it into the groups of files
+   that really match; files that cannot be read are left out, as are archive members,
+   which cannot be compared on their own */
+fn confirm_set(dup: &DupFile, buf_a: &mut [u8], buf_b: &mut [u8], report: &mut ConfirmReport) -> Vec<DupFile> {
+    let paths : Vec<&PathBuf> = std::iter::once(&dup.one_path).chain(dup.other_paths.iter()).collect();
+    let mut inodes : Vec<Option<(u64, u64)>> = vec![None; paths.len()];  // (device, inode) as they are now
+    let mut groups : Vec<Vec<usize>> = Vec::new();
+
+    'files: for i in 0..paths.len() {
+        if dup.members[i] {
+            continue;
+        }
+        /* links to the inode of a file already grouped need no comparing, as they are now
+           (the inodes recorded may since have been replaced) */
+        match std::fs::metadata(paths[i]) {
+            Ok(_meta) => inodes[i] = Some((_meta.dev(), _meta.ino())),
+            Err(_e) => {
+                report.unreadable.push((paths[i].clone(), format!("failed to stat file for comparing ({})", _e)));
+                continue;
+            }
+        }
+        if let Some(g) = groups.iter_mut().find(|g| inodes[g[0]] == inodes[i]) {
+            g.push(i);
+            continue;
+        }
+        if let Err(_e) = File::open(paths[i]) {
+            report.unreadable.push((paths[i].clone(), format!("failed to open file for comparing ({})", _e)));
+            continue;
+        }
+        for g in groups.iter_mut() {
+            match same_content(paths[g[0]], paths[i], buf_a, buf_b) {
+                Ok(true) => {
+                    g.push(i);
+                    continue 'files;
+                },
+                Ok(false) => (),
+                Err(_e) => {
+                    report.unreadable.push((paths[i].clone(), format!("failed to compare file with {} ({})", 
+                                                                      escape::display(paths[g[0]]), _e)));
+                    continue 'files;
+                }
+            }
+        }
+        groups.push(vec![i]);
+    }
+
+    /* files left out are reported as such, and do not make for a split */
+    if dup.confidence != Confidence::SizeOnly && groups.len() > 1 {
+        report.split.push((dup.one_path.clone(), groups.len()));
+    }
+    groups.into_iter()
+        .filter(|g| g.len() > 1)
+        .map(|g| DupFile { one_path: paths[g[0]].clone(),
+                           other_paths: g[1..].iter().map(|&j| paths[j].clone()).collect(),
+                           hash: dup.hash.clone(),
+                           num_dups: g.len() as u64,
+                           size: dup.size,
+                           inodes: g.iter().map(|&j| link_key(dup, g[0], inodes[j])).collect(),
+                           members: vec![false; g.len()],
+                           mtimes: g.iter().map(|&j| dup.mtimes[j]).collect(),
+                           confidence: Confidence::ByteVerified })
+        .collect()
+}
+
+/* the (medium, device, inode) key of a file of a confirmed group, off its (device, inode)
+   as compared; the whole group takes the medium of its first file, as links found then
+   are links whatever medium they were recorded in */
+fn link_key(dup: &DupFile, first: usize, live: Option<(u64, u64)>) -> Option<(i64, u64, u64)> {
+    let medium = dup.inodes[first].map_or(0, |_k| _k.0);
+    live.map(|(_d, _i)| (medium, _d, _i))
+}
+
+/// Byte-verifies duplicate sets, those that do not fully match being split up;
+/// also reports the files that could not be compared and the sets split.
+pub fn confirm_sets(dups: &[DupFile]) -> (Vec<DupFile>, ConfirmReport) {
+    let mut buf_a = vec![0u8; COMPARE_CHUNK];
+    let mut buf_b = vec![0u8; COMPARE_CHUNK];
+    let mut confirmed : Vec<DupFile> = Vec::new();
+    let mut report = ConfirmReport::default();
+    for dup in dups {
+        confirmed.extend(confirm_set(dup, &mut buf_a, &mut buf_b, &mut report));
+    }
+    (confirmed, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::ScratchDir;
+
+    /* a set of the given paths, as found by slowhash, recorded with inodes of their own */
+    fn dup_set(paths: &[PathBuf], members: &[bool]) -> DupFile {
+        DupFile { one_path: paths[0].clone(), other_paths: paths[1..].to_vec(), hash: "digest".to_string(),
+                  num_dups: paths.len() as u64, size: 5, inodes: (0..paths.len()).map(|_i| Some((1, 1, _i as u64))).collect(),
+                  members: members.to_vec(), mtimes: vec![None; paths.len()], confidence: Confidence::Slowhash }
+    }
+
+    #[test]
+    fn splits_sets_that_do_not_match() {
+        let scratch = ScratchDir::new("confirm-split");
+        let paths = [scratch.write("a", b"same\n"), scratch.write("b", b"diff\n"), scratch.write("c", b"same\n")];
+        let (confirmed, report) = confirm_sets(&[dup_set(&paths, &[false; 3])]);
+        assert_eq!(confirmed.len(), 1);
+        assert_eq!(confirmed[0].one_path, paths[0]);
+        assert_eq!(confirmed[0].other_paths, vec![paths[2].clone()]);
+        assert_eq!(confirmed[0].confidence, Confidence::ByteVerified);
+        assert_eq!(report.split, vec![(paths[0].clone(), 2)]);
+        assert!(report.unreadable.is_empty());
+    }
+
+    #[test]
+    fn leaves_out_unreadable_files_without_splitting() {
+        let scratch = ScratchDir::new("confirm-unreadable");
+        let paths = [scratch.write("a", b"same\n"), scratch.join("gone"), scratch.write("c", b"same\n")];
+        let (confirmed, report) = confirm_sets(&[dup_set(&paths, &[false; 3])]);
+        assert_eq!(confirmed.len(), 1);
+        assert_eq!(confirmed[0].other_paths, vec![paths[2].clone()]);
+        assert_eq!(report.unreadable.len(), 1);
+        assert_eq!(report.unreadable[0].0, paths[1]);
+        assert!(report.split.is_empty());
+    }
+
+    #[test]
+    fn leaves_out_archive_members() {
+        let scratch = ScratchDir::new("confirm-members");
+        let paths = [scratch.write("a", b"same\n"), scratch.join("backup.zip!/a"), scratch.write("c", b"same\n")];
+        let (confirmed, report) = confirm_sets(&[dup_set(&paths, &[false, true, false])]);
+        assert_eq!(confirmed.len(), 1);
+        assert_eq!(confirmed[0].other_paths, vec![paths[2].clone()]);
+        assert!(report.unreadable.is_empty());
+        assert!(report.split.is_empty());
+    }
+
+    #[test]
+    fn compares_files_that_are_no_longer_hardlinks() {
+        let scratch = ScratchDir::new("confirm-hardlinks");
+        let linked = scratch.write("a", b"same\n");
+        std::fs::hard_link(&linked, scratch.join("b")).unwrap();
+        let replaced = scratch.write("c", b"diff\n");
+        let paths = [linked, scratch.join("b"), replaced];
+
+        /* all three recorded as links of a single inode, of which c no longer is one */
+        let mut dup = dup_set(&paths, &[false; 3]);
+        dup.inodes = vec![Some((1, 1, 7)); 3];
+        let (confirmed, report) = confirm_sets(&[dup]);
+        assert_eq!(confirmed.len(), 1);
+        assert_eq!(confirmed[0].other_paths, vec![paths[1].clone()]);
+        assert!(confirmed[0].is_hardlink(1));
+        assert_eq!(report.split, vec![(paths[0].clone(), 2)]);
+    }
+}
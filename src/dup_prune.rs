@@ -1,14 +1,16 @@
 use indexmap::map::IndexMap;
 use std::path::PathBuf;
-use std::io;
 use std::fmt;
 use std::collections::HashSet;
-use regex::Regex;
-use crate::db::DupFile;
+use std::os::unix::fs::MetadataExt;
+use crate::db::{Confidence, DupFile};
+use crate::escape;
+use crate::error::{Error, Result, Context};
 
-#[derive(Copy, Clone)]
+/// Which of the files of a duplicate set to keep, the others being removed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[allow(non_camel_case_types)]
-enum KeepStrategy { 
+pub enum KeepStrategy { 
     KEEP_AS_IS,
     KEEP_THIS_OF_THESE(usize),
     KEEP_THIS_OF_ANY(usize),
@@ -31,62 +33,15 @@ impl fmt::Display for KeepStrategy {
     }
 }
 
-impl KeepStrategy {
-    fn from_str(s : &str, max_idx: usize, default: Option<KeepStrategy>) 
-        -> Result<KeepStrategy, &'static str>
-    {
-        if let Some(d) = default {
-            if let KeepStrategy::KEEP_THIS_OF_THESE(_) = d {
-                return Err("Cannot use \"keep this of these\" rule as default rule");
-            }
-            if let KeepStrategy::KEEP_THIS_OF_ANY(_) = d {
-                return Err("Cannot use \"keep this of any\" rule as default rule");
-            }
-        }
-
-        let re_keep_as_is = Regex::new("^a").unwrap();
-        let re_keep_this_of_these = Regex::new("^b ([0-9]+)").unwrap();
-        let re_keep_this_of_any = Regex::new("^c ([0-9]+)").unwrap();
-        let re_keep_any_one = Regex::new("^d").unwrap();
-        let re_keep_oldest = Regex::new("^e").unwrap();
-        let re_keep_newest = Regex::new("^f").unwrap();
- 
-        if re_keep_as_is.is_match(s) {
-            return Ok(KeepStrategy::KEEP_AS_IS);
-        } else if re_keep_this_of_these.is_match(s) {
-            let idx = re_keep_this_of_these.captures(s).unwrap().get(1).unwrap().as_str().parse::<usize>().unwrap();
-            if idx > max_idx {
-                return Err("Invalid index for Keep This of These strategy");
-            }
-            return Ok(KeepStrategy::KEEP_THIS_OF_THESE(idx));
-        } else if re_keep_this_of_any.is_match(s) {
-            let idx = re_keep_this_of_any.captures(s).unwrap().get(1).unwrap().as_str().parse::<usize>().unwrap();
-            if idx > max_idx {
-                return Err("Invalid index for Keep This Of Any strategy");
-            }
-            return Ok(KeepStrategy::KEEP_THIS_OF_ANY(idx));
-        } else if re_keep_any_one.is_match(s) {
-            return Ok(KeepStrategy::KEEP_ANY_ONE);
-        } else if re_keep_oldest.is_match(s) {
-            return Ok(KeepStrategy::KEEP_OLDEST);
-        } else if re_keep_newest.is_match(s) {
-            return Ok(KeepStrategy::KEEP_NEWEST);
-        }
-
-        return match default {
-            None => Err("No default keep strategy set"),
-            Some(t) => Ok(t),
-        }; 
-    }
-}
-
+/// What to keep of the sets of duplicates residing in the same directories; the
+/// index of a strategy refers to `paths` (1 being the first).
 pub struct DirBasedPruneRule {
-    verdict: KeepStrategy,
-    paths: Vec<PathBuf>,
+    pub verdict: KeepStrategy,
+    pub paths: Vec<PathBuf>,
 }
 
 // a little helper for the DirBasedPruneRule formatter
-fn list_and_highlight(paths : &Vec<PathBuf>, highlighted : Option<usize>)
+fn list_and_highlight(paths : &[PathBuf], highlighted : Option<usize>)
         -> String
 {
     let mut s = String::new();
@@ -96,8 +51,8 @@ fn list_and_highlight(paths : &Vec<PathBuf>, highlighted : Option<usize>)
         } else {
             s.push_str("+-- ");
         }
-        s.push_str(paths[i-1].to_str().unwrap());
-        s.push_str("\n");
+        s.push_str(&escape::display(&paths[i-1]));
+        s.push('\n');
     }
     return s;
 }
@@ -116,88 +71,300 @@ impl fmt::Display for DirBasedPruneRule {
     }
 }
 
+/* directories the files of a set reside in, sorted and without repetitions */
+fn dup_dirs(dup: &DupFile) -> Vec<PathBuf> {
+    let root = PathBuf::from(format!("{}", std::path::MAIN_SEPARATOR));
+    let mut dirs : Vec<PathBuf> = std::iter::once(&dup.one_path).chain(dup.other_paths.iter())
+        .map(|_p| _p.parent().unwrap_or(&root).to_path_buf())
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    dirs
+}
 
-// TODO FileBasedPruneRule
+/// A set of duplicates standing for all sets residing in the same directories,
+/// which one rule is to be decided for.
+pub struct RuleCase<'d> {
+    pub key: String,       // of the rule in a PrunePlan
+    pub dirs: Vec<PathBuf>,
+    pub dup: &'d DupFile
+}
 
-fn pick_dups_for_rules<'a>(dups: &'a Vec<DupFile>) -> Vec<&'a DupFile>
-{
+/// One case per distinct combination of directories, in the order of the sets; sets
+/// of hardlinks only, or residing in a single directory, are left out.
+pub fn rule_cases(dups: &[DupFile]) -> Vec<RuleCase<'_>> {
     let mut patterns = HashSet::new();
-    let mut uniq_vec : Vec<&DupFile> = Vec::new();
+    let mut cases : Vec<RuleCase> = Vec::new();
     for d in dups {
-        let one_path_dir = PathBuf::from(&d.one_path).parent()
-            .unwrap_or(&PathBuf::from(&format!("{}",std::path::MAIN_SEPARATOR))).to_path_buf();
-        let mut vec = vec![one_path_dir];
-        for p in &d.other_paths {
-            let other_path_dir = PathBuf::from(&p).parent()
-            .unwrap_or(&PathBuf::from(&format!("{}",std::path::MAIN_SEPARATOR))).to_path_buf();
-            vec.push(other_path_dir);
+        if d.num_copies() < 2 { // hardlinks only, nothing to prune
+            continue;
         }
-        vec.sort();
-        vec.dedup();
-
-        if vec.len() == 1 { // this will get sorted later by a file-based rule
+        let dirs = dup_dirs(d);
+        if dirs.len() == 1 { // this will get sorted later by a file-based rule
             continue;
         }
-
-        let mut new_vec : Vec<&str> = Vec::new();
-        for _n in &vec {
-            new_vec.push(_n.to_str().unwrap());
+        let key = dirs.iter().map(|_d| escape::encode(_d)).collect::<Vec<_>>().join(",");
+        if patterns.insert(key.clone()) {
+            cases.push(RuleCase { key, dirs, dup: d });
         }
-        let key = new_vec.join(",");
+    }
+    cases
+}
 
-        if patterns.contains(&key) {
-            continue;
-        }
+// TODO FileBasedPruneRule
 
-        patterns.insert(key);
-        uniq_vec.push(&d);
-    }
-    uniq_vec
+/// Rules deciding what to keep of duplicate sets, by the directories the sets reside in.
+#[derive(Default)]
+pub struct PrunePlan {
+    rules: IndexMap<String, DirBasedPruneRule>
 }
 
-pub fn collect_dup_path_rules(dups: &Vec<DupFile>) -> IndexMap<String, DirBasedPruneRule>
-{
-    let mut patterns : IndexMap<String, DirBasedPruneRule> = IndexMap::new();
-    let cases = pick_dups_for_rules(dups);
-    let mut idx : usize = 1;
-    for c in &cases {
-        let one_path_dir = PathBuf::from(&c.one_path).parent()
-            .unwrap_or(&PathBuf::from(&format!("{}",std::path::MAIN_SEPARATOR))).to_path_buf();
-        let mut vec = vec![one_path_dir];
-        for p in &c.other_paths {
-            let other_path_dir = PathBuf::from(&p).parent()
-            .unwrap_or(&PathBuf::from(&format!("{}",std::path::MAIN_SEPARATOR))).to_path_buf();
-            vec.push(other_path_dir);
+/// Files of a duplicate set to keep and to remove, and how sure it is that they are duplicates.
+pub struct PruneDecision {
+    pub keep: Vec<PathBuf>,
+    pub remove: Vec<PathBuf>,
+    pub confidence: Confidence
+}
+
+impl PrunePlan {
+    pub fn new() -> Self {
+        PrunePlan { rules: IndexMap::new() }
+    }
+
+    /// Sets the rule of the sets of a [`RuleCase`], by its key.
+    pub fn insert(&mut self, key: String, rule: DirBasedPruneRule) {
+        self.rules.insert(key, rule);
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &DirBasedPruneRule> {
+        self.rules.values()
+    }
+
+    /* the rule for the directories of a set, or else a "keep this of any" rule
+       whose marked directory is among them */
+    fn rule_for(&self, dirs: &[PathBuf]) -> Option<&DirBasedPruneRule> {
+        let key = dirs.iter().map(|_d| escape::encode(_d)).collect::<Vec<_>>().join(",");
+        self.rules.get(&key).or_else(|| self.rules.values().find(|_r| match _r.verdict {
+            KeepStrategy::KEEP_THIS_OF_ANY(i) => marked_dir(_r, i).is_some_and(|_d| dirs.contains(_d)),
+            _ => false
+        }))
+    }
+
+    /// What to keep and to remove of each set that a rule applies to, leaving out the
+    /// sets the rule keeps as they are (or cannot decide for, e.g. as the age of files
+    /// neither was recorded nor can be found out).
+    pub fn decide(&self, dups: &[DupFile]) -> Vec<PruneDecision> {
+        let mut decisions : Vec<PruneDecision> = Vec::new();
+        for dup in dups {
+            if dup.num_copies() < 2 {
+                continue;
+            }
+            let rule = match self.rule_for(&dup_dirs(dup)) {
+                Some(_rule) => _rule,
+                None => continue
+            };
+            let paths : Vec<&PathBuf> = std::iter::once(&dup.one_path).chain(dup.other_paths.iter()).collect();
+            let kept : Vec<bool> = match rule.verdict {
+                KeepStrategy::KEEP_AS_IS => continue,
+                KeepStrategy::KEEP_THIS_OF_THESE(i) | KeepStrategy::KEEP_THIS_OF_ANY(i) => {
+                    let dir = match marked_dir(rule, i) {
+                        Some(_dir) => _dir,
+                        None => continue
+                    };
+                    paths.iter().map(|_p| _p.parent() == Some(dir.as_path())).collect()
+                },
+                KeepStrategy::KEEP_ANY_ONE => (0..paths.len()).map(|_i| _i == 0).collect(),
+                KeepStrategy::KEEP_OLDEST | KeepStrategy::KEEP_NEWEST => {
+                    /* by the mtimes recorded, or else those of the files as they are now */
+                    let mtimes : Option<Vec<i64>> = (0..paths.len())
+                        .map(|_i| dup.mtimes[_i].or_else(|| std::fs::metadata(paths[_i]).ok().map(|_m| _m.mtime())))
+                        .collect();
+                    let mtimes = match mtimes {
+                        Some(_mtimes) => _mtimes,
+                        None => continue
+                    };
+                    let picked = match rule.verdict {
+                        KeepStrategy::KEEP_OLDEST => (0..paths.len()).min_by_key(|&_i| mtimes[_i]),
+                        _ => (0..paths.len()).max_by_key(|&_i| mtimes[_i])
+                    };
+                    (0..paths.len()).map(|_i| Some(_i) == picked).collect()
+                }
+            };
+            if !kept.contains(&false) || !kept.contains(&true) {
+                continue;
+            }
+            let mut decision = PruneDecision { keep: Vec::new(), remove: Vec::new(), confidence: dup.confidence };
+            for (p, k) in paths.into_iter().zip(kept) {
+                match k {
+                    true => decision.keep.push(p.clone()),
+                    false => decision.remove.push(p.clone())
+                }
+            }
+            decisions.push(decision);
         }
-        vec.sort();
-        vec.dedup();
+        decisions
+    }
+}
 
-        let mut new_vec : Vec<&str> = Vec::new();
-        for _n in &vec {
-            new_vec.push(_n.to_str().unwrap());
+/* directory marked by the (1-based) index of a rule */
+fn marked_dir(rule: &DirBasedPruneRule, idx: usize) -> Option<&PathBuf> {
+    idx.checked_sub(1).and_then(|_i| rule.paths.get(_i))
+}
+
+impl PruneDecision {
+    /// Removes the files to remove, provided they were byte-verified to be duplicates
+    /// (see [`crate::dup_confirm::confirm_sets`]) and the files to keep are still there;
+    /// returns the number of files removed.
+    pub fn apply(&self) -> Result<usize> {
+        if self.confidence != Confidence::ByteVerified {
+            return Err(Error::Usage(format!("duplicates are only known by {}, not byte-verified, {} files left as they are",
+                                            self.confidence, self.remove.len())));
+        }
+        if let Some(_missing) = self.keep.iter().find(|_p| !_p.is_file()) {
+            return Err(Error::NotFound(format!("{} was not found, its duplicates are left as they are", 
+                                               escape::display(_missing))));
+        }
+        for p in &self.remove {
+            std::fs::remove_file(p).context(&format!("error removing {}", escape::display(p)))?;
         }
-        let key = new_vec.join(",");
+        Ok(self.remove.len())
+    }
+}
 
-        println!("How should we handle this? [{} of {} decisions]\n{}", 
-                 idx, cases.len(), c.format_minimal(true));
-        println!("a. {}", KeepStrategy::KEEP_AS_IS);
-        println!("b. {}", KeepStrategy::KEEP_THIS_OF_THESE(1));
-        println!("c. {}", KeepStrategy::KEEP_THIS_OF_ANY(1));
-        println!("d. {}", KeepStrategy::KEEP_ANY_ONE);
-        println!("e. {}", KeepStrategy::KEEP_OLDEST);
-        println!("f. {}", KeepStrategy::KEEP_NEWEST);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::ScratchDir;
+
+    fn dup_set(paths: &[PathBuf], confidence: Confidence) -> DupFile {
+        DupFile { one_path: paths[0].clone(), other_paths: paths[1..].to_vec(), hash: "digest".to_string(),
+                  num_dups: paths.len() as u64, size: 5, inodes: (0..paths.len()).map(|_i| Some((1, 1, _i as u64))).collect(),
+                  members: vec![false; paths.len()], mtimes: vec![None; paths.len()], confidence }
+    }
 
-        let mut choice = String::new();
-        io::stdin().read_line(&mut choice).unwrap();
+    fn keep_first_of(dirs: &[PathBuf]) -> (String, DirBasedPruneRule) {
+        let key = dirs.iter().map(|_d| escape::encode(_d)).collect::<Vec<_>>().join(",");
+        (key, DirBasedPruneRule { verdict: KeepStrategy::KEEP_THIS_OF_THESE(1), paths: dirs.to_vec() })
+    }
 
-        let choice_type = KeepStrategy::from_str(&choice, vec.len(), 
-            Some(KeepStrategy::KEEP_AS_IS)).unwrap();
+    #[test]
+    fn decides_by_the_rule_of_the_directories() {
+        let scratch = ScratchDir::new("prune-decide");
+        let (a, b) = (scratch.join("a"), scratch.join("b"));
+        let dups = vec![dup_set(&[b.join("x"), a.join("x")], Confidence::ByteVerified),
+                        dup_set(&[a.join("y"), a.join("z")], Confidence::ByteVerified),
+                        dup_set(&[a.join("w"), b.join("w"), scratch.join("c/w")], Confidence::ByteVerified)];
+        let cases = rule_cases(&dups);
+        assert_eq!(cases.len(), 2); // the set within a single directory is left to file-based rules
 
-        patterns.insert(key, DirBasedPruneRule 
-                { verdict: choice_type, paths: vec });
-        idx += 1;
+        let mut plan = PrunePlan::new();
+        let (key, rule) = keep_first_of(&[a.clone(), b.clone()]);
+        assert_eq!(key, cases[0].key);
+        plan.insert(key, rule);
+        let decisions = plan.decide(&dups);
+        assert_eq!(decisions.len(), 1);
+        assert_eq!(decisions[0].keep, vec![a.join("x")]);
+        assert_eq!(decisions[0].remove, vec![b.join("x")]);
+        assert_eq!(decisions[0].confidence, Confidence::ByteVerified);
+    }
+
+    #[test]
+    fn leaves_hardlinks_alone() {
+        let scratch = ScratchDir::new("prune-hardlinks");
+        let (a, b) = (scratch.join("a"), scratch.join("b"));
+        let mut dup = dup_set(&[a.join("x"), b.join("x")], Confidence::ByteVerified);
+        dup.inodes = vec![Some((1, 1, 7)), Some((1, 1, 7))];
+        assert!(rule_cases(std::slice::from_ref(&dup)).is_empty());
+
+        let mut plan = PrunePlan::new();
+        let (key, rule) = keep_first_of(&[a, b]);
+        plan.insert(key, rule);
+        assert!(plan.decide(&[dup]).is_empty());
+    }
+
+    #[test]
+    fn tells_copies_on_other_devices_or_media_from_hardlinks() {
+        let scratch = ScratchDir::new("prune-devices");
+        let (a, b) = (scratch.join("a"), scratch.join("b"));
+        let mut dup = dup_set(&[a.join("x"), b.join("x")], Confidence::ByteVerified);
+        dup.inodes = vec![Some((1, 1, 7)), Some((1, 2, 7))];
+        assert!(!dup.is_hardlink(1));
+        assert_eq!(dup.num_copies(), 2);
+
+        /* device numbers get reused by media mounted in turn */
+        dup.inodes = vec![Some((1, 1, 7)), Some((2, 1, 7))];
+        assert!(!dup.is_hardlink(1));
+        assert_eq!(dup.freeable_bytes(), dup.size);
+
+        let mut plan = PrunePlan::new();
+        let (key, rule) = keep_first_of(&[a.clone(), b.clone()]);
+        plan.insert(key, rule);
+        let decisions = plan.decide(&[dup]);
+        assert_eq!(decisions.len(), 1);
+        assert_eq!(decisions[0].remove, vec![b.join("x")]);
     }
-    return patterns;
-}
 
+    #[test]
+    fn keeps_the_oldest_by_the_mtimes_recorded() {
+        let scratch = ScratchDir::new("prune-oldest");
+        let (a, b) = (scratch.join("a"), scratch.join("b"));
+        let mut dup = dup_set(&[a.join("x"), b.join("x")], Confidence::ByteVerified);
+        let mut plan = PrunePlan::new();
+        let (key, mut rule) = keep_first_of(&[a.clone(), b.clone()]);
+        rule.verdict = KeepStrategy::KEEP_OLDEST;
+        plan.insert(key, rule);
 
+        /* neither recorded nor there to look at */
+        assert!(plan.decide(std::slice::from_ref(&dup)).is_empty());
+        dup.mtimes = vec![Some(200), Some(100)];
+        let decisions = plan.decide(std::slice::from_ref(&dup));
+        assert_eq!((decisions[0].keep.clone(), decisions[0].remove.clone()), (vec![b.join("x")], vec![a.join("x")]));
+
+        /* the mtime of a file as it is now stands in for one not recorded */
+        scratch.write("a/x", b"same\n");
+        dup.mtimes = vec![None, Some(i64::MAX)];
+        let decisions = plan.decide(&[dup]);
+        assert_eq!((decisions[0].keep.clone(), decisions[0].remove.clone()), (vec![a.join("x")], vec![b.join("x")]));
+    }
+
+    #[test]
+    fn frees_nothing_by_archive_members() {
+        let scratch = ScratchDir::new("prune-members");
+        let mut dup = dup_set(&[scratch.join("a/x"), scratch.join("b.zip!/x"), scratch.join("c/x")],
+                              Confidence::Slowhash);
+        assert_eq!(dup.freeable_bytes(), 2 * dup.size);
+        dup.members = vec![false, true, false];
+        assert_eq!(dup.freeable_bytes(), dup.size);
+        dup.members = vec![true, true, false];
+        assert_eq!(dup.freeable_bytes(), 0);
+    }
+
+    #[test]
+    fn applies_only_byte_verified_decisions() {
+        let scratch = ScratchDir::new("prune-apply");
+        let kept = scratch.write("a/x", b"same\n");
+        let removed = scratch.write("b/x", b"same\n");
+
+        for confidence in [Confidence::SizeOnly, Confidence::Fasthash, Confidence::Slowhash] {
+            let decision = PruneDecision { keep: vec![kept.clone()], remove: vec![removed.clone()], confidence };
+            assert!(matches!(decision.apply(), Err(Error::Usage(_))));
+            assert!(removed.is_file());
+        }
+
+        let decision = PruneDecision { keep: vec![kept.clone()], remove: vec![removed.clone()],
+                                       confidence: Confidence::ByteVerified };
+        assert_eq!(decision.apply().unwrap(), 1);
+        assert!(kept.is_file());
+        assert!(!removed.exists());
+    }
+
+    #[test]
+    fn keeps_duplicates_of_a_vanished_file() {
+        let scratch = ScratchDir::new("prune-vanished");
+        let removed = scratch.write("b/x", b"same\n");
+        let decision = PruneDecision { keep: vec![scratch.join("a/x")], remove: vec![removed.clone()],
+                                       confidence: Confidence::ByteVerified };
+        assert!(matches!(decision.apply(), Err(Error::NotFound(_))));
+        assert!(removed.is_file());
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+use std::io;
+
+/* exit codes of operations that ran to their end */
+pub const EXIT_OK : i32 = 0;
+pub const EXIT_FILES_SKIPPED : i32 = 1;   // some files could not be read and were left out
+pub const EXIT_PROBLEMS_FOUND : i32 = 2;  // e.g. verify found modified, corrupted or missing files
+pub const EXIT_INTERRUPTED : i32 = 3;     // stopped by a signal, what was done is kept
+
+/* exit codes of operations that failed (as in sysexits.h) */
+pub const EXIT_USAGE : i32 = 64;
+pub const EXIT_NOT_FOUND : i32 = 66;
+pub const EXIT_DB : i32 = 70;
+pub const EXIT_IO : i32 = 74;
+pub const EXIT_BUSY : i32 = 75;           // stash file locked by another process, try again later
+pub const EXIT_INCOMPATIBLE : i32 = 78;
+
+/* errors that stop an operation; errors about a single file are reported
+   and the file is skipped instead */
+#[derive(Debug)]
+pub enum Error {
+    Usage(String),                 // invalid arguments
+    Incompatible(String),          // stash file of another schema or settings (or not a stash file)
+    NotFound(String),              // stash file, medium or run that does not exist
+    Io(String, io::Error),         // what was being done when it failed
+    Db(String, rusqlite::Error)
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => EXIT_USAGE,
+            Error::Incompatible(_) => EXIT_INCOMPATIBLE,
+            Error::NotFound(_) => EXIT_NOT_FOUND,
+            Error::Io(_, _) => EXIT_IO,
+            Error::Db(_, _e) => match _e.sqlite_error_code() {
+                Some(rusqlite::ErrorCode::DatabaseBusy) |
+                Some(rusqlite::ErrorCode::DatabaseLocked) => EXIT_BUSY,
+                _ => EXIT_DB
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Usage(_s) | Error::Incompatible(_s) | Error::NotFound(_s) => write!(f, "{}", _s),
+            Error::Io(_s, _e) => write!(f, "{} ({})", _s, _e),
+            Error::Db(_s, _e) => write!(f, "{} ({})", _s, _e)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(_, _e) => Some(_e),
+            Error::Db(_, _e) => Some(_e),
+            _ => None
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Db("error accessing stash file".to_string(), e)
+    }
+}
+
+/* attaches what was being done to an error of the filesystem or the stash file */
+pub trait Context<T> {
+    fn context(self, what: &str) -> Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, what: &str) -> Result<T> {
+        self.map_err(|_e| Error::Io(what.to_string(), _e))
+    }
+}
+
+impl<T> Context<T> for rusqlite::Result<T> {
+    fn context(self, what: &str) -> Result<T> {
+        self.map_err(|_e| Error::Db(what.to_string(), _e))
+    }
+}
+
+/* how an operation that ran to its end went */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    Done,
+    FilesSkipped,
+    ProblemsFound,
+    Interrupted
+}
+
+impl Status {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Status::Done => EXIT_OK,
+            Status::FilesSkipped => EXIT_FILES_SKIPPED,
+            Status::ProblemsFound => EXIT_PROBLEMS_FOUND,
+            Status::Interrupted => EXIT_INTERRUPTED
+        }
+    }
+}
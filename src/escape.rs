@@ -0,0 +1,116 @@
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/* paths are recorded as text, which any path can be encoded to and decoded back from:
+   valid UTF-8 is kept as is, except for backslashes that are doubled, and each byte
+   that is not part of valid UTF-8 is written as \xHH; displayed paths have control
+   characters escaped likewise, so that they do not garble terminals */
+
+fn escape(bytes: &[u8], controls: bool) -> Cow<'_, str> {
+    if let Ok(_s) = std::str::from_utf8(bytes) {
+        if !(_s.contains('\\') || controls && _s.chars().any(char::is_control)) {
+            return Cow::Borrowed(_s);
+        }
+    }
+    let mut out = String::with_capacity(bytes.len() + 8);
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                _ if controls && c.is_control() => {
+                    for b in c.encode_utf8(&mut [0u8; 4]).bytes() {
+                        let _ = write!(out, "\\x{:02x}", b);
+                    }
+                },
+                _ => out.push(c)
+            }
+        }
+        for b in chunk.invalid() {
+            let _ = write!(out, "\\x{:02x}", b);
+        }
+    }
+    Cow::Owned(out)
+}
+
+/* text a path is recorded as in the stash file */
+pub fn encode(path: &Path) -> Cow<'_, str> {
+    escape(path.as_os_str().as_bytes(), false)
+}
+
+/* text a path is shown as (which decode() also takes) */
+pub fn display(path: &Path) -> Cow<'_, str> {
+    escape(path.as_os_str().as_bytes(), true)
+}
+
+/* path of the text it was encoded (or displayed) as; a backslash not starting
+   an escape is taken as is */
+pub fn decode(s: &str) -> PathBuf {
+    if !s.contains('\\') {
+        return PathBuf::from(s);
+    }
+    let text = s.as_bytes();
+    let mut bytes : Vec<u8> = Vec::with_capacity(text.len());
+    let mut i : usize = 0;
+    while i < text.len() {
+        if text[i] == b'\\' {
+            match (text.get(i + 1), text.get(i + 2..i + 4)) {
+                (Some(&b'\\'), _) => {
+                    bytes.push(b'\\');
+                    i += 2;
+                    continue;
+                },
+                (Some(&b'x'), Some(_hex)) if _hex.iter().all(u8::is_ascii_hexdigit) => {
+                    bytes.push(u8::from_str_radix(std::str::from_utf8(_hex).unwrap(), 16).unwrap());
+                    i += 4;
+                    continue;
+                },
+                _ => ()
+            }
+        }
+        bytes.push(text[i]);
+        i += 1;
+    }
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path_of(bytes: &[u8]) -> PathBuf {
+        PathBuf::from(OsString::from_vec(bytes.to_vec()))
+    }
+
+    #[test]
+    fn encodes_backslashes_and_invalid_bytes() {
+        assert!(matches!(encode(Path::new("/home/user/ünïcode")), Cow::Borrowed("/home/user/ünïcode")));
+        assert_eq!(encode(Path::new("/a\\b")), "/a\\\\b");
+        assert_eq!(encode(&path_of(b"/latin1-\xe9t\xe9")), "/latin1-\\xe9t\\xe9");
+        /* text that merely looks like an escape stays apart from one */
+        assert_eq!(encode(Path::new("/\\x41")), "/\\\\x41");
+        assert_eq!(encode(Path::new("/line\nbreak")), "/line\nbreak");
+        assert_eq!(display(Path::new("/line\nbreak\t")), "/line\\x0abreak\\x09");
+    }
+
+    #[test]
+    fn decodes_what_was_encoded_or_displayed() {
+        let paths = [path_of(b"/plain/path"), path_of(b"/a\\b\\\\c\\"), path_of(b"/\\x41\\xzz"),
+                     path_of(b"/latin1-\xe9t\xe9/\xff\xfe"), path_of(b"/line\nbreak\t\x1b[31m"),
+                     path_of("/ünïcode\\".as_bytes())];
+        for path in &paths {
+            assert_eq!(&decode(&encode(path)), path);
+            assert_eq!(&decode(&display(path)), path);
+        }
+    }
+
+    #[test]
+    fn takes_a_lone_backslash_as_is() {
+        assert_eq!(decode("/a\\b"), Path::new("/a\\b"));
+        assert_eq!(decode("/a\\"), Path::new("/a\\"));
+        assert_eq!(decode("/\\x4"), Path::new("/\\x4"));
+        assert_eq!(decode("/\\x4g"), Path::new("/\\x4g"));
+    }
+}
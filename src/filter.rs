@@ -0,0 +1,111 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use regex::Regex;
+use walkdir::DirEntry;
+use std::path::{Path, PathBuf};
+use crate::error::{Error, Result};
+
+pub const IGNORE_FILE : &str = ".wfilesignore";
+
+/* decides which of the entries met during traversal are to be visited;
+   excluded directories are not descended into, includes only apply to files */
+pub struct PathFilter {
+    include_globs: GlobSet,
+    exclude_globs: GlobSet,
+    include_regexes: Vec<Regex>,
+    exclude_regexes: Vec<Regex>,
+    use_ignore_files: bool,
+}
+
+/* .wfilesignore matchers of the directories above the current entry, along
+   with the depth of the directory each was found in */
+pub type IgnoreStack = Vec<(usize, Gitignore)>;
+
+fn build_globset(globs: &[&str]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for g in globs {
+        builder.add(Glob::new(g)
+            .map_err(|_e| Error::Usage(format!("invalid glob pattern {} ({})", g, _e)))?);
+    }
+    builder.build().map_err(|_e| Error::Usage(format!("error compiling glob patterns ({})", _e)))
+}
+
+fn build_regexes(regexes: &[&str]) -> Result<Vec<Regex>> {
+    regexes.iter().map(|r| Regex::new(r)
+        .map_err(|_e| Error::Usage(format!("invalid regular expression {} ({})", r, _e))))
+        .collect()
+}
+
+impl PathFilter {
+    pub fn new(include_globs: &[&str], exclude_globs: &[&str],
+               include_regexes: &[&str], exclude_regexes: &[&str],
+               use_ignore_files: bool) -> Result<Self> {
+        Ok(PathFilter { include_globs: build_globset(include_globs)?,
+                        exclude_globs: build_globset(exclude_globs)?,
+                        include_regexes: build_regexes(include_regexes)?,
+                        exclude_regexes: build_regexes(exclude_regexes)?,
+                        use_ignore_files })
+    }
+
+    /* admits every entry */
+    pub fn all() -> Self {
+        PathFilter { include_globs: GlobSet::empty(), exclude_globs: GlobSet::empty(),
+                     include_regexes: Vec::new(), exclude_regexes: Vec::new(),
+                     use_ignore_files: false }
+    }
+
+    /* globs are matched against the file name and the path relative to the
+       top directory, regular expressions against the full path */
+    fn matches(globs: &GlobSet, regexes: &[Regex], rel_path: &Path, entry: &DirEntry) -> bool {
+        globs.is_match(entry.file_name()) || globs.is_match(rel_path) ||
+            regexes.iter().any(|r| r.is_match(&entry.path().to_string_lossy()))
+    }
+
+    /* must be called for every entry of a traversal, in traversal order; ignore files 
+       that cannot be parsed (in part or at all) are added to errors, along with why */
+    pub fn admits(&self, topdir: &Path, entry: &DirEntry, ignores: &mut IgnoreStack,
+                  errors: &mut Vec<(PathBuf, String)>) -> bool {
+        let depth = entry.depth();
+        let is_dir = entry.file_type().is_dir();
+        ignores.retain(|(d, _)| *d < depth);
+
+        if depth > 0 {
+            let rel_path = entry.path().strip_prefix(topdir).unwrap_or(entry.path());
+
+            if Self::matches(&self.exclude_globs, &self.exclude_regexes, rel_path, entry) {
+                return false;
+            }
+
+            // the deepest .wfilesignore with a matching pattern decides
+            for (_, gi) in ignores.iter().rev() {
+                match gi.matched(entry.path(), is_dir) {
+                    Match::Ignore(_) => return false,
+                    Match::Whitelist(_) => break,
+                    Match::None => continue,
+                }
+            }
+
+            let has_includes = !self.include_globs.is_empty() || !self.include_regexes.is_empty();
+            if !is_dir && has_includes &&
+               !Self::matches(&self.include_globs, &self.include_regexes, rel_path, entry) {
+                return false;
+            }
+        }
+
+        if is_dir && self.use_ignore_files {
+            let ignore_file = entry.path().join(IGNORE_FILE);
+            if ignore_file.is_file() {
+                let mut builder = GitignoreBuilder::new(entry.path());
+                if let Some(_e) = builder.add(&ignore_file) {
+                    errors.push((ignore_file.clone(), _e.to_string()));
+                }
+                match builder.build() {
+                    Ok(gi) => ignores.push((depth, gi)),
+                    Err(_e) => errors.push((ignore_file, _e.to_string()))
+                }
+            }
+        }
+        true
+    }
+}
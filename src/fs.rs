@@ -4,52 +4,95 @@ use vmap::Map;
 use libc::{posix_fadvise, c_int, off_t};
 use std::os::unix::io::AsRawFd;
 use walkdir::{WalkDir,DirEntry};
-use std::path::Path;
-use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::cell::RefCell;
+use std::io::{Error, SeekFrom};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::hasher::ByteHasher;
+use crate::filter::{PathFilter, IgnoreStack};
 
 const POSIX_FADV_SEQUENTIAL : c_int = 2;
+const PARTIAL_HASH_CHUNK : u64 = 16 * 1024; // bytes hashed at each end of a file
 
-pub struct FileVisitor<F>
-    where F: FnMut(&DirEntry)
+pub struct WalkOptions {
+    pub follow_links: bool,      // with loop detection
+    pub same_file_system: bool,
+    pub min_depth: usize,        // files directly under a top directory are at depth 1
+    pub max_depth: usize,
+    pub symlinks: bool           // visit symlinks themselves (when not following them)
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        WalkOptions { follow_links: false, same_file_system: false, 
+                      min_depth: 0, max_depth: usize::MAX, symlinks: false }
+    }
+}
+
+/* what the traversal could not do, as handed to the file processor */
+pub enum WalkError<'e> {
+    Unreadable(&'e walkdir::Error),   // an entry that could not be walked (e.g. a directory)
+    IgnoreFile(&'e Path, &'e str)     // an ignore file that could not be parsed, and why
+}
+
+/* entries that could not be walked (e.g. unreadable directories) are handed to the 
+   file processor as errors, by the actual pass only */
+pub struct FileVisitor<'f, F>
+    where F: FnMut(Result<&DirEntry, WalkError>)
 {
     pub dry_run: bool,
-    pub verbose: bool,
+    pub options: &'f WalkOptions,
+    pub filter: &'f PathFilter,
+    pub interrupted: Option<&'f AtomicBool>, // traversal stops once set
     pub file_processor : F
 }
 
-impl<F> FileVisitor<F>
-    where F: FnMut(&DirEntry)
+impl<'f, F> FileVisitor<'f, F>
+    where F: FnMut(Result<&DirEntry, WalkError>)
 {
     pub fn traverse<T: AsRef<Path>>(&mut self, 
-                                    topdirs: &Vec<T>, 
-                                    n_items_hint: usize) -> usize
+                                    topdirs: &Vec<T>) -> usize
     {
         let fp = &mut self.file_processor;
         let mut n_entries : usize = 0;
+        let filter = self.filter;
         for dir in topdirs {
-            for entry in WalkDir::new(dir) {
+            let mut ignores : IgnoreStack = Vec::new();
+            let ignore_errors : RefCell<Vec<(PathBuf, String)>> = RefCell::new(Vec::new());
+            let report_ignore_errors = |fp: &mut F| if !self.dry_run {
+                for (path, reason) in ignore_errors.borrow_mut().drain(..) {
+                    fp(Err(WalkError::IgnoreFile(&path, &reason)));
+                }
+            };
+            /* min_depth is not handed to WalkDir, as entries skipped by it would
+               not go through the filter (which needs to see every directory) */
+            let walker = WalkDir::new(dir)
+                .sort_by_file_name()
+                .follow_links(self.options.follow_links)
+                .same_file_system(self.options.same_file_system)
+                .max_depth(self.options.max_depth)
+                .into_iter()
+                .filter_entry(|_e| filter.admits(dir.as_ref(), _e, &mut ignores, 
+                                                 &mut ignore_errors.borrow_mut()));
+            for entry in walker {
+                if self.interrupted.is_some_and(|_i| _i.load(Ordering::Relaxed)) {
+                    return n_entries;
+                }
+                report_ignore_errors(fp);
                 match entry {
-                   Ok(_entry) => if _entry.file_type().is_file() { 
+                   Ok(_entry) => if _entry.depth() >= self.options.min_depth && 
+                                    (_entry.file_type().is_file() || 
+                                     (self.options.symlinks && _entry.file_type().is_symlink())) { 
                         n_entries += 1;
-                        if !self.dry_run { 
-                            if self.verbose {
-                                eprint!("> processing {} ({}/{})",
-                                    _entry.path().display(),
-                                    n_entries,
-                                    n_items_hint);
-                            }
-                            fp(&_entry);
-                            if self.verbose {
-                                eprint!("\x1b[2K\r");
-                            }
-                        } 
+                        fp(Ok(&_entry));
                    },
-                   Err(_err) => eprint!("Failed to walk dir entry ({})\n",
-                                        _err)
+                   Err(_err) => if !self.dry_run { // reported once, by the actual pass
+                        fp(Err(WalkError::Unreadable(&_err)));
+                   }
                 }
             }
+            report_ignore_errors(fp);
         }
         return n_entries;
     }    
@@ -68,65 +111,175 @@ impl <B> FileHasher<B>
 	pub fn new(_hasher: B, bufsz : u64, force_read: bool) -> Self {
 	    return Self {   hasher: Box::new(_hasher),
                         buf : vec![0; bufsz as usize],
-                        force_read : force_read}
+                        force_read}
 	}
 	
-	pub fn hash_filehandle(&mut self, f: &mut File, file_size: u64) -> String {
+	pub fn hash_filehandle(&mut self, f: &mut File, file_size: u64) -> Result<String, Error> {
+        let fed = self.feed_filehandle(f, file_size, &mut []);
+        let finished = self.hasher.finish(); // resets the hasher even if reading failed
+        fed.and(finished).map(|_| self.hasher.digest())
+	}
+
+    /* hashes a file with this and any other hashers in a single pass, 
+       returning the digest of this hasher followed by those of the others */
+    pub fn hash_filehandle_multi(&mut self, f: &mut File, file_size: u64, 
+                                 others: &mut [&mut dyn ByteHasher]) -> Result<Vec<String>, Error> {
+        let fed = self.feed_filehandle(f, file_size, others);
+        let mut finished = self.hasher.finish();
+        for other in others.iter_mut() {
+            finished = finished.and(other.finish());
+        }
+        fed.and(finished)?;
+        let mut digests = vec![self.hasher.digest()];
+        for other in others.iter() {
+            digests.push(other.digest());
+        }
+        Ok(digests)
+    }
+
+    fn feed_filehandle(&mut self, f: &mut File, file_size: u64, 
+                       others: &mut [&mut dyn ByteHasher]) -> Result<(), Error> {
         let mut gotta_try_read = true;
 
+        /* only a hint, files are read all the same if it is not taken */
         if cfg!(unix) {
             unsafe {
-                let adv : c_int;
-                adv = posix_fadvise(f.as_raw_fd(), 
+                posix_fadvise(f.as_raw_fd(), 
                               0 as off_t, 
                               0 as off_t,
                               POSIX_FADV_SEQUENTIAL);
-                if adv !=0 {
-                    eprintln!("error: POSIX_FADV_SEQUENTIAL was not applied");
-                }
             }
         }
 
         if !self.force_read { // let's try mmap
-            let try_mmap = Map::with_options().map(f);
-            if try_mmap.is_ok() {
+            if let Ok(map) = Map::with_options().map(f) {
                 gotta_try_read = false;
-                self.hasher.update(&try_mmap.unwrap().as_ref());
+                self.hasher.update(map.as_ref())?;
+                for other in others.iter_mut() {
+                    other.update(map.as_ref())?;
+                }
             }
         }
 
-        if gotta_try_read == true {
+        if gotta_try_read {
             let buf = &mut self.buf;
     	    let mut n : usize;
 
 	        loop {
-                n = f.read(buf).expect("error reading file!");
+                n = match f.read(buf) {
+                    Ok(_n) => _n,
+                    Err(_e) if _e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(_e) => return Err(_e)
+                };
                 if n != 0 {
-                    self.hasher.update(&mut buf[..n]);
+                    self.hasher.update(&buf[..n])?;
+                    for other in others.iter_mut() {
+                        other.update(&buf[..n])?;
+                    }
                     if n == file_size as usize { break };
                 } else {
                     break;
                 }
             }
         }
-
-        self.hasher.finish();
-        self.hasher.digest()
+        Ok(())
 	}
 
-    pub fn hash_dbentry(&mut self, path: &str, fname : &str, file_size: u64) -> Result<String, Error>  
+    pub fn hash_dbentry(&mut self, full_path: &Path, file_size: u64) -> Result<String, Error>  
     {
-        let full_path : String = format!("{}{}{}", 
-                    path,
-                    std::path::MAIN_SEPARATOR, 
-                    fname);
-        
-        let mut f = File::open(&full_path)?;
-        Ok(self.hash_filehandle(&mut f, file_size))
+        let mut f = File::open(full_path)?;
+        self.hash_filehandle(&mut f, file_size)
 	}
 
+    fn feed_partial(&mut self, f: &mut File, file_size: u64) -> Result<(), Error>
+    {
+        let head_len = file_size.min(PARTIAL_HASH_CHUNK);
+        let tail_len = (file_size - head_len).min(PARTIAL_HASH_CHUNK);
+        let mut chunk = vec![0u8; head_len as usize];
+
+        f.seek(SeekFrom::Start(0))?;
+        f.read_exact(&mut chunk)?;
+        self.hasher.update(&chunk)?;
+        if tail_len > 0 {
+            chunk.truncate(tail_len as usize);
+            f.seek(SeekFrom::Start(file_size - tail_len))?;
+            f.read_exact(&mut chunk)?;
+            self.hasher.update(&chunk)?;
+        }
+        self.hasher.update(&file_size.to_be_bytes())?;
+        f.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    /* hashes the first and last PARTIAL_HASH_CHUNK bytes of a file along with its size,
+       leaving the file position at the start of the file */
+    pub fn hash_partial(&mut self, f: &mut File, file_size: u64) -> Result<String, Error>
+    {
+        let fed = self.feed_partial(f, file_size);
+        let finished = self.hasher.finish(); // resets the hasher even if reading failed
+        fed.and(finished).map(|_| self.hasher.digest())
+    }
+
+    pub fn hash_partial_dbentry(&mut self, full_path: &Path, file_size: u64) -> Result<String, Error>
+    {
+        let mut f = File::open(full_path)?;
+        self.hash_partial(&mut f, file_size)
+    }
+
 
 	
 }
 
+/* digests of a stream that can only be read once (e.g. an archive member) */
+pub struct StreamDigests {
+    pub size: u64,
+    pub parthash: String,
+    pub fasthash: String,
+    pub slowhash: String
+}
+
+/* computes all digests in a single pass, the partial one keeping the first and 
+   last PARTIAL_HASH_CHUNK bytes aside, so as to match FileHasher::hash_partial() */
+pub fn hash_stream<R, Q, S>(r: &mut R, quick: &mut FileHasher<Q>, partial: &mut Q, 
+                            slow: &mut FileHasher<S>) -> Result<StreamDigests, Error>
+    where R: Read, Q: ByteHasher, S: ByteHasher
+{
+    let chunk = PARTIAL_HASH_CHUNK as usize;
+    let mut head : Vec<u8> = Vec::with_capacity(chunk);
+    let mut tail : Vec<u8> = Vec::with_capacity(2 * chunk);
+    let mut size : u64 = 0;
+    let buf = &mut quick.buf;
+
+    let mut feed = || -> Result<(), Error> {
+        loop {
+            let n = match r.read(buf) {
+                Ok(0) => break,
+                Ok(_n) => _n,
+                Err(_e) if _e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_e) => return Err(_e)
+            };
+            let data = &buf[..n];
+            quick.hasher.update(data)?;
+            slow.hasher.update(data)?;
+            size += n as u64;
 
+            let to_head = (chunk - head.len()).min(n);
+            head.extend_from_slice(&data[..to_head]);
+            let rest = &data[to_head..];
+            tail.extend_from_slice(&rest[rest.len().saturating_sub(chunk)..]);
+            if tail.len() > chunk {
+                tail.drain(..tail.len() - chunk);
+            }
+        }
+        partial.update(&head)?;
+        partial.update(&tail)?;
+        partial.update(&size.to_be_bytes())
+    };
+    let fed = feed();
+
+    /* the hashers are reset even if reading failed */
+    let finished = partial.finish().and(quick.hasher.finish()).and(slow.hasher.finish());
+    fed.and(finished)?;
+    Ok(StreamDigests { size, parthash: partial.digest(), 
+                       fasthash: quick.hasher.digest(), slowhash: slow.hasher.digest() })
+}
@@ -1,74 +1,251 @@
 use xxhash_rust::xxh3::Xxh3;
+use openssl::error::ErrorStack;
 use openssl::hash::{Hasher, MessageDigest};
-use core::hash::Hasher as RustStdHasher; // to aleviate name mangling
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use crate::error::{Error, Result};
 
+/* a failure of the digest itself is told along with those of reading the file hashed */
 pub trait ByteHasher {
-    fn update(&mut self, data: &[u8]); // mixes input
-    fn finish(&mut self); // finishes mixing, reseting int. state
+    fn update(&mut self, data: &[u8]) -> io::Result<()>; // mixes input
+    fn finish(&mut self) -> io::Result<()>; // finishes mixing, reseting int. state
     fn digest(&self) -> String; // outputs owned hex-encoded hash
 }
 
-const XXH3_DIGEST_SZ : usize = 8;
-const MD5_DIGEST_SZ : usize = 16;
-const SHA512_DIGEST_SZ : usize = 64;
+/* algorithms are recorded by name in the stash file, so names must not change */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QuickAlgorithm {
+    Xxh3,
+    Xxh3_128
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SlowAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3
+}
+
+impl QuickAlgorithm {
+    pub const NAMES : [&'static str; 2] = ["xxh3", "xxh3-128"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            QuickAlgorithm::Xxh3 => "xxh3",
+            QuickAlgorithm::Xxh3_128 => "xxh3-128"
+        }
+    }
+}
+
+impl SlowAlgorithm {
+    pub const NAMES : [&'static str; 5] = ["md5", "sha1", "sha256", "sha512", "blake3"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SlowAlgorithm::Md5 => "md5",
+            SlowAlgorithm::Sha1 => "sha1",
+            SlowAlgorithm::Sha256 => "sha256",
+            SlowAlgorithm::Sha512 => "sha512",
+            SlowAlgorithm::Blake3 => "blake3"
+        }
+    }
+}
+
+impl FromStr for QuickAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "xxh3" => Ok(QuickAlgorithm::Xxh3),
+            "xxh3-128" => Ok(QuickAlgorithm::Xxh3_128),
+            _ => Err(format!("unknown quick hash algorithm {}", s))
+        }
+    }
+}
+
+impl FromStr for SlowAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "md5" => Ok(SlowAlgorithm::Md5),
+            "sha1" => Ok(SlowAlgorithm::Sha1),
+            "sha256" => Ok(SlowAlgorithm::Sha256),
+            "sha512" => Ok(SlowAlgorithm::Sha512),
+            "blake3" => Ok(SlowAlgorithm::Blake3),
+            _ => Err(format!("unknown slow hash algorithm {}", s))
+        }
+    }
+}
+
+impl fmt::Display for QuickAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl fmt::Display for SlowAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/* the algorithms a stash file is hashed with */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct HashAlgorithms {
+    pub quick: QuickAlgorithm,
+    pub slow: SlowAlgorithm
+}
+
+impl Default for HashAlgorithms {
+    fn default() -> Self {
+        HashAlgorithms { quick: QuickAlgorithm::Xxh3, slow: SlowAlgorithm::Md5 }
+    }
+}
 
 pub struct QuickHasher {
+    algorithm : QuickAlgorithm,
     digestor : Box<Xxh3>,
-    digest   : [u8; XXH3_DIGEST_SZ]
+    digest   : Vec<u8>
 }
 
 impl QuickHasher {
-    pub fn new() -> Self {
-        return Self {   digestor: Box::new(Xxh3::new()),
-                     digest: [0; XXH3_DIGEST_SZ]   }
+    pub fn new(algorithm: QuickAlgorithm) -> Self {
+        return Self {   algorithm,
+                        digestor: Box::new(Xxh3::new()),
+                        digest: Vec::new()   }
+    }
+
+    pub fn algorithm(&self) -> QuickAlgorithm {
+        self.algorithm
     }
 }
 
 impl ByteHasher for QuickHasher {
-    fn update(&mut self, data: &[u8]) {
+    fn update(&mut self, data: &[u8]) -> io::Result<()> {
         self.digestor.update(data);
+        Ok(())
     }
 
-    fn finish(&mut self) {
-        let digest : u64 = self.digestor.finish();
-        self.digest.copy_from_slice(&digest.to_be_bytes());
+    fn finish(&mut self) -> io::Result<()> {
+        self.digest = match self.algorithm {
+            QuickAlgorithm::Xxh3 => self.digestor.digest().to_be_bytes().to_vec(),
+            QuickAlgorithm::Xxh3_128 => self.digestor.digest128().to_be_bytes().to_vec()
+        };
         self.digestor.reset();
+        Ok(())
     }
 
     fn digest(&self) -> String {
-        hex::encode(self.digest)
+        hex::encode(&self.digest)
     }
 }
 
+enum SlowDigestor {
+    OpenSsl(Box<Hasher>),
+    Blake3(Box<blake3::Hasher>)
+}
+
 pub struct SlowHasher {
-    digestor: Box<openssl::hash::Hasher>,
-    digest: Box<[u8]>
+    digestor: SlowDigestor,
+    digest: Vec<u8>
 }
 
-impl SlowHasher {
-    pub fn MD5() -> Self {
-        return Self { digestor: Box::new(Hasher::new(MessageDigest::md5()).unwrap()),
-                      digest: Box::new([0; MD5_DIGEST_SZ]) }
-    }
+fn digest_error(e: ErrorStack) -> io::Error {
+    io::Error::other(format!("digest failed: {}", e))
+}
 
-    pub fn SHA512() -> Self {
-        return Self { 
-            digestor: Box::new(Hasher::new(MessageDigest::sha512()).unwrap()),
-            digest: Box::new([0; SHA512_DIGEST_SZ]) }
+impl SlowHasher {
+    /* fails if the algorithm is not available here (e.g. md5 under a FIPS policy of openssl) */
+    pub fn new(algorithm: SlowAlgorithm) -> Result<Self> {
+        let openssl = |md: MessageDigest| Hasher::new(md)
+            .map(|_h| SlowDigestor::OpenSsl(Box::new(_h)))
+            .map_err(|_e| Error::Incompatible(format!("{} hashing is not available ({})", algorithm, _e)));
+        let digestor = match algorithm {
+            SlowAlgorithm::Md5 => openssl(MessageDigest::md5())?,
+            SlowAlgorithm::Sha1 => openssl(MessageDigest::sha1())?,
+            SlowAlgorithm::Sha256 => openssl(MessageDigest::sha256())?,
+            SlowAlgorithm::Sha512 => openssl(MessageDigest::sha512())?,
+            SlowAlgorithm::Blake3 => SlowDigestor::Blake3(Box::new(blake3::Hasher::new()))
+        };
+        return Ok(Self { digestor, digest: Vec::new() })
     }
 }
 
 impl ByteHasher for SlowHasher {
-    fn update(&mut self, data: &[u8]) {
-        self.digestor.update(data).unwrap();
+    fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.digestor {
+            SlowDigestor::OpenSsl(h) => h.update(data).map_err(digest_error),
+            SlowDigestor::Blake3(h) => { h.update(data); Ok(()) }
+        }
     }
 
-    fn finish(&mut self) {
-        let digest = self.digestor.finish().unwrap();
-        self.digest.copy_from_slice(&digest);
+    fn finish(&mut self) -> io::Result<()> {
+        self.digest = match &mut self.digestor {
+            SlowDigestor::OpenSsl(h) => h.finish().map_err(digest_error)?.to_vec(),
+            SlowDigestor::Blake3(h) => {
+                let digest = h.finalize().as_bytes().to_vec();
+                h.reset();
+                digest
+            }
+        };
+        Ok(())
     }
 
     fn digest(&self) -> String {
         hex::encode(&self.digest)
-    }    
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slow_digest(hasher: &mut SlowHasher, data: &[u8]) -> String {
+        hasher.update(data).unwrap();
+        hasher.finish().unwrap();
+        hasher.digest()
+    }
+
+    #[test]
+    fn digests_with_each_slow_algorithm() {
+        let mut md5 = SlowHasher::new(SlowAlgorithm::Md5).unwrap();
+        assert_eq!(slow_digest(&mut md5, b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        /* finishing resets the hasher for the next file */
+        assert_eq!(slow_digest(&mut md5, b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+
+        let mut sha1 = SlowHasher::new(SlowAlgorithm::Sha1).unwrap();
+        assert_eq!(slow_digest(&mut sha1, b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        let mut sha256 = SlowHasher::new(SlowAlgorithm::Sha256).unwrap();
+        assert_eq!(slow_digest(&mut sha256, b"abc"),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        let mut sha512 = SlowHasher::new(SlowAlgorithm::Sha512).unwrap();
+        assert_eq!(slow_digest(&mut sha512, b"abc"),
+                   "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                    2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f");
+        let mut blake3 = SlowHasher::new(SlowAlgorithm::Blake3).unwrap();
+        assert_eq!(slow_digest(&mut blake3, b"abc"),
+                   "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+        assert_eq!(slow_digest(&mut blake3, b"abc"),
+                   "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+    }
+
+    fn quick_digest(hasher: &mut QuickHasher, data: &[u8]) -> String {
+        hasher.update(data).unwrap();
+        hasher.finish().unwrap();
+        hasher.digest()
+    }
+
+    /* digests are written as xxhsum writes them (big-endian) */
+    #[test]
+    fn digests_with_each_quick_algorithm() {
+        let mut xxh3 = QuickHasher::new(QuickAlgorithm::Xxh3);
+        assert_eq!(quick_digest(&mut xxh3, b"abc"), "78af5f94892f3950");
+        assert_eq!(quick_digest(&mut xxh3, b"abc"), "78af5f94892f3950");
+        let mut xxh3_128 = QuickHasher::new(QuickAlgorithm::Xxh3_128);
+        assert_eq!(quick_digest(&mut xxh3_128, b"abc"), "06b05ab6733a618578af5f94892f3950");
+    }
 }
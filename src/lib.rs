@@ -0,0 +1,57 @@
+//! Records the files of one or more media in a stash file (an SQLite database),
+//! along with their digests, so as to find and prune duplicates among them.
+//!
+//! The `wfiles` command is a thin layer over this library:
+//!
+//! * [`DataBase`] opens (or creates) a stash file, of which [`DataBase::for_reading`]
+//!   is enough for queries;
+//! * [`StashOptions::run`] records the files under some paths, reporting each one
+//!   through a callback;
+//! * [`IdentifyDupsStatement`] yields the sets of duplicate files recorded, which
+//!   [`dup_confirm::confirm_sets`] can byte-verify;
+//! * [`PrunePlan`] decides which files of each set to keep by the directories they
+//!   reside in, and removes the rest.
+//!
+//! ```no_run
+//! use wfiles::{DataBase, IdentifyDupsStatement, StashEvent, StashOptions};
+//!
+//! let options = StashOptions { topdirs: vec!["/home".into()], ..StashOptions::default() };
+//! let summary = options.run(|event| if let StashEvent::Failed { path, reason, .. } = event {
+//!     eprintln!("{}: {}", wfiles::escape::display(path), reason);
+//! })?;
+//! println!("{} files recorded", summary.n_recorded);
+//!
+//! let store = DataBase::for_reading(&options.dbfile)?;
+//! let mut dups = IdentifyDupsStatement::new(&store)?;
+//! for set in dups.dups()? {
+//!     let set = set?;
+//!     println!("{} and {} more", wfiles::escape::display(&set.one_path), set.other_paths.len());
+//! }
+//! # Ok::<(), wfiles::Error>(())
+//! ```
+//!
+//! Errors about a single file are reported and the file is skipped, while an [`Error`]
+//! stops the operation at hand.
+
+#![allow(clippy::needless_return, clippy::needless_late_init)]
+
+pub mod error;
+pub mod hasher;
+pub mod fs;
+pub mod filter;
+pub mod mounts;
+pub mod archive;
+pub mod escape;
+pub mod db;
+mod db_migrate;
+pub mod stash;
+pub mod dup_confirm;
+pub mod dup_prune;
+#[doc(hidden)]
+pub mod testutil; // for the tests of the command line as well
+
+pub use error::{Error, Result, Status};
+pub use db::{DataBase, DupFile, Confidence, IdentifyDupsStatement};
+pub use fs::FileHasher;
+pub use stash::{StashOptions, StashEvent, StashSummary};
+pub use dup_prune::{PrunePlan, KeepStrategy};
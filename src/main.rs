@@ -1,11 +1,21 @@
+#![allow(clippy::needless_return, clippy::needless_late_init)]
+
 use clap::{App, AppSettings};
+use wfiles::error;
+
+/* the subcommands, each parsing its arguments and running over the library */
+mod cli {
+    pub mod stash;
+    pub mod dbcmd;
+    pub mod dup;
+    pub mod media;
+    pub mod resolve;
+    pub mod verify;
+    pub mod errors;
+    pub mod runs;
+}
 
-mod stash;
-mod db;
-mod fs;
-mod hasher;
-mod dup;
-mod dup_prune;
+use cli::{stash, dbcmd, dup, media, resolve, verify, errors, runs};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -15,18 +25,64 @@ fn main() {
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .setting(AppSettings::VersionlessSubcommands)
         .subcommand(stash::args_config())
-        .subcommand(dup::args_config());
+        .subcommand(dup::args_config())
+        .subcommand(media::args_config())
+        .subcommand(resolve::args_config())
+        .subcommand(verify::args_config())
+        .subcommand(errors::args_config())
+        .subcommand(runs::args_config())
+        .subcommand(dbcmd::args_config());
 
-    let matches = params.get_matches();
-    match matches.subcommand() {
+    /* help and version requests are not errors */
+    let matches = match params.get_matches_safe() {
+        Ok(_m) => _m,
+        Err(_e) if !_e.use_stderr() => _e.exit(),
+        Err(_e) => {
+            eprintln!("{}", _e.message);
+            std::process::exit(error::EXIT_USAGE);
+        }
+    };
+    let result = match matches.subcommand() {
         ("stash", Some(sub_m)) => {
-            let stash_op = stash::StashOperation::from_args(&sub_m);
-            stash_op.do_operation();
+            stash::StashOperation::from_args(sub_m)
+                .and_then(|stash_op| stash_op.do_operation())
         },
         ("dup", Some(sub_m)) => {
-            let dup_op = dup::DupOperation::from_args(&sub_m);
-            dup_op.do_operation();
+            dup::DupOperation::from_args(sub_m)
+                .and_then(|dup_op| dup_op.do_operation())
+        },
+        ("media", Some(sub_m)) => {
+            media::MediaOperation::from_args(sub_m)
+                .and_then(|media_op| media_op.do_operation())
+        },
+        ("resolve", Some(sub_m)) => {
+            resolve::ResolveOperation::from_args(sub_m)
+                .and_then(|resolve_op| resolve_op.do_operation())
         },
-        _ => { println!("{}", matches.usage()); std::process::exit(1); },
+        ("verify", Some(sub_m)) => {
+            verify::VerifyOperation::from_args(sub_m)
+                .and_then(|verify_op| verify_op.do_operation())
+        },
+        ("errors", Some(sub_m)) => {
+            errors::ErrorsOperation::from_args(sub_m)
+                .and_then(|errors_op| errors_op.do_operation())
+        },
+        ("runs", Some(sub_m)) => {
+            runs::RunsOperation::from_args(sub_m)
+                .and_then(|runs_op| runs_op.do_operation())
+        },
+        ("db", Some(sub_m)) => {
+            dbcmd::DbOperation::from_args(sub_m)
+                .and_then(|db_op| db_op.do_operation())
+        },
+        _ => { println!("{}", matches.usage()); std::process::exit(error::EXIT_USAGE); },
+    };
+
+    match result {
+        Ok(status) => std::process::exit(status.exit_code()),
+        Err(_e) => {
+            eprintln!("{}", _e);
+            std::process::exit(_e.exit_code());
+        }
     }
 }
@@ -0,0 +1,206 @@
+use std::fs;
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use libc::{statvfs, makedev};
+
+const MOUNTINFO : &str = "/proc/self/mountinfo";
+const BY_UUID_DIR : &str = "/dev/disk/by-uuid";
+const BY_LABEL_DIR : &str = "/dev/disk/by-label";
+
+pub struct Mount {
+    pub fs_root: PathBuf,    // directory of the filesystem mounted (for bind mounts)
+    pub mount_point: PathBuf,
+    pub fstype: String,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+}
+
+impl Mount {
+    /* path of an absolute (canonical) file path, relative to the root
+       of the filesystem it resides in */
+    pub fn medium_path(&self, abs_path: &Path) -> PathBuf {
+        let in_mount = abs_path.strip_prefix(&self.mount_point)
+            .unwrap_or(abs_path);
+        self.fs_root.strip_prefix("/").unwrap_or(&self.fs_root).join(in_mount)
+    }
+
+    /* total size of the filesystem in bytes */
+    #[allow(clippy::unnecessary_cast)] // statvfs field types vary across platforms
+    pub fn capacity(&self) -> Option<u64> {
+        let c_path = CString::new(self.mount_point.as_os_str().as_bytes()).ok()?;
+        let mut st = MaybeUninit::<libc::statvfs>::uninit();
+        unsafe {
+            if statvfs(c_path.as_ptr(), st.as_mut_ptr()) != 0 {
+                return None;
+            }
+            let st = st.assume_init();
+            Some(st.f_blocks as u64 * st.f_frsize as u64)
+        }
+    }
+}
+
+pub struct MountTable {
+    pub(crate) mounts: Vec<Mount>
+}
+
+// mountinfo escapes space, tab, newline and backslash as octal (e.g. \040)
+fn unescape_octal(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out : Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() &&
+           bytes[i+1..i+4].iter().all(|b| (b'0'..=b'7').contains(b)) {
+            let v = (bytes[i+1] - b'0') * 64 + (bytes[i+2] - b'0') * 8 + (bytes[i+3] - b'0');
+            out.push(v);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// udev escapes unsafe characters of labels as \xHH (e.g. \x20)
+fn unescape_hex(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out : Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i+1] == b'x' {
+            let hex = std::str::from_utf8(&bytes[i+2..i+4]).unwrap_or("");
+            if let Ok(v) = u8::from_str_radix(hex, 16) {
+                out.push(v);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/* finds the by-uuid/by-label entry pointing at the given device */
+fn find_device_link(dir: &str, dev: u64, source: &Path) -> Option<String> {
+    let canonical_source = fs::canonicalize(source).ok();
+    for entry in fs::read_dir(dir).ok()?.flatten() {
+        let link = entry.path();
+        let same_rdev = fs::metadata(&link).map(|m| m.rdev() == dev).unwrap_or(false);
+        let same_source = canonical_source.is_some() &&
+            fs::canonicalize(&link).ok() == canonical_source;
+        if same_rdev || same_source {
+            return Some(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    None
+}
+
+impl MountTable {
+    pub fn load() -> MountTable {
+        let mut mounts : Vec<Mount> = Vec::new();
+        let mountinfo = fs::read_to_string(MOUNTINFO).unwrap_or_default();
+
+        for line in mountinfo.lines() {
+            // 36 35 98:0 /mnt1 /mnt/parent rw,noatime master:1 - ext3 /dev/root rw
+            let (left, right) = match line.split_once(" - ") {
+                Some(_lr) => _lr,
+                None => continue
+            };
+            let lfields : Vec<&str> = left.split(' ').collect();
+            let rfields : Vec<&str> = right.split(' ').collect();
+            if lfields.len() < 5 || rfields.len() < 2 {
+                continue;
+            }
+            let dev = match lfields[2].split_once(':') {
+                Some((_maj, _min)) => match (_maj.parse::<u32>(), _min.parse::<u32>()) {
+                    (Ok(major), Ok(minor)) => makedev(major, minor),
+                    _ => continue
+                },
+                None => continue
+            };
+            let source = unescape_octal(rfields[1]);
+            let uuid = find_device_link(BY_UUID_DIR, dev, Path::new(&source));
+            let label = find_device_link(BY_LABEL_DIR, dev, Path::new(&source))
+                .map(|l| unescape_hex(&l));
+
+            mounts.push(Mount { fs_root: PathBuf::from(unescape_octal(lfields[3])),
+                                mount_point: PathBuf::from(unescape_octal(lfields[4])),
+                                fstype: unescape_octal(rfields[0]),
+                                uuid,
+                                label });
+        }
+        MountTable { mounts }
+    }
+
+    /* the mount an absolute (canonical) path resides in, i.e. the last mounted
+       among the ones with the longest mount point prefix */
+    pub fn find_for_path(&self, abs_path: &Path) -> Option<&Mount> {
+        let mut best : Option<&Mount> = None;
+        for m in &self.mounts {
+            if !abs_path.starts_with(&m.mount_point) {
+                continue;
+            }
+            let longer = match best {
+                Some(_b) => m.mount_point.as_os_str().len() >= _b.mount_point.as_os_str().len(),
+                None => true
+            };
+            if longer {
+                best = Some(m);
+            }
+        }
+        best
+    }
+
+    /* the mounts visible at mount points strictly below an absolute (canonical) path */
+    pub fn find_below(&self, abs_path: &Path) -> Vec<&Mount> {
+        self.mounts.iter()
+            .filter(|_m| _m.mount_point != abs_path && _m.mount_point.starts_with(abs_path))
+            .filter(|_m| self.find_for_path(&_m.mount_point).is_some_and(|_v| std::ptr::eq(_v, *_m)))
+            .collect()
+    }
+
+    /* current location of a path stored relative to the root of the filesystem
+       with the given uuid, None if no such filesystem is mounted (or the file lies
+       outside of any bind mounted directory) */
+    pub fn locate(&self, uuid: &str, medium_path: &Path) -> Option<PathBuf> {
+        let rooted = Path::new("/").join(medium_path);
+        for m in &self.mounts {
+            if m.uuid.as_deref() != Some(uuid) {
+                continue;
+            }
+            if let Ok(in_mount) = rooted.strip_prefix(&m.fs_root) {
+                return Some(m.mount_point.join(in_mount));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mount(mount_point: &str, uuid: &str) -> Mount {
+        Mount { fs_root: PathBuf::from("/"), mount_point: PathBuf::from(mount_point), fstype: "ext4".to_string(),
+                uuid: Some(uuid.to_string()), label: None }
+    }
+
+    #[test]
+    fn finds_the_mounts_visible_below_a_path() {
+        let table = MountTable { mounts: vec![mount("/", "root"), mount("/data", "data"), 
+                                              mount("/data/photos", "old"), mount("/data/photos", "photos"),
+                                              mount("/database", "other"), mount("/home", "home")] };
+        assert_eq!(table.find_for_path(Path::new("/data/photos/2019")).unwrap().uuid.as_deref(), Some("photos"));
+
+        let below : Vec<&str> = table.find_below(Path::new("/data")).iter()
+            .map(|_m| _m.uuid.as_deref().unwrap()).collect();
+        assert_eq!(below, vec!["photos"]);
+        assert!(table.find_below(Path::new("/data/photos")).is_empty());
+        assert_eq!(table.find_below(Path::new("/")).len(), 4);
+    }
+}
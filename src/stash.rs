@@ -1,189 +1,1322 @@
-use clap::{App, AppSettings, Arg, SubCommand, ArgMatches};
 use walkdir::DirEntry;
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::os::unix::fs::MetadataExt;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{mpsc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use libc::{c_int, SIGINT, SIGTERM};
 
 use crate::db;
 use crate::hasher;
 use crate::fs;
+use crate::mounts::{Mount, MountTable};
+use crate::filter::PathFilter;
+use crate::archive;
+use crate::escape;
+use crate::error::{self, Error};
 
-const DEFAULT_PATH : &str = ".";
-const DEFAULT_READ_BUFFER_SIZE : u64 = 8 * 1024 * 1024;
-
-pub struct StashOperation<'a> {
-    topdirs: Vec<&'a str>,
-    dbfile: &'a str,
-    bufsize: u64,
-    verbose: bool,
-    force_db_overwrite: bool,
-    force_read: bool,
-    force_sha512 : bool,
-    only_slowhash : bool
+pub const DEFAULT_READ_BUFFER_SIZE : u64 = 8 * 1024 * 1024;
+const QUEUE_DEPTH : usize = 16; // work items queued per hashing worker
+const CHECKPOINT_FILES : u64 = 1000; // files recorded between checkpoint commits
+const CHECKPOINT_INTERVAL : Duration = Duration::from_secs(60); // or time elapsed
+
+/* set on SIGINT/SIGTERM (or by interrupt()), so that the run stops and commits what is done */
+static INTERRUPTED : AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(sig: c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    unsafe {
+        libc::signal(sig, libc::SIG_DFL); // a second signal terminates at once
+    }
+}
+
+/// Makes SIGINT and SIGTERM interrupt a run in progress, as [`interrupt`] does;
+/// a second signal terminates the process at once.
+pub fn catch_signals() {
+    unsafe {
+        libc::signal(SIGINT, on_signal as *const () as libc::sighandler_t);
+        libc::signal(SIGTERM, on_signal as *const () as libc::sighandler_t);
+    }
+}
+
+/// Stops the run in progress, which still commits the files recorded so far;
+/// it can be picked up later by a run with `resume` set.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/* name of this host, as recorded with a run */
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&_b| _b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+/* name of the user running this process (or else its uid), as recorded with a run */
+fn user_name() -> String {
+    let uid = unsafe { libc::getuid() };
+    let pw = unsafe { libc::getpwuid(uid) };
+    if pw.is_null() {
+        return uid.to_string();
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr((*pw).pw_name) };
+    name.to_string_lossy().into_owned()
+}
+
+/// Settings of a run, which records files under `topdirs` in the stash file `dbfile`.
+///
+/// Start from [`StashOptions::default`] and change what is needed.
+pub struct StashOptions {
+    pub dbfile: PathBuf,
+    pub topdirs: Vec<PathBuf>,       // may be left empty when resuming (for those of the interrupted run)
+    pub medium: Option<String>,      // default: label or UUID of the filesystem, or else DEFAULT_MEDIUM
+    pub medium_descr: Option<String>,
+    pub bufsize: u64,                // read buffer, in bytes
+    pub threads: usize,              // hashing workers
+    pub walk_options: fs::WalkOptions,
+    pub filter: PathFilter,
+    pub force_db_overwrite: bool,
+    pub incremental: bool,           // skip files recorded with the same size, mtime and inode
+    pub resume: bool,                // pick up the last (interrupted) run
+    pub sync: bool,                  // also follow moved files and remove vanished ones
+    pub archives: bool,              // also record the members of archives
+    pub force_read: bool,            // read(2) instead of mmap(2)
+    pub quick_algorithm : Option<hasher::QuickAlgorithm>, // of a new stash file
+    pub slow_algorithm : Option<hasher::SlowAlgorithm>,
+    pub only_slowhash : bool,
+    pub size_first : bool,           // only hash files whose size is shared with another file
+    pub partial : bool               // hash the head and tail of files, in full only on collision
+}
+
+impl Default for StashOptions {
+    fn default() -> Self {
+        StashOptions {
+            dbfile: PathBuf::from(db::DEFAULT_DB),
+            topdirs: Vec::new(),
+            medium: None,
+            medium_descr: None,
+            bufsize: DEFAULT_READ_BUFFER_SIZE,
+            threads: std::thread::available_parallelism().map_or(1, |_n| _n.get()),
+            walk_options: fs::WalkOptions::default(),
+            filter: PathFilter::all(),
+            force_db_overwrite: false,
+            incremental: false,
+            resume: false,
+            sync: false,
+            archives: false,
+            force_read: false,
+            quick_algorithm: None,
+            slow_algorithm: None,
+            only_slowhash: false,
+            size_first: false,
+            partial: false
+        }
+    }
+}
+
+/// What a run reports as it goes, through the callback given to [`StashOptions::run`].
+pub enum StashEvent<'e> {
+    /// The stash file was upgraded from an older schema, a copy of it being kept.
+    Upgraded { upgrade: &'e db::Upgrade },
+    /// A path to stash, as given, and where it is recorded (relative to the root of its medium).
+    Root { path: &'e Path, medium: &'e str, medium_path: &'e Path },
+    /// Files are about to be walked; `resumed` is the interrupted run picked up, if any.
+    Started { algorithms: hasher::HashAlgorithms, resumed: Option<&'e db::InterruptedRun>, n_files: usize },
+    /// The `n`-th of about `total` files is being recorded.
+    Processing { path: &'e Path, n: usize, total: usize },
+    Processed { path: &'e Path },
+    /// A file (or some of its digests or archive members) could not be recorded;
+    /// it is also recorded in the errors of the run.
+    Failed { path: &'e Path, kind: db::FailureKind, reason: &'e str }
+}
+
+/// How a run went; it is only stored if the run returned Ok.
+pub struct StashSummary {
+    pub run_id: i64,
+    pub n_files: usize,       // found by the traversal
+    pub n_recorded: u64,      // by the run, including before it was interrupted and resumed
+    pub n_bytes: u64,         // of the files recorded, likewise
+    pub n_added: usize,
+    pub n_changed: usize,
+    pub n_moved: usize,       // sync mode
+    pub n_removed: usize,     // sync mode
+    pub n_unchanged: usize,   // skipped (incremental, resume and sync modes)
+    pub n_failed: usize,      // files skipped or left incomplete for errors (and unreadable directories,
+                              // recorded files that could not be hashed on a collision)
+    pub interrupted: bool
+}
+
+/* a path to be stashed, along with the medium it resides in; filesystems mounted
+   below such a path get roots of their own, for their files to be recorded in their
+   own media, though they are only walked as part of the path they are mounted below */
+struct StashRoot {
+    topdir: PathBuf,      // canonical absolute path
+    medium: String,
+    medium_path: PathBuf, // topdir relative to the root of the medium
+    walked: bool          // a path given, rather than a filesystem mounted below one
 }
 
-impl<'a> StashOperation<'a> {
-    pub fn from_args(sub_m: &'a ArgMatches<'a>) -> Self {
-        let mut _topdirs : Vec<&str> = vec!(DEFAULT_PATH);
-        let mut _dbfile : &str = db::DEFAULT_DB;
-        let mut _bufsize : u64 = DEFAULT_READ_BUFFER_SIZE;
-        let _force_db_overwrite : bool;
-        let _verbose : bool;
-        let _force_read : bool;
-        let _force_sha512 : bool;
-        let _only_slowhash : bool;
-
-        if let Some(_p) = sub_m.values_of("path") {
-            _topdirs = _p.collect();
-        }
-
-        if let Some(_db) = sub_m.value_of("db") {
-            _dbfile = _db;
-        }
-
-        if let Some(_buf) = sub_m.value_of("buf") {
-            _bufsize = _buf.parse::<u64>()
-                .expect("error during parsing of buffer size to integer") 
-                * 1024 * 1024;
-        }
-
-        _verbose = sub_m.is_present("verbose");
-        _force_db_overwrite = sub_m.is_present("force_db_overwrite");
-        _force_read = sub_m.is_present("force_read");
-        _force_sha512 = sub_m.is_present("force_sha512");
-        _only_slowhash = sub_m.is_present("only_slowhash");
-
-        StashOperation {
-            topdirs: _topdirs,
-            dbfile: _dbfile,
-            bufsize: _bufsize,
-            verbose: _verbose,
-            force_db_overwrite: _force_db_overwrite,
-            force_read: _force_read,
-            force_sha512 : _force_sha512,
-            only_slowhash : _only_slowhash
-        }
-    }
-
-    pub fn do_operation(&self) {
-        let _medium = "filesystem";
-        let medium_descr = "my computer";
-	
-	    let mut store = db::DataBase::new(self.dbfile, self.force_db_overwrite, self.force_sha512, self.only_slowhash);
-	    let trans = db::DBTransaction::new(&mut store);
-	    let mut media_statement = db::MediaInsertStatement::new(&trans);
-	    let mut file_statement = db::FileInsertStatement::new(&trans);
-        
-
-        let mut quick_hasher = fs::FileHasher::new(hasher::QuickHasher::new(),
-                                                      self.bufsize, 
-                                                      self.force_read);
-        let mut slow_hasher = match self.force_sha512 {
-            false => fs::FileHasher::new(hasher::SlowHasher::MD5(), self.bufsize, self.force_read),
-            true => fs::FileHasher::new(hasher::SlowHasher::SHA512(), self.bufsize, self.force_read)
+enum ItemState {
+    Pending,      // to be hashed by a worker (left as is when interrupted)
+    Unchanged,    // already in stash (incremental and resume modes)
+    Hashed { parthash: Option<String>, fasthash: Option<String>, slowhash: Option<String> },
+    Symlink(String), // recorded with its target, not hashed
+    Hardlink,     // inode already met during this run, digests are copied over
+    UniqueSize,   // no other file of its size, recorded without hashes (size-first mode)
+    Moved(u64),   // recorded at another path that no longer exists (sync mode)
+    Failed(db::FailureKind, String)
+}
+
+/* what the walker thread consults to decide about each file */
+struct WalkerState<'d> {
+    lookup: db::FileLookupStatement<'d>,
+    size_lookup: db::SizeLookupStatement<'d>,
+    moved_lookup: db::MovedFileStatement<'d>,
+    moved_rowids: Vec<u64>,
+    inodes_seen: HashSet<(u64, u64)>,
+    run_sizes: &'d HashMap<u64, usize> // files of each size in this run (size-first mode)
+}
+
+/* a file on its way from the walker through the hashing workers to the writer */
+struct WorkItem {
+    seq: usize,          // traversal order
+    path: PathBuf,
+    root: usize,         // index of the StashRoot the file was found under
+    medium_path: PathBuf,
+    meta: Option<std::fs::Metadata>,
+    stored_rowid: Option<u64>,
+    state: ItemState,
+    size_collides: bool, // other files of its size are known, so it is hashed fully in one pass
+    members: Option<Result<Vec<archive::ArchiveMember>, String>> // of an archive (archives mode)
+}
+
+impl StashOptions {
+    /* identifies the medium of each path by the UUID of the filesystem it resides in 
+       (or else by the given medium name) and records the media in the stash */
+    fn resolve_roots<F>(&self, topdirs: &[PathBuf], mounts: &MountTable, 
+                        media_statement: &mut db::MediaInsertStatement,
+                        on_event: &mut F) -> error::Result<Vec<StashRoot>>
+        where F: FnMut(StashEvent)
+    {
+        let mut roots : Vec<StashRoot> = Vec::new();
+        let mut nested : Vec<StashRoot> = Vec::new();
+
+        for dir in topdirs {
+            let topdir = std::fs::canonicalize(dir).map_err(|_e| match _e.kind() {
+                std::io::ErrorKind::NotFound => Error::NotFound(format!("cannot resolve path {} ({})", escape::display(dir), _e)),
+                _ => Error::Io(format!("cannot resolve path {}", escape::display(dir)), _e)
+            })?;
+            let mount = match mounts.find_for_path(&topdir).filter(|_m| _m.uuid.is_some()) {
+                Some(_mount) if self.medium.is_none() && Self::kept_in_default_medium(_mount, media_statement)? => None,
+                _found => _found
+            };
+
+            let (medium, medium_path) = match mount {
+                Some(_mount) => {
+                    let uuid = _mount.uuid.as_ref().unwrap();
+                    let medium = match self.medium.as_deref() {
+                        Some(_name) => {
+                            if let Some(Some(_uuid)) = media_statement.medium_uuid(_name)? {
+                                if &_uuid != uuid {
+                                    return Err(Error::Usage(format!(
+                                        "medium {} is recorded for filesystem UUID {}, but {} resides in {}",
+                                        _name, _uuid, escape::display(dir), uuid)));
+                                }
+                            }
+                            if let Some(_other) = media_statement.medium_by_uuid(uuid)? {
+                                if _other != _name {
+                                    return Err(Error::Usage(format!(
+                                        "filesystem UUID {} of {} is already recorded as medium {}",
+                                        uuid, escape::display(dir), _other)));
+                                }
+                            }
+                            _name.to_string()
+                        },
+                        None => Self::medium_of_mount(_mount, media_statement)?
+                    };
+                    media_statement.try_add_medium(&medium, self.medium_descr.as_deref(), Some(_mount))?;
+                    (medium, _mount.medium_path(&topdir))
+                },
+                None => {
+                    let medium = self.medium.as_deref().unwrap_or(db::DEFAULT_MEDIUM);
+                    if let Some(Some(_uuid)) = media_statement.medium_uuid(medium)? {
+                        return Err(Error::Usage(format!(
+                            "medium {} is recorded for filesystem UUID {}, but {} does not reside in it",
+                            medium, _uuid, escape::display(dir))));
+                    }
+                    media_statement.try_add_medium(medium, self.medium_descr.as_deref(), None)?;
+                    (medium.to_string(), topdir.strip_prefix("/").unwrap().to_path_buf())
+                }
+            };
+
+            on_event(StashEvent::Root { path: dir, medium: &medium, medium_path: &medium_path });
+
+            /* the walk crosses into filesystems mounted below, unless kept to one */
+            if !self.walk_options.same_file_system {
+                for _mount in mounts.find_below(&topdir) {
+                    if nested.iter().any(|_r| _r.topdir == _mount.mount_point) {
+                        continue;
+                    }
+                    let (medium, medium_path) = match _mount.uuid {
+                        Some(_) if !Self::kept_in_default_medium(_mount, media_statement)? => {
+                            let medium = Self::medium_of_mount(_mount, media_statement)?;
+                            media_statement.try_add_medium(&medium, None, Some(_mount))?;
+                            (medium, _mount.medium_path(&_mount.mount_point))
+                        },
+                        _ => {
+                            media_statement.try_add_medium(db::DEFAULT_MEDIUM, None, None)?;
+                            (db::DEFAULT_MEDIUM.to_string(), 
+                             _mount.mount_point.strip_prefix("/").unwrap().to_path_buf())
+                        }
+                    };
+                    on_event(StashEvent::Root { path: &_mount.mount_point, medium: &medium, medium_path: &medium_path });
+                    nested.push(StashRoot { topdir: _mount.mount_point.clone(), medium, medium_path, walked: false });
+                }
+            }
+            roots.push(StashRoot { topdir, medium, medium_path, walked: true });
+        }
+        nested.retain(|_n| !roots.iter().any(|_r| _r.topdir == _n.topdir));
+        roots.extend(nested);
+        Ok(roots)
+    }
+
+    /* a filesystem with a UUID whose files were recorded in the default medium, before media
+       were told by UUID (see db_migrate), stays in it, as another medium would record them anew */
+    fn kept_in_default_medium(mount: &Mount, media_statement: &mut db::MediaInsertStatement) -> error::Result<bool> {
+        Ok(media_statement.medium_by_uuid(mount.uuid.as_ref().unwrap())?.is_none() &&
+           media_statement.has_files_under(db::DEFAULT_MEDIUM, mount.mount_point.strip_prefix("/").unwrap())?)
+    }
+
+    /* the medium of a filesystem with a UUID, when not named: the one recorded for the
+       UUID, or else the label of the filesystem (unless taken by another medium) or its UUID */
+    fn medium_of_mount(mount: &Mount, media_statement: &mut db::MediaInsertStatement) -> error::Result<String> {
+        let uuid = mount.uuid.as_ref().unwrap();
+        Ok(match media_statement.medium_by_uuid(uuid)? {
+            Some(_known) => _known,
+            None => match &mount.label {
+                Some(_label) if media_statement.medium_uuid(_label)?.is_none() => _label.clone(),
+                _ => uuid.clone()
+            }
+        })
+    }
+
+    /* the settings a run is recorded with, besides its algorithms */
+    fn run_options(&self) -> Vec<&'static str> {
+        [(self.incremental, "incremental"), (self.sync, "sync"), (self.archives, "archives"),
+         (self.only_slowhash, "only-slowhash"), (self.size_first, "size-first"), (self.partial, "partial"),
+         (self.walk_options.follow_links, "follow-links"), (self.walk_options.symlinks, "record-symlinks"),
+         (self.walk_options.same_file_system, "one-file-system")]
+            .iter().filter(|(_set, _)| *_set).map(|(_, _name)| *_name).collect()
+    }
+
+    fn new_quick_hasher(&self, algorithms: hasher::HashAlgorithms) -> fs::FileHasher<hasher::QuickHasher> {
+        fs::FileHasher::new(hasher::QuickHasher::new(algorithms.quick), self.bufsize, self.force_read)
+    }
+
+    fn new_slow_hasher(&self, algorithms: hasher::HashAlgorithms) -> error::Result<fs::FileHasher<hasher::SlowHasher>> {
+        Ok(fs::FileHasher::new(hasher::SlowHasher::new(algorithms.slow)?, self.bufsize, self.force_read))
+    }
+
+    /* whether files recorded with the same size, mtime and inode are not hashed again */
+    fn skips_unchanged(&self) -> bool {
+        self.incremental || self.resume || self.sync
+    }
+
+    /* a path found under one of the roots, yet to be looked into */
+    fn new_item(&self, seq: usize, path: PathBuf, roots: &[StashRoot]) -> WorkItem {
+        let root = (0..roots.len())
+            .filter(|&_i| path.starts_with(&roots[_i].topdir))
+            .max_by_key(|&_i| roots[_i].topdir.as_os_str().len())
+            .unwrap();
+        let medium_path = roots[root].medium_path.join(path.strip_prefix(&roots[root].topdir).unwrap());
+        WorkItem { seq, path, root, medium_path, 
+                   meta: None, stored_rowid: None, state: ItemState::Pending,
+                   size_collides: false, members: None }
+    }
+
+    /* an entry the walker could not read (e.g. a directory, or an ignore file), passed 
+       along so that it is reported and recorded in traversal order */
+    fn unwalked_item(&self, seq: usize, err: &fs::WalkError, roots: &[StashRoot]) -> WorkItem {
+        let (path, state) = match err {
+            fs::WalkError::Unreadable(_err) => (_err.path().unwrap_or(&roots[0].topdir), match _err.io_error() {
+                Some(_e) => ItemState::Failed(db::FailureKind::Walk.of(_e), format!("failed to read directory ({})", _e)),
+                None => ItemState::Failed(db::FailureKind::Walk, _err.to_string())
+            }),
+            fs::WalkError::IgnoreFile(_path, _e) => 
+                (*_path, ItemState::Failed(db::FailureKind::Walk, format!("failed to parse ignore file ({})", _e)))
         };
+        let mut item = self.new_item(seq, path.to_path_buf(), roots);
+        item.state = state;
+        item
+    }
+
+    /* stats a file found by the walker and decides whether it needs hashing */
+    fn walk_item(&self, seq: usize, dirent: &DirEntry, roots: &[StashRoot], 
+                 state: &mut WalkerState) -> error::Result<WorkItem> {
+        let mut item = self.new_item(seq, dirent.path().to_path_buf(), roots);
+        let root = item.root;
 
-        let mut slow_hasher_lazy = match self.force_sha512 {
-            false => fs::FileHasher::new(hasher::SlowHasher::MD5(), self.bufsize, self.force_read),
-            true => fs::FileHasher::new(hasher::SlowHasher::SHA512(), self.bufsize, self.force_read)
+        let meta = match dirent.metadata() {
+            Ok(_m) => _m,
+            Err(_e) => { 
+                item.state = match _e.io_error() {
+                    Some(_io) => ItemState::Failed(db::FailureKind::Stat.of(_io), format!("failed to stat file ({})", _e)),
+                    None => ItemState::Failed(db::FailureKind::Stat, format!("failed to stat file ({})", _e))
+                };
+                return Ok(item);
+            }
         };
 
+        if let Some(_stored) = state.lookup.lookup(&roots[root].medium, &item.medium_path)? {
+            if self.skips_unchanged() && _stored.is_unchanged(meta.len(), meta.mtime(), meta.ino()) {
+                item.state = ItemState::Unchanged;
+            }
+            item.stored_rowid = Some(_stored.rowid);
+        }
+
+        if self.sync && item.stored_rowid.is_none() {
+            if let Some(_rowid) = state.moved_lookup.find(&roots[root].medium, &meta, &state.moved_rowids)? {
+                state.moved_rowids.push(_rowid);
+                item.state = ItemState::Moved(_rowid);
+            }
+        }
+
+        if matches!(item.state, ItemState::Pending) && meta.file_type().is_symlink() {
+            item.state = match std::fs::read_link(&item.path) {
+                Ok(_target) => ItemState::Symlink(escape::encode(&_target).into_owned()),
+                Err(_e) => ItemState::Failed(db::FailureKind::Symlink.of(&_e), format!("failed to read symlink ({})", _e))
+            };
+        }
+
+        if matches!(item.state, ItemState::Pending) && meta.file_type().is_file() && !self.only_slowhash {
+            let size_shared = state.run_sizes.get(&meta.len()).copied().unwrap_or(0) >= 2 ||
+                              state.size_lookup.size_taken(meta.len(), &roots[root].medium, meta.dev(), meta.ino(),
+                                                           item.stored_rowid)?;
+            if self.size_first && !size_shared {
+                item.state = ItemState::UniqueSize;
+            }
+            item.size_collides = size_shared && !self.partial;
+        }
+
+        /* each inode is hashed once, for the first of its links met (or of the paths it
+           is reached by through symlinks, which do not count as links) */
+        if meta.file_type().is_file() &&
+           !state.inodes_seen.insert((meta.dev(), meta.ino())) && 
+           matches!(item.state, ItemState::Pending) {
+            item.state = ItemState::Hardlink;
+        }
+        item.meta = Some(meta);
+        Ok(item)
+    }
+
+    /* computes the digests that are needed regardless of what is in the stash */
+    fn hash_item(&self, item: &mut WorkItem, 
+                 quick_hasher: &mut fs::FileHasher<hasher::QuickHasher>,
+                 slow_hasher: &mut fs::FileHasher<hasher::SlowHasher>) {
+        if !matches!(item.state, ItemState::Pending) {
+            return;
+        }
+        let size = item.meta.as_ref().unwrap().len();
+        item.state = match File::open(&item.path) {
+            Ok(mut f) => {
+                let hashed = if self.only_slowhash {
+                    slow_hasher.hash_filehandle(&mut f, size)
+                        .map(|_slowhash| ItemState::Hashed { parthash: None, fasthash: None, 
+                                                             slowhash: Some(_slowhash) })
+                } else if self.partial {
+                    quick_hasher.hash_partial(&mut f, size)
+                        .map(|_parthash| ItemState::Hashed { parthash: Some(_parthash), 
+                                                             fasthash: None, slowhash: None })
+                } else if item.size_collides {
+                    /* the slow digest is likely to be needed as well, read the file once for both */
+                    quick_hasher.hash_filehandle_multi(&mut f, size, &mut [&mut *slow_hasher.hasher])
+                        .map(|mut _digests| {
+                            let slowhash = _digests.pop();
+                            ItemState::Hashed { parthash: None, fasthash: _digests.pop(), slowhash }
+                        })
+                } else {
+                    quick_hasher.hash_filehandle(&mut f, size)
+                        .map(|_fasthash| ItemState::Hashed { parthash: None, fasthash: Some(_fasthash), 
+                                                             slowhash: None })
+                };
+                hashed.unwrap_or_else(|_e| ItemState::Failed(db::FailureKind::Read.of(&_e), 
+                                                             format!("failed to read file ({})", _e)))
+            },
+            Err(_e) => ItemState::Failed(db::FailureKind::Open.of(&_e), format!("failed to open file ({})", _e))
+        };
+    }
+
+    /* hashes the members of an archive that is (re)recorded */
+    fn read_archive(&self, item: &mut WorkItem, 
+                    quick_hasher: &mut fs::FileHasher<hasher::QuickHasher>,
+                    slow_hasher: &mut fs::FileHasher<hasher::SlowHasher>) {
+        if self.archives && matches!(item.state, ItemState::Hashed { .. } | ItemState::UniqueSize) &&
+           archive::is_archive(&item.path) {
+            item.members = Some(archive::read_members(&item.path, quick_hasher, slow_hasher));
+        }
+    }
+
+    /* records the members of an archive, checking them for collisions like any other file;
+       returns why the archive could not be read, if so */
+    #[allow(clippy::too_many_arguments)]
+    fn record_members(&self, item: &WorkItem, medium: &str, container: u64,
+                      file_statement: &mut db::FileInsertStatement,
+                      check_collision: &mut db::CheckCollisionStatement,
+                      quick_hasher: &mut fs::FileHasher<hasher::QuickHasher>,
+                      slow_hasher: &mut fs::FileHasher<hasher::SlowHasher>) 
+        -> error::Result<Option<(db::FailureKind, String)>> {
+        let members = match &item.members {
+            Some(Ok(_members)) => _members,
+            Some(Err(_e)) => return Ok(Some((db::FailureKind::Archive, format!("failed to read archive ({})", _e)))),
+            None => return Ok(None)
+        };
+        file_statement.clear_members(container)?;
+        for m in members {
+            let rowid = file_statement.add_member(medium, &item.medium_path, m, container)?;
+            if !self.only_slowhash {
+                check_collision.fill_fasthashes(quick_hasher, m.digests.size, None, Some(rowid))?;
+                check_collision.collision(slow_hasher, &m.digests.fasthash, Some(rowid))?;
+            }
+        }
+        Ok(None)
+    }
+
+    /* escalates from partial to quick to slow hashing (of both the file and the 
+       recorded files it collides with) for as long as the digests collide;
+       returns why the file could not be read for a digest it needed, if so */
+    fn escalate(&self, fm: &mut db::FileMetadata, path: &Path, stored_rowid: Option<u64>,
+                check_collision: &mut db::CheckCollisionStatement,
+                quick_hasher: &mut fs::FileHasher<hasher::QuickHasher>,
+                slow_hasher: &mut fs::FileHasher<hasher::SlowHasher>) 
+        -> error::Result<Option<(db::FailureKind, String)>> {
+        if self.only_slowhash {
+            return Ok(None);
+        }
+        match fm.parthash.clone() {
+            Some(_parthash) => {
+                check_collision.fill_parthashes(quick_hasher, fm.size, stored_rowid)?;
+                if !check_collision.partial_collision(&_parthash, stored_rowid)? {
+                    return Ok(None);
+                }
+                /* files alike in head and tail are likely duplicates, read once for both digests */
+                let hashed = File::open(path).and_then(|mut f| 
+                    quick_hasher.hash_filehandle_multi(&mut f, fm.size, &mut [&mut *slow_hasher.hasher]));
+                match hashed {
+                    Ok(mut _digests) => {
+                        fm.slowhash = _digests.pop();
+                        fm.fasthash = _digests.pop();
+                    },
+                    Err(_e) => return Ok(Some((db::FailureKind::Read.of(&_e), format!("failed to read file ({})", _e))))
+                }
+                check_collision.fill_fasthashes(quick_hasher, fm.size, Some(&_parthash), stored_rowid)?;
+            },
+            None => check_collision.fill_fasthashes(quick_hasher, fm.size, None, stored_rowid)?
+        }
+        if check_collision.collision(slow_hasher, fm.fasthash.as_ref().unwrap(), stored_rowid)? &&
+           fm.slowhash.is_none() {
+            match File::open(path).and_then(|mut f| slow_hasher.hash_filehandle(&mut f, fm.size)) {
+                Ok(_slowhash) => fm.slowhash = Some(_slowhash),
+                Err(_e) => {
+                    fm.pending_slowhash = true;
+                    return Ok(Some((db::FailureKind::Read.of(&_e), 
+                                    format!("failed to read file ({}), slow hashing is pending", _e))));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Records the files under the top directories in the stash file, calling `on_event`
+    /// as it goes; files that cannot be read are reported and skipped, while errors of
+    /// the stash file itself stop the run. What was committed at the checkpoints before
+    /// such an error is kept, and the run can be picked up with `resume` set.
+    pub fn run<F>(&self, on_event: F) -> error::Result<StashSummary>
+        where F: FnMut(StashEvent)
+    {
+        self.run_in(&MountTable::load(), on_event)
+    }
+
+    /* a run among the given mounts (those of the system, but for tests) */
+    fn run_in<F>(&self, mounts: &MountTable, mut on_event: F) -> error::Result<StashSummary>
+        where F: FnMut(StashEvent)
+    {
+        let mut store = db::DataBase::new(&self.dbfile, self.force_db_overwrite, 
+                                          self.quick_algorithm, self.slow_algorithm, self.only_slowhash)?;
+        if let Some(upgrade) = store.upgraded() {
+            on_event(StashEvent::Upgraded { upgrade });
+        }
+        /* the walker looks files up in the stash as it was before this run, which
+           neither the checkpoints of the writer nor the timing of the threads change */
+        store.set_wal_mode(true)?;
+        let recorded = self.record(&mut store, mounts, &mut on_event);
+        /* left however the run ended, once the connection of the walker is closed */
+        let restored = store.set_wal_mode(false);
+        let summary = recorded?;
+        restored?;
+        Ok(summary)
+    }
+
+    fn record<F>(&self, store: &mut db::DataBase, mounts: &MountTable, on_event: &mut F) -> error::Result<StashSummary>
+        where F: FnMut(StashEvent)
+    {
+        let algorithms = store.algorithms();
+        let reader = db::DataBase::for_reading(&self.dbfile)?;
+        reader.pin_snapshot()?;
+        let trans = db::DBTransaction::new(store)?;
+        let mut media_statement = db::MediaInsertStatement::new(&trans)?;
+        let mut run_statement = db::RunStatement::new(&trans)?;
+
+        /* a resumed run skips the files recorded so far (as in incremental mode) */
+        let resumed = match self.resume {
+            true => match run_statement.interrupted()? {
+                Some(_run) => Some(_run),
+                None => return Err(Error::NotFound("no interrupted run to resume found in stash file".to_string()))
+            },
+            false => None
+        };
+        let resumed_paths : Vec<PathBuf> = match &resumed {
+            Some(_run) if self.topdirs.is_empty() => _run.paths.clone(),
+            _ => self.topdirs.clone()
+        };
+
+        let roots = self.resolve_roots(&resumed_paths, mounts, &mut media_statement, on_event)?;
+        let topdirs : Vec<&PathBuf> = roots.iter().filter(|_r| _r.walked).map(|_r| &_r.topdir).collect();
+
+        let (run_id, mut n_recorded, mut n_bytes) = match &resumed {
+            Some(_run) => (_run.run_id, _run.n_files, _run.n_bytes),
+            None => {
+                let provenance = db::RunProvenance { hostname: hostname(), user: Some(user_name()), algorithms,
+                                                     options: self.run_options() };
+                let paths : Vec<(&Path, &str)> = roots.iter().filter(|_r| _r.walked)
+                    .map(|_r| (_r.topdir.as_path(), _r.medium.as_str())).collect();
+                (run_statement.start(&provenance, &paths)?, 0, 0)
+            }
+        };
+        let mut file_statement = db::FileInsertStatement::new(&trans, run_id)?;
+
         /* CheckCollisionStatement needs a FileHasher<SlowHasher> to perform lazy slow hashing to the old entry
            when a new entry has a colliding "fast" hash */
-        let mut check_collision = db::CheckCollisionStatement::new(&trans);
-
-	    let do_nothing = |_: &DirEntry| {};
-	
-	    let hash_and_store = |dirent: &DirEntry| {
-	        let _path = dirent.path();
-            match File::open(_path) {
-                Ok(_f) => {
-                    let mut f = _f;
-        	        let _size = f.metadata().unwrap().len();
-                    let q_digest_str: Option<String>;
-                    let s_digest_str: Option<String>;
-
-                    q_digest_str = if self.only_slowhash {
-                        None
-                    } else {
-                        Some(quick_hasher.hash_filehandle(&mut f, _size))
-                    };
+        let mut check_collision = db::CheckCollisionStatement::new(&trans)?;
+        let mut hardlink_statement = db::HardlinkStatement::new(&trans)?;
+        let mut slow_hasher = self.new_slow_hasher(algorithms)?;
+        /* likewise, files left unhashed for their unique size get quick hashed 
+           once a file of the same size shows up */
+        let mut quick_hasher = self.new_quick_hasher(algorithms);
+        let worker_hashers = (0..self.threads)
+            .map(|_| Ok((self.new_quick_hasher(algorithms), self.new_slow_hasher(algorithms)?)))
+            .collect::<error::Result<Vec<_>>>()?;
+
+        /* in size-first mode, the counting pass also collects the sizes of the files
+           (each inode counted once), as only files of non-unique size get hashed */
+        let mut run_sizes : HashMap<u64, usize> = HashMap::new();
+        let mut counted_inodes : HashSet<(u64, u64)> = HashSet::new();
+        let collect_sizes = |entry: std::result::Result<&DirEntry, fs::WalkError>| {
+            let dirent = match entry {
+                Ok(_dirent) if self.size_first && _dirent.file_type().is_file() => _dirent,
+                _ => return
+            };
+            if let Ok(_meta) = dirent.metadata() {
+                if counted_inodes.insert((_meta.dev(), _meta.ino())) {
+                    *run_sizes.entry(_meta.len()).or_insert(0) += 1;
+                }
+            }
+        };
+
+        let mut fv_dry_run = fs::FileVisitor { 
+            dry_run: true,
+            options: &self.walk_options,
+            filter: &self.filter,
+            interrupted: Some(&INTERRUPTED),
+            file_processor : collect_sizes
+        };
+
+        let n_items = fv_dry_run.traverse(&topdirs);
+        let mut n_unchanged : usize = 0;
+        on_event(StashEvent::Started { algorithms, resumed: resumed.as_ref(), n_files: n_items });
+
+        /* the walker feeds files (in traversal order) to a pool of hashing workers, 
+           whose results are stored by this thread in the very same order, so that 
+           lazy slow hashing is not affected by the number of workers */
+        let mut last_path : Option<PathBuf> = None;
+        let (mut n_added, mut n_changed, mut n_moved) : (usize, usize, usize) = (0, 0, 0);
+        let mut n_failed : usize = 0; // files (and directories) skipped for errors
+        /* in sync mode, the rows of the files met are kept track of (within this run) */
+        let mut sync_statement = match self.sync {
+            true => Some(db::SyncStatement::new(&trans, run_id)?),
+            false => None
+        };
+        let (work_tx, work_rx) = mpsc::sync_channel::<WorkItem>(self.threads * QUEUE_DEPTH);
+        let (done_tx, done_rx) = mpsc::sync_channel::<WorkItem>(self.threads * QUEUE_DEPTH);
+        let work_rx = Mutex::new(work_rx);
+
+        /* an error of the walker or the writer stops the run, as an interruption would */
+        let (written, walked) = std::thread::scope(|scope| {
+            let roots = &roots;
+            let topdirs = &topdirs;
+            let run_sizes = &run_sizes;
 
-                    if self.only_slowhash || (!self.only_slowhash && check_collision.collision(&mut slow_hasher_lazy, q_digest_str.as_ref().unwrap().as_ref())) {
-                        s_digest_str = Some(slow_hasher.hash_filehandle(&mut f, _size));
-                    } else {
-                        s_digest_str = None;
+            let walker = scope.spawn(move || -> error::Result<()> {
+                let mut state = WalkerState { lookup: db::FileLookupStatement::new(&reader)?,
+                                              size_lookup: db::SizeLookupStatement::new(&reader)?,
+                                              moved_lookup: db::MovedFileStatement::new(&reader)?,
+                                              moved_rowids: Vec::new(),
+                                              inodes_seen: HashSet::new(),
+                                              run_sizes };
+                let mut seq : usize = 0;
+                let mut failure : Option<Error> = None;
+                let mut fv_walker = fs::FileVisitor {
+                    dry_run: false,
+                    options: &self.walk_options,
+                    filter: &self.filter,
+                    interrupted: Some(&INTERRUPTED),
+                    file_processor: |entry: std::result::Result<&DirEntry, fs::WalkError>| {
+                        let walked = match entry {
+                            Ok(_dirent) => self.walk_item(seq, _dirent, roots, &mut state),
+                            Err(_err) => Ok(self.unwalked_item(seq, &_err, roots))
+                        };
+                        match walked {
+                            Ok(item) => {
+                                seq += 1;
+                                // only fails if the writer has failed, which is reported by it
+                                let _ = work_tx.send(item);
+                            },
+                            Err(_e) => {
+                                INTERRUPTED.store(true, Ordering::SeqCst);
+                                failure.get_or_insert(_e);
+                            }
+                        }
                     }
-                    
-        	        file_statement.add_file(db::FileMetadata{path: &_path, 
-                                                                fasthash: q_digest_str,
-                                                                slowhash: s_digest_str,
-                                                                medium: _medium,
-                                                                size: _size});
-                },
-                Err(_e) => eprint!("failed to open file ({})\n", _e)
+                };
+                fv_walker.traverse(topdirs);
+                match failure {
+                    Some(_e) => Err(_e),
+                    None => Ok(())
+                }
+            });
+
+            for (mut quick_hasher, mut slow_hasher) in worker_hashers {
+                let work_rx = &work_rx;
+                let done_tx = done_tx.clone();
+                scope.spawn(move || {
+                    loop {
+                        let next = work_rx.lock().unwrap().recv();
+                        let mut item = match next {
+                            Ok(_item) => _item,
+                            Err(_) => break // walker is done
+                        };
+                        if !INTERRUPTED.load(Ordering::Relaxed) {
+                            self.hash_item(&mut item, &mut quick_hasher, &mut slow_hasher);
+                            self.read_archive(&mut item, &mut quick_hasher, &mut slow_hasher);
+                        }
+                        if done_tx.send(item).is_err() {
+                            break; // writer has quit
+                        }
+                    }
+                });
             }
-	    };
-	
-	    let mut fv_dry_run = fs::FileVisitor { 
-	        dry_run: true,
-	        verbose: false,
-	        file_processor : do_nothing
-	    };
-	
-	    let n_items = fv_dry_run.traverse(&self.topdirs, 0);
-	
-	    media_statement.try_add_medium(_medium, medium_descr);
-	
-	    let mut fv_actual = fs::FileVisitor {
-	        dry_run: false,
-	        verbose: self.verbose,
-	        file_processor: hash_and_store
-	    };
-	
-	    fv_actual.traverse(&self.topdirs, n_items);
+            drop(done_tx);
+
+            let mut pending : BTreeMap<usize, WorkItem> = BTreeMap::new();
+            let mut next_seq : usize = 0;
+            let mut since_checkpoint : u64 = 0;
+            let mut last_checkpoint = Instant::now();
+            let mut write_items = || -> error::Result<()> {
+                for done in &done_rx {
+                    pending.insert(done.seq, done);
+                    while let Some(item) = pending.remove(&next_seq) {
+                        next_seq += 1;
+                        on_event(StashEvent::Processing { path: &item.path, n: next_seq, total: n_items });
+                        let medium = &roots[item.root].medium;
+                        let recorded = !matches!(item.state, ItemState::Unchanged | ItemState::Failed(..) | 
+                                                             ItemState::Pending);
+                        let mut failures : Vec<(db::FailureKind, String)> = Vec::new();
+                        if recorded {
+                            match (&item.state, item.stored_rowid) {
+                                (ItemState::Moved(_), _) => n_moved += 1,
+                                (_, Some(_)) => n_changed += 1,
+                                (_, None) => n_added += 1
+                            }
+                        }
+                        let seen_rowid : Option<u64> = match &item.state {
+                            ItemState::Unchanged => {
+                                n_unchanged += 1;
+                                item.stored_rowid
+                            },
+                            ItemState::Failed(_kind, _e) => {
+                                failures.push((*_kind, _e.clone()));
+                                item.stored_rowid
+                            },
+                            ItemState::Pending => None, // not hashed due to interruption
+                            ItemState::Symlink(target) => {
+                                let mut fm = db::FileMetadata::from_fs(&item.medium_path, medium, 
+                                                                       item.meta.as_ref().unwrap());
+                                fm.link_target = Some(target.clone());
+                                Some(file_statement.add_file(fm)?)
+                            },
+                            ItemState::Moved(rowid) => {
+                                let fm = db::FileMetadata::from_fs(&item.medium_path, medium, 
+                                                                   item.meta.as_ref().unwrap());
+                                sync_statement.as_mut().unwrap().relocate(*rowid, &fm)?;
+                                Some(*rowid)
+                            },
+                            ItemState::Hardlink => {
+                                let mut fm = db::FileMetadata::from_fs(&item.medium_path, medium, 
+                                                                       item.meta.as_ref().unwrap());
+                                /* the first link could not be hashed, try this one */
+                                if !hardlink_statement.copy_digests(&mut fm, item.stored_rowid)? {
+                                    match File::open(&item.path)
+                                        .and_then(|mut f| slow_hasher.hash_filehandle(&mut f, fm.size)) {
+                                        Ok(_slowhash) => fm.slowhash = Some(_slowhash),
+                                        Err(_e) => failures.push((db::FailureKind::Read.of(&_e), 
+                                                                  format!("failed to read file ({})", _e)))
+                                    }
+                                }
+                                if fm.parthash.is_some() || fm.fasthash.is_some() || fm.slowhash.is_some() {
+                                    Some(file_statement.add_file(fm)?)
+                                } else {
+                                    item.stored_rowid
+                                }
+                            },
+                            ItemState::UniqueSize => {
+                                Some(file_statement.add_file(db::FileMetadata::from_fs(&item.medium_path, medium, 
+                                                                                       item.meta.as_ref().unwrap()))?)
+                            },
+                            ItemState::Hashed { parthash, fasthash, slowhash } => {
+                                let mut fm = db::FileMetadata::from_fs(&item.medium_path, medium, 
+                                                                       item.meta.as_ref().unwrap());
+                                fm.parthash = parthash.clone();
+                                fm.fasthash = fasthash.clone();
+                                fm.slowhash = slowhash.clone();
+                                failures.extend(self.escalate(&mut fm, &item.path, item.stored_rowid, 
+                                                              &mut check_collision, &mut quick_hasher, 
+                                                              &mut slow_hasher)?);
+                                Some(file_statement.add_file(fm)?)
+                            }
+                        };
+                        if let (true, Some(_rowid)) = (recorded, seen_rowid) {
+                            failures.extend(self.record_members(&item, medium, _rowid, &mut file_statement, 
+                                                                &mut check_collision, &mut quick_hasher, 
+                                                                &mut slow_hasher)?);
+                        }
+                        for (kind, reason) in &failures {
+                            on_event(StashEvent::Failed { path: &item.path, kind: *kind, reason });
+                            run_statement.failed(run_id, medium, &item.medium_path, *kind, reason)?;
+                            n_failed += 1;
+                        }
+                        /* recorded files that were to be hashed for colliding with this one */
+                        for lazy in check_collision.take_failures() {
+                            on_event(StashEvent::Failed { path: &lazy.path, kind: lazy.kind, reason: &lazy.reason });
+                            run_statement.failed(run_id, &lazy.medium, &lazy.medium_path, lazy.kind, &lazy.reason)?;
+                            n_failed += 1;
+                        }
+                        if let (Some(_sync), Some(_rowid)) = (sync_statement.as_mut(), seen_rowid) {
+                            _sync.seen(_rowid)?;
+                        }
+                        on_event(StashEvent::Processed { path: &item.path });
+                        if recorded {
+                            n_recorded += 1;
+                            n_bytes += item.meta.as_ref().map_or(0, |_m| _m.len());
+                            since_checkpoint += 1;
+                            if since_checkpoint >= CHECKPOINT_FILES || last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                                run_statement.progress(run_id, &item.path, n_recorded, n_bytes)?;
+                                trans.checkpoint()?;
+                                since_checkpoint = 0;
+                                last_checkpoint = Instant::now();
+                            }
+                            last_path = Some(item.path);
+                        }
+                    }
+                }
+                Ok(())
+            };
+            let written = write_items();
+            if written.is_err() {
+                /* stops the walker, what is already on its way is let through */
+                INTERRUPTED.store(true, Ordering::SeqCst);
+                for _ in &done_rx {}
+            }
+            let walked = walker.join().unwrap_or_else(|_e| std::panic::resume_unwind(_e));
+            (written, walked)
+        });
+        /* cleared for a later run of this process */
+        let interrupted = INTERRUPTED.swap(false, Ordering::SeqCst);
+        written?;
+        walked?;
+
+        if let Some(_path) = &last_path {
+            run_statement.progress(run_id, _path, n_recorded, n_bytes)?;
+        }
+        run_statement.finish(run_id, !interrupted)?;
+        drop(run_statement);
+
+        /* vanished files can only be told after a complete traversal */
+        let mut n_removed : usize = 0;
+        if let Some(_sync) = sync_statement.as_mut() {
+            if !interrupted {
+                for root in &roots {
+                    n_removed += _sync.remove_vanished(&root.medium, &root.medium_path)?;
+                }
+            }
+        }
+        drop(sync_statement);
 
         drop(media_statement);
         drop(file_statement);
         drop(check_collision);
-	    trans.commit();
+        drop(hardlink_statement);
+        trans.commit()?;
+
+        Ok(StashSummary { run_id, n_files: n_items, n_recorded, n_bytes, n_added, n_changed, n_moved, n_removed,
+                          n_unchanged, n_failed, interrupted })
     }
 }
 
-pub fn args_config<'a, 'b>() -> App<'a, 'b> {
-    return SubCommand::with_name("stash")
-            .about("Creates a stash of recorded files")
-            .usage("wfiles stash [-v] [-f] [-r] [-s] [-l] [-d <FILE>] [-b <SIZE>] <PATH> ...")
-            .setting(AppSettings::TrailingVarArg)
-            .arg(Arg::with_name("db")
-                 .short("d")
-                 .value_name("FILE")
-                 .default_value("stash.db")
-                 .help("Sets stash file"))
-            .arg(Arg::with_name("force_db_overwrite")
-                 .short("f")
-                 .help("Forces the overwrite of an existing stash file"))
-            .arg(Arg::with_name("force_read")
-                 .short("r")
-                 .help("Don't mmap(2), use read(2) instead"))
-            .arg(Arg::with_name("force_sha512")
-                .short("s")
-                .help("Prefer SHA512 over MD5 for slow hashing"))
-            .arg(Arg::with_name("only_slowhash")
-                .short("l")
-                .help("Disable quick hashing (and perform only slow hashing)"))
-            .arg(Arg::with_name("verbose")
-                .short("v")
-                .help("Verbose mode"))
-            .arg(Arg::with_name("buf")
-                .short("b")
-                .value_name("SIZE")
-                .default_value("8")
-                .help("Read-buffer size (in megabytes)"))
-            .arg(Arg::with_name("path")
-                .required(true)
-//              .last(true) cannot use as it makes '--' mandatory :/
-                .allow_hyphen_values(true)
-                .multiple(true)
-                .value_name("PATH")
-                .help("Path(s) to examine"));
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, ScratchDir};
+
+    fn options(scratch: &ScratchDir) -> StashOptions {
+        StashOptions { dbfile: scratch.join("stash.db"), topdirs: vec![scratch.join("files")], threads: 2,
+                       ..StashOptions::default() }
+    }
+
+    #[test]
+    fn records_more_files_than_the_page_cache_holds() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("page-cache");
+        let n : usize = 3000;
+        for i in 0..n {
+            scratch.write(format!("files/{}/{}", i / 100, i), format!("file {}\n", i).as_bytes());
+        }
+        /* a page cache this small is spilled by the writer long before each checkpoint */
+        let options = options(&scratch);
+        drop(db::DataBase::new(&options.dbfile, false, None, None, false).unwrap());
+        rusqlite::Connection::open(&options.dbfile).unwrap()
+            .execute_batch("PRAGMA default_cache_size = 10").unwrap();
+
+        let summary = options.run(|_| ()).unwrap();
+        assert_eq!(summary.n_recorded, n as u64);
+        assert_eq!(summary.n_failed, 0);
+        assert!(!summary.interrupted);
+    }
+
+    #[test]
+    fn resumes_an_interrupted_run() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("resume");
+        let n : usize = 2500;
+        for i in 0..n {
+            scratch.write(format!("files/{}/{}", i / 100, i), format!("file {}\n", i).as_bytes());
+        }
+        let options = options(&scratch);
+
+        /* stopped past the first checkpoint */
+        let mut n_processed : usize = 0;
+        let first = options.run(|event| if let StashEvent::Processed { .. } = event {
+            n_processed += 1;
+            if n_processed == 1500 {
+                interrupt();
+            }
+        }).unwrap();
+        assert!(first.interrupted);
+        assert!(first.n_recorded >= 1500 && first.n_recorded < n as u64);
+
+        let resumed = StashOptions { topdirs: Vec::new(), resume: true, ..options }.run(|_| ()).unwrap();
+        assert_eq!(resumed.run_id, first.run_id);
+        assert!(!resumed.interrupted);
+        assert_eq!(resumed.n_recorded, n as u64);
+        assert_eq!(resumed.n_unchanged as u64, first.n_recorded);
+
+        let store = db::DataBase::for_reading(scratch.join("stash.db")).unwrap();
+        let runs = db::RunListStatement::new(&store).unwrap().runs(None, None).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert!(runs[0].completed);
+        assert_eq!(runs[0].n_files, n as u64);
+        drop(store);
+        let n_rows : i64 = rusqlite::Connection::open(scratch.join("stash.db")).unwrap()
+            .query_row("SELECT count(*) from files", [], |r| r.get(0)).unwrap();
+        assert_eq!(n_rows, n as i64);
+    }
+
+    /* (fname, fasthash, run that last recorded it) of the files recorded, by fname */
+    fn recorded_runs(scratch: &ScratchDir) -> Vec<(String, Option<String>, i64)> {
+        let conn = rusqlite::Connection::open(scratch.join("stash.db")).unwrap();
+        let mut stmt = conn.prepare("SELECT fname, fasthash, run_id from files order by fname").unwrap();
+        let recorded = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        recorded
+    }
+
+    #[test]
+    fn records_only_new_or_changed_files_when_incremental() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("incremental");
+        scratch.write("files/a", b"left as is\n");
+        scratch.write("files/b", b"to be edited\n");
+        let options = StashOptions { incremental: true, ..options(&scratch) };
+        let first = options.run(|_| ()).unwrap();
+        assert_eq!(first.n_added, 2);
+        let before = recorded_runs(&scratch);
+
+        scratch.write("files/b", b"edited\n");
+        scratch.write("files/c", b"new\n");
+        let second = options.run(|_| ()).unwrap();
+        assert_eq!((second.n_unchanged, second.n_changed, second.n_added), (1, 1, 1));
+
+        /* the row of the file left as is was not touched, that of the edited file updated in place */
+        let after = recorded_runs(&scratch);
+        assert_eq!(after.len(), 3);
+        assert_eq!(after[0], before[0]);
+        assert_eq!((after[1].0.as_str(), after[1].2), ("b", second.run_id));
+        assert_ne!(after[1].1, before[1].1);
+    }
+
+    #[test]
+    fn follows_moved_files_and_removes_vanished_ones_when_syncing() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("sync");
+        scratch.write("files/a", b"stays\n");
+        scratch.write("files/b", b"moves\n");
+        scratch.write("files/c", b"vanishes\n");
+        let options = StashOptions { sync: true, ..options(&scratch) };
+        options.run(|_| ()).unwrap();
+        let before = recorded_runs(&scratch);
+
+        std::fs::create_dir(scratch.join("files/moved")).unwrap();
+        std::fs::rename(scratch.join("files/b"), scratch.join("files/moved/b")).unwrap();
+        std::fs::remove_file(scratch.join("files/c")).unwrap();
+        let summary = options.run(|_| ()).unwrap();
+        assert_eq!((summary.n_unchanged, summary.n_moved, summary.n_removed, summary.n_added), (1, 1, 1, 0));
+
+        /* the moved file keeps its row and digests, under its new path */
+        let after = recorded_runs(&scratch);
+        assert_eq!(after.iter().map(|_r| _r.0.as_str()).collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(after[0], before[0]);
+        assert_eq!(after[1].1, before[1].1);
+        let path : String = rusqlite::Connection::open(scratch.join("stash.db")).unwrap()
+            .query_row("SELECT path from files where fname = 'b'", [], |r| r.get(0)).unwrap();
+        assert!(path.ends_with("files/moved"));
+    }
 
+    #[test]
+    fn records_the_provenance_of_each_run() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("provenance");
+        scratch.write("files/a", b"a\n");
+        scratch.write("named/b", b"bb\n");
+        let first = options(&scratch).run(|_| ()).unwrap();
+        let named = StashOptions { topdirs: vec![scratch.join("named")], medium: Some("named".to_string()),
+                                   size_first: true, ..options(&scratch) };
+        let second = named.run(|_| ()).unwrap();
+
+        let store = db::DataBase::for_reading(scratch.join("stash.db")).unwrap();
+        let mut run_list = db::RunListStatement::new(&store).unwrap();
+        let runs = run_list.runs(None, None).unwrap();
+        assert_eq!(runs.iter().map(|_r| _r.run_id).collect::<Vec<_>>(), vec![first.run_id, second.run_id]);
+        let run = &runs[1];
+        assert!(run.completed && run.finished.is_some());
+        assert_eq!(run.user.as_deref(), Some(user_name().as_str()));
+        assert_eq!(run.paths, vec![(std::fs::canonicalize(scratch.join("named")).unwrap(), Some("named".to_string()))]);
+        assert_eq!(run.algorithms, Some((store.algorithms().quick.to_string(), store.algorithms().slow.to_string())));
+        assert!(run.options.iter().any(|_o| _o == "size-first"));
+        assert_eq!((run.n_files, run.n_bytes, run.n_errors), (1, 3, 0));
+
+        /* by the media they stashed, or the latest ones */
+        for runs in [run_list.runs(Some("named"), None).unwrap(), run_list.runs(None, Some(1)).unwrap()] {
+            assert_eq!(runs.iter().map(|_r| _r.run_id).collect::<Vec<_>>(), vec![second.run_id]);
+        }
+        drop(run_list);
+        drop(store);
+
+        /* each file is linked to the run that recorded it */
+        assert_eq!(recorded_runs(&scratch).iter().map(|_r| _r.2).collect::<Vec<_>>(), vec![first.run_id, second.run_id]);
+    }
+
+    /* stashes dir, then another dir whose file collides with the (since removed) file of dir */
+    fn collide_with_removed(name: &str, size_first: bool) -> (ScratchDir, StashSummary, Vec<PathBuf>) {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new(name);
+        let removed = scratch.write("files/one/a", b"same contents\n");
+        scratch.write("files/two/b", b"same contents\n");
+        let options = |dir: &str| StashOptions { topdirs: vec![scratch.join("files").join(dir)], size_first,
+                                                 ..options(&scratch) };
+        assert_eq!(options("one").run(|_| ()).unwrap().n_failed, 0);
+        std::fs::remove_file(&removed).unwrap();
+
+        let mut failed : Vec<PathBuf> = Vec::new();
+        let summary = options("two").run(|event| if let StashEvent::Failed { path, kind, .. } = event {
+            assert_eq!(kind, db::FailureKind::Read);
+            failed.push(path.to_path_buf());
+        }).unwrap();
+        (scratch, summary, failed)
+    }
+
+    fn recorded_errors(scratch: &ScratchDir, run_id: i64) -> Vec<(PathBuf, db::FailureKind)> {
+        let store = db::DataBase::for_reading(scratch.join("stash.db")).unwrap();
+        let mut error_statement = db::ErrorListStatement::new(&store).unwrap();
+        error_statement.errors(Some(run_id), None).unwrap().into_iter().map(|_e| (_e.path, _e.kind)).collect()
+    }
+
+    #[test]
+    fn records_failures_of_triggered_slow_hashing() {
+        let (scratch, summary, failed) = collide_with_removed("lazy-slowhash", false);
+        let removed = std::fs::canonicalize(scratch.join("files")).unwrap().join("one/a");
+        assert_eq!(summary.n_failed, 1);
+        assert_eq!(failed, vec![removed.clone()]);
+        assert_eq!(recorded_errors(&scratch, summary.run_id), vec![(removed, db::FailureKind::Read)]);
+    }
+
+    #[test]
+    fn records_failures_of_lazy_quick_hashing() {
+        let (scratch, summary, failed) = collide_with_removed("lazy-fasthash", true);
+        let removed = std::fs::canonicalize(scratch.join("files")).unwrap().join("one/a");
+        assert_eq!(summary.n_failed, 1);
+        assert_eq!(failed, vec![removed.clone()]);
+        assert_eq!(recorded_errors(&scratch, summary.run_id), vec![(removed, db::FailureKind::Read)]);
+    }
+
+    #[test]
+    fn leaves_wal_mode_when_the_run_fails() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("wal-failed");
+        scratch.write("files/a", b"a\n");
+        let options = StashOptions { resume: true, ..options(&scratch) };
+        assert!(matches!(options.run(|_| {}), Err(Error::NotFound(_))));
+        let conn = rusqlite::Connection::open(&options.dbfile).unwrap();
+        let mode : String = conn.query_row("PRAGMA journal_mode", [], |r| r.get(0)).unwrap();
+        assert_eq!(mode, "delete");
+    }
+
+    #[test]
+    fn keeps_migrated_files_of_a_filesystem_in_the_default_medium() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("migrated-medium");
+        scratch.write("files/a", b"a\n");
+        scratch.write("files/b", b"b\n");
+        scratch.write("files/new/c", b"c\n");
+        let files = std::fs::canonicalize(scratch.join("files")).unwrap();
+        let options = options(&scratch);
+        testutil::first_schema_stash(&options.dbfile, db::DEFAULT_MEDIUM,
+                                     &[(files.to_str().unwrap(), "a", 2), (files.to_str().unwrap(), "b", 2)]);
+
+        /* the stashed path turns out to reside in a filesystem with a UUID, as does one
+           mounted below it that was never stashed */
+        let mount = |_path: &Path, _uuid: &str| Mount { fs_root: PathBuf::from("/"), mount_point: _path.to_path_buf(),
+                                                        fstype: "ext4".to_string(), uuid: Some(_uuid.to_string()),
+                                                        label: Some(format!("{}-label", _uuid)) };
+        let mounts = MountTable { mounts: vec![mount(files.parent().unwrap(), "old"), mount(&files.join("new"), "new")] };
+        let summary = options.run_in(&mounts, |_| {}).unwrap();
+        assert_eq!((summary.n_added, summary.n_changed), (1, 2));
+
+        let conn = rusqlite::Connection::open(&options.dbfile).unwrap();
+        let recorded : Vec<(String, Option<String>, String, String)> = conn.prepare(
+            "SELECT medium, uuid, path, fname from files join media using (medium_id) order by fname").unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        let path = escape::encode(files.strip_prefix("/").unwrap()).into_owned();
+        assert_eq!(recorded, vec![(db::DEFAULT_MEDIUM.to_string(), None, path.clone(), "a".to_string()),
+                                  (db::DEFAULT_MEDIUM.to_string(), None, path, "b".to_string()),
+                                  ("new-label".to_string(), Some("new".to_string()), "".to_string(), "c".to_string())]);
+    }
+
+    /* (fname, fasthash recorded) of the files recorded, by fname */
+    fn hashed(scratch: &ScratchDir) -> Vec<(String, bool)> {
+        let conn = rusqlite::Connection::open(scratch.join("stash.db")).unwrap();
+        let mut stmt = conn.prepare("SELECT fname, fasthash is not null from files order by fname").unwrap();
+        let hashed = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        hashed
+    }
+
+    #[test]
+    fn hashes_only_files_of_shared_size_when_size_first() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("size-first");
+        scratch.write("files/a", b"same size\n");
+        scratch.write("files/b", b"also size\n");
+        scratch.write("files/c", b"a size of its own\n");
+        let options = StashOptions { size_first: true, ..options(&scratch) };
+        options.run(|_| ()).unwrap();
+        assert_eq!(hashed(&scratch), vec![("a".to_string(), true), ("b".to_string(), true),
+                                          ("c".to_string(), false)]);
+
+        /* a file of its size stashed later gets it hashed after all */
+        scratch.write("later/d", b"of the same size.\n");
+        let later = StashOptions { topdirs: vec![scratch.join("later")], ..options };
+        assert_eq!(later.run(|_| ()).unwrap().n_failed, 0);
+        assert_eq!(hashed(&scratch), vec![("a".to_string(), true), ("b".to_string(), true),
+                                          ("c".to_string(), true), ("d".to_string(), true)]);
+    }
+
+    #[test]
+    fn hashes_in_full_only_files_alike_in_head_and_tail() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("partial");
+        /* a and b differ only between the ends hashed by the partial digest, c right at its head */
+        let contents = vec![b'x'; 64 * 1024];
+        let mut middle = contents.clone();
+        middle[32 * 1024] = b'y';
+        let mut head = contents.clone();
+        head[0] = b'y';
+        scratch.write("files/a", &contents);
+        scratch.write("files/b", &middle);
+        scratch.write("files/c", &head);
+        let options = StashOptions { partial: true, ..options(&scratch) };
+        assert_eq!(options.run(|_| ()).unwrap().n_failed, 0);
+
+        let conn = rusqlite::Connection::open(scratch.join("stash.db")).unwrap();
+        let mut stmt = conn.prepare("SELECT fname, parthash, fasthash from files order by fname").unwrap();
+        let recorded : Vec<(String, Option<String>, Option<String>)> = stmt
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?))).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        assert!(recorded.iter().all(|_r| _r.1.is_some()));
+        assert_eq!(recorded[0].1, recorded[1].1);
+        assert_ne!(recorded[0].1, recorded[2].1);
+        assert!(recorded[0].2.is_some() && recorded[1].2.is_some());
+        assert_ne!(recorded[0].2, recorded[1].2);
+        assert_eq!(recorded[2].2, None);
+    }
+
+    #[test]
+    fn counts_hardlinks_as_one_file() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("hardlinks");
+        let linked = scratch.write("files/a", b"linked\n");
+        std::fs::hard_link(&linked, scratch.join("files/b")).unwrap();
+        scratch.write("files/c", b"not linked\n");
+
+        /* the size of a and b is theirs alone, so neither gets hashed */
+        let options = StashOptions { size_first: true, ..options(&scratch) };
+        assert_eq!(options.run(|_| ()).unwrap().n_recorded, 3);
+        assert_eq!(hashed(&scratch), vec![("a".to_string(), false), ("b".to_string(), false),
+                                          ("c".to_string(), false)]);
+        /* nor taken for probable duplicates of each other */
+        let store = db::DataBase::for_reading(scratch.join("stash.db")).unwrap();
+        assert!(db::IdentifyDupsStatement::new(&store).unwrap().get_size_dups().unwrap().is_empty());
+        drop(store);
+
+        /* a copy of theirs gets both links hashed */
+        scratch.write("files/d", b"linked\n");
+        assert_eq!(options.run(|_| ()).unwrap().n_failed, 0);
+        assert_eq!(hashed(&scratch), vec![("a".to_string(), true), ("b".to_string(), true),
+                                          ("c".to_string(), false), ("d".to_string(), true)]);
+    }
+
+    /* records the file a of the stash (medium one) once more in medium other, with the same
+       device and inode, as a file of another disk mounted in turn at the same device may be */
+    fn record_in_other_medium(scratch: &ScratchDir, fasthash: Option<&str>) -> rusqlite::Connection {
+        let conn = rusqlite::Connection::open(scratch.join("stash.db")).unwrap();
+        conn.execute("INSERT INTO media (medium) VALUES ('other')", []).unwrap();
+        conn.execute("INSERT INTO files (medium_id, path, fname, size, mtime, inode, dev, fasthash)
+                      SELECT (select medium_id from media where medium = 'other'), 'elsewhere', fname, 
+                      size, mtime, inode, dev, ? from files where fname = 'a'", [fasthash]).unwrap();
+        conn
+    }
+
+    /* fasthash and slowhash recorded for the file a of a medium */
+    fn digests_in(conn: &rusqlite::Connection, medium: &str) -> (Option<String>, Option<String>) {
+        conn.query_row("SELECT fasthash, slowhash from files join media using (medium_id) 
+                        where medium = ? and fname = 'a'", [medium], |r| Ok((r.get(0)?, r.get(1)?))).unwrap()
+    }
+
+    #[test]
+    fn slow_hashes_every_colliding_file_and_leaves_unavailable_ones_pending() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("collide-n-way");
+        scratch.write("files/one/a", b"same contents\n");
+        scratch.write("files/two/c", b"same contents\n");
+        let options = |dir: &str| StashOptions { topdirs: vec![scratch.join("files").join(dir)], ..options(&scratch) };
+        options("one").run(|_| ()).unwrap();
+        /* a copy of a on a medium not plugged in */
+        let fasthash = digests_in(&rusqlite::Connection::open(scratch.join("stash.db")).unwrap(), db::DEFAULT_MEDIUM).0;
+        let conn = record_in_other_medium(&scratch, fasthash.as_deref());
+
+        let summary = options("two").run(|_| ()).unwrap();
+        assert_eq!(summary.n_failed, 1);
+        let slowhash = digests_in(&conn, db::DEFAULT_MEDIUM).1;
+        assert!(slowhash.is_some());
+        let pending : (Option<String>, bool) = conn.query_row(
+            "SELECT slowhash, pending_slowhash from files join media using (medium_id) where medium = 'other'",
+            [], |r| Ok((r.get(0)?, r.get(1)?))).unwrap();
+        assert_eq!(pending, (None, true));
+        drop(conn);
+
+        /* a and c are duplicates, the copy a probable one */
+        let store = db::DataBase::for_reading(scratch.join("stash.db")).unwrap();
+        let mut dup_state = db::IdentifyDupsStatement::new(&store).unwrap();
+        let dups = dup_state.get_dups().unwrap();
+        assert_eq!((dups.len(), dups[0].num_dups, Some(&dups[0].hash)), (1, 2, slowhash.as_ref()));
+        let probable = dup_state.get_probable_dups().unwrap();
+        assert_eq!((probable.len(), probable[0].num_dups), (1, 3));
+    }
+
+    #[test]
+    fn keeps_hardlinks_within_their_medium() {
+        let _turn = testutil::take_turn();
+        let options = |scratch: &ScratchDir, size_first: bool| StashOptions { medium: Some("one".to_string()),
+                                                                             size_first, ..options(scratch) };
+
+        /* a file of the other medium makes the size of a shared, so it gets hashed */
+        let scratch = ScratchDir::new("media-links-size");
+        scratch.write("files/a", b"contents\n");
+        options(&scratch, true).run(|_| ()).unwrap();
+        let conn = record_in_other_medium(&scratch, None);
+        options(&scratch, true).run(|_| ()).unwrap();
+        assert!(digests_in(&conn, "one").0.is_some());
+        drop(scratch);
+
+        /* a slow hashed (as left unchanged) on a collision, which its namesake of the other
+           medium does not share */
+        let scratch = ScratchDir::new("media-links-collision");
+        scratch.write("files/a", b"contents\n");
+        options(&scratch, false).run(|_| ()).unwrap();
+        let conn = record_in_other_medium(&scratch, Some("other"));
+        scratch.write("files/c", b"contents\n");
+        StashOptions { incremental: true, ..options(&scratch, false) }.run(|_| ()).unwrap();
+        assert!(digests_in(&conn, "one").1.is_some());
+        assert_eq!(digests_in(&conn, "other"), (Some("other".to_string()), None));
+    }
+
+    #[test]
+    fn counts_files_reached_through_symlinks_once() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("follow-links");
+        let target = scratch.write("files/target", b"target\n");
+        std::os::unix::fs::symlink(&target, scratch.join("files/link")).unwrap();
+        scratch.write("files/other", b"other\n");
+
+        /* the target is not a copy of itself, though met twice */
+        let options = StashOptions { size_first: true,
+                                     walk_options: fs::WalkOptions { follow_links: true, ..fs::WalkOptions::default() },
+                                     ..options(&scratch) };
+        assert_eq!(options.run(|_| ()).unwrap().n_recorded, 3);
+        assert_eq!(hashed(&scratch), vec![("link".to_string(), false), ("other".to_string(), false),
+                                          ("target".to_string(), false)]);
+    }
+
+    #[test]
+    fn records_ignore_files_that_cannot_be_parsed() {
+        let _turn = testutil::take_turn();
+        let scratch = ScratchDir::new("ignore-file");
+        scratch.write("files/kept", b"kept\n");
+        let ignore_file = scratch.write(format!("files/{}", crate::filter::IGNORE_FILE), b"unclosed{\n");
+        let options = StashOptions { filter: PathFilter::new(&[], &[], &[], &[], true).unwrap(),
+                                     ..options(&scratch) };
+
+        let mut failed : Vec<(PathBuf, db::FailureKind)> = Vec::new();
+        let summary = options.run(|event| if let StashEvent::Failed { path, kind, .. } = event {
+            failed.push((path.to_path_buf(), kind));
+        }).unwrap();
+        let ignore_file = std::fs::canonicalize(ignore_file).unwrap();
+        assert_eq!(failed, vec![(ignore_file.clone(), db::FailureKind::Walk)]);
+        assert_eq!(summary.n_failed, 1);
+        assert_eq!(recorded_errors(&scratch, summary.run_id), vec![(ignore_file, db::FailureKind::Walk)]);
+    }
+}
@@ -0,0 +1,73 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/* runs share the interruption flag of stash, so tests running them take turns */
+static RUNS : Mutex<()> = Mutex::new(());
+
+pub fn take_turn() -> MutexGuard<'static, ()> {
+    RUNS.lock().unwrap_or_else(|_e| _e.into_inner())
+}
+
+/* a directory of its own for a test, removed along with its contents when dropped */
+pub struct ScratchDir {
+    pub path: PathBuf
+}
+
+impl ScratchDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("wfiles-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        ScratchDir { path }
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, rel: P) -> PathBuf {
+        self.path.join(rel)
+    }
+
+    /* writes a file below the directory, creating the directories on its way */
+    pub fn write<P: AsRef<Path>>(&self, rel: P, contents: &[u8]) -> PathBuf {
+        let path = self.path.join(rel);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/* a stash file of the first schema (0x0100), with the given files (directory, name
+   and size, with made up digests) recorded in the given medium */
+pub fn first_schema_stash(dbfile: &Path, medium: &str, files: &[(&str, &str, u64)]) {
+    let conn = rusqlite::Connection::open(dbfile).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE config
+         (config_id INTEGER NOT NULL, key TEXT NOT NULL UNIQUE, value TEXT, PRIMARY KEY (config_id));
+         CREATE TABLE media
+         (medium_id INTEGER NOT NULL, medium text NOT NULL UNIQUE, medium_comment text,
+         PRIMARY KEY (medium_id));
+         CREATE TABLE files
+         (medium_id INTEGER NOT NULL, path text NOT NULL, fname text NOT NULL, fasthash text,
+         slowhash text, size INTEGER NOT NULL,
+         CONSTRAINT files_id PRIMARY KEY (medium_id, path, fname),
+         FOREIGN KEY(medium_id) REFERENCES media(medium_id));
+         CREATE INDEX idx_fasthash ON files (fasthash);
+         CREATE INDEX idx_slowhash ON files (slowhash);
+         CREATE INDEX idx_size ON files (size);
+         CREATE INDEX idx_fname ON files (fname);
+         CREATE INDEX idx_medium ON media (medium);
+         CREATE INDEX idx_config ON config (key);
+         INSERT INTO config (key, value) VALUES('version', '256');
+         INSERT INTO config (key, value) VALUES('force_sha512', 'true');
+         INSERT INTO config (key, value) VALUES('only_slowhash', 'false')").unwrap();
+    conn.execute("INSERT INTO media (medium) VALUES (?)", [medium]).unwrap();
+    for (i, (path, fname, size)) in files.iter().enumerate() {
+        conn.execute("INSERT INTO files (medium_id, path, fname, fasthash, slowhash, size) VALUES (1, ?, ?, ?, ?, ?)",
+                     rusqlite::params![path, fname, format!("f{}", i + 1), format!("s{}", i + 1), *size as i64])
+            .unwrap();
+    }
+}